@@ -0,0 +1,209 @@
+use crate::units::Unit;
+use crate::{AktenError, PatientRecord};
+use arrow_array::builder::StringDictionaryBuilder;
+use arrow_array::types::Int32Type;
+use arrow_array::{
+    ArrayRef, BooleanArray, Date32Array, Float32Array, RecordBatch, StringArray, TimestampMicrosecondArray, UInt32Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{NaiveDate, Utc};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::Arc;
+
+/// Version of the column layout below, stored in the file metadata
+pub const SCHEMA_VERSION: &str = "1.5";
+
+/// What the file was exported for, recorded as `aktenakrobat.export_kind`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportKind {
+    Records,
+    Ai,
+}
+
+impl ExportKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Records => "records",
+            Self::Ai => "ai",
+        }
+    }
+}
+
+/// Patient ids are dictionary-encoded strings (categoricals in pandas/polars),
+/// dates are Date32, counts and pressures unsigned integers. Measurements are
+/// nullable; unrecorded values are written as nulls (since schema 1.1). The
+/// extended vitals and derived BMI follow the original columns (since 1.2),
+/// then the further patient identifiers as `system|value;...` (since 1.3).
+/// `date` is the local calendar day; `timestamp` is the UTC measurement time,
+/// null for date-only measurements (since 1.4). `temperature` is in °C and
+/// `blood_sugar` in mg/dL whatever the input unit, noted as the UCUM `unit`
+/// in their field metadata (since 1.5)
+pub fn schema() -> Schema {
+    let unit = |ucum: &str| HashMap::from([("unit".to_string(), ucum.to_string())]);
+    Schema::new(vec![
+        Field::new_dictionary("patient_id", DataType::Int32, DataType::Utf8, false),
+        Field::new("date", DataType::Date32, false),
+        Field::new("heart_rate", DataType::UInt32, true),
+        Field::new("bp_systolic", DataType::UInt32, true),
+        Field::new("bp_diastolic", DataType::UInt32, true),
+        Field::new("temperature", DataType::Float32, true).with_metadata(unit(Unit::Celsius.ucum())),
+        Field::new("blood_sugar", DataType::Float32, true).with_metadata(unit(Unit::MilligramsPerDeciliter.ucum())),
+        Field::new("steps", DataType::UInt32, true),
+        Field::new("spo2", DataType::UInt32, true),
+        Field::new("respiratory_rate", DataType::UInt32, true),
+        Field::new("supplemental_oxygen", DataType::Boolean, true),
+        Field::new_dictionary("consciousness", DataType::Int32, DataType::Utf8, true),
+        Field::new("weight", DataType::Float32, true),
+        Field::new("height", DataType::Float32, true),
+        Field::new("bmi", DataType::Float32, true),
+        Field::new("identifiers", DataType::Utf8, true),
+        Field::new("timestamp", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), true),
+    ])
+}
+
+fn parquet_error(e: impl std::fmt::Display) -> AktenError {
+    AktenError::Parquet(e.to_string())
+}
+
+pub fn records_to_batch(records: &[PatientRecord]) -> Result<RecordBatch, AktenError> {
+    let mut patient_ids = StringDictionaryBuilder::<Int32Type>::new();
+    let mut consciousness = StringDictionaryBuilder::<Int32Type>::new();
+    let mut dates = Vec::with_capacity(records.len());
+    for record in records {
+        patient_ids.append_value(&record.patient_id);
+        consciousness.append_option(record.consciousness.map(|level| level.letter()));
+        dates.push((record.timestamp.date() - NaiveDate::default()).num_days() as i32);
+    }
+    let times = records
+        .iter()
+        .map(|r| (!r.timestamp.is_date_only()).then(|| r.timestamp.instant().timestamp_micros()))
+        .collect::<TimestampMicrosecondArray>()
+        .with_timezone("UTC");
+    let unsigned = |value: fn(&PatientRecord) -> Option<u32>| -> ArrayRef {
+        Arc::new(records.iter().map(value).collect::<UInt32Array>())
+    };
+    let float = |value: fn(&PatientRecord) -> Option<f32>| -> ArrayRef {
+        Arc::new(records.iter().map(value).collect::<Float32Array>())
+    };
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(patient_ids.finish()),
+        Arc::new(Date32Array::from(dates)),
+        unsigned(|r| r.heart_rate),
+        unsigned(|r| r.bp_systolic),
+        unsigned(|r| r.bp_diastolic),
+        float(|r| r.temperature),
+        float(|r| r.blood_sugar),
+        unsigned(|r| r.steps),
+        unsigned(|r| r.spo2),
+        unsigned(|r| r.respiratory_rate),
+        Arc::new(records.iter().map(|r| r.supplemental_oxygen).collect::<BooleanArray>()),
+        Arc::new(consciousness.finish()),
+        float(|r| r.weight),
+        float(|r| r.height),
+        float(|r| r.bmi().map(|bmi| bmi as f32)),
+        Arc::new(
+            records
+                .iter()
+                .map(|r| Some(r.identifiers.to_string()).filter(|text| !text.is_empty()))
+                .collect::<StringArray>(),
+        ),
+        Arc::new(times),
+    ];
+    RecordBatch::try_new(Arc::new(schema()), columns).map_err(parquet_error)
+}
+
+/// Write `records` as a single Snappy-compressed Parquet file with the schema
+/// version and export details in the key-value metadata
+pub fn write_parquet(records: &[PatientRecord], output_path: &str, kind: ExportKind) -> Result<(), AktenError> {
+    let batch = records_to_batch(records)?;
+    let metadata = [
+        ("aktenakrobat.schema_version", SCHEMA_VERSION.to_string()),
+        ("aktenakrobat.export_kind", kind.as_str().to_string()),
+        ("aktenakrobat.export_timestamp", Utc::now().to_rfc3339()),
+        ("aktenakrobat.record_count", records.len().to_string()),
+        ("aktenakrobat.version", env!("CARGO_PKG_VERSION").to_string()),
+    ];
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_key_value_metadata(Some(
+            metadata.into_iter().map(|(key, value)| KeyValue::new(key.to_string(), value)).collect(),
+        ))
+        .build();
+
+    let file = File::create(output_path)?;
+    let mut writer = ArrowWriter::try_new(file, batch.schema(), Some(properties)).map_err(parquet_error)?;
+    writer.write(&batch).map_err(parquet_error)?;
+    writer.close().map_err(parquet_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamp::Timestamp;
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use tempfile::Builder;
+
+    fn record(patient_id: &str, timestamp: &str) -> PatientRecord {
+        PatientRecord {
+            patient_id: patient_id.to_string(),
+            timestamp: Timestamp::parse(timestamp).unwrap(),
+            heart_rate: Some(78),
+            bp_systolic: Some(120),
+            bp_diastolic: Some(80),
+            temperature: Some(36.6),
+            blood_sugar: Some(92.0),
+            steps: Some(4500),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parquet_round_trip() -> Result<(), AktenError> {
+        let file = Builder::new().suffix(".parquet").tempfile()?;
+        let path = file.path().to_str().unwrap();
+        let mut records = [record("1", "2024-12-01"), record("2", "2024-12-02"), record("1", "2024-12-03")];
+        records[1].blood_sugar = None;
+        write_parquet(&records, path, ExportKind::Ai)?;
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?).map_err(parquet_error)?;
+        let metadata: Vec<(String, Option<String>)> = builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .into_iter()
+            .flatten()
+            .map(|kv| (kv.key.clone(), kv.value.clone()))
+            .collect();
+        assert!(metadata.contains(&("aktenakrobat.schema_version".to_string(), Some(SCHEMA_VERSION.to_string()))));
+        assert!(metadata.contains(&("aktenakrobat.export_kind".to_string(), Some("ai".to_string()))));
+
+        let batch = builder.build().map_err(parquet_error)?.next().unwrap().map_err(parquet_error)?;
+        assert_eq!(batch.num_rows(), 3);
+        assert_eq!(batch.schema().as_ref(), &schema());
+        let dates = batch.column(1).as_any().downcast_ref::<Date32Array>().unwrap();
+        assert_eq!(dates.value_as_date(2), NaiveDate::from_ymd_opt(2024, 12, 3));
+        let blood_sugar = batch.column(6).as_any().downcast_ref::<Float32Array>().unwrap();
+        assert_eq!((blood_sugar.null_count(), blood_sugar.is_null(1)), (1, true));
+        let ids = batch.column(0);
+        assert_eq!(ids.data_type(), &DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_measurement_times_are_utc() {
+        let batch = records_to_batch(&[record("4", "2024-12-01T00:30:00+01:00"), record("4", "2024-12-01")]).unwrap();
+        let dates = batch.column(1).as_any().downcast_ref::<Date32Array>().unwrap();
+        assert_eq!(dates.value_as_date(0), NaiveDate::from_ymd_opt(2024, 12, 1));
+        let times = batch.column(16).as_any().downcast_ref::<TimestampMicrosecondArray>().unwrap();
+        assert_eq!(times.value_as_datetime(0).unwrap().to_string(), "2024-11-30 23:30:00");
+        assert!(times.is_null(1));
+    }
+}
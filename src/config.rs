@@ -1,4 +1,11 @@
+use crate::csv_io::{self, Dialect};
+use crate::fhir::VitalField;
+use crate::identity;
+use crate::units::{self, Unit};
+use chrono::format::{Item, StrftimeItems};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::{fs, path::Path};
 use thiserror::Error;
 
@@ -11,15 +18,35 @@ pub enum ConfigError {
     ParseError(#[from] toml::de::Error),
     #[error("Invalid threshold value: {0}")]
     InvalidThreshold(String),
+    #[error("Invalid time setting: {0}")]
+    InvalidTime(String),
+    #[error("Invalid CSV column mapping: {0}")]
+    InvalidMapping(String),
 }
 
 /// Main configuration structure containing all thresholds
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ThresholdConfig {
     pub thresholds: Thresholds,
+    #[serde(default)]
+    pub identity: IdentityConfig,
+    #[serde(default)]
+    pub time: TimeConfig,
+    #[serde(default)]
+    pub fhir: FhirConfig,
+    #[serde(default)]
+    pub openehr: OpenEhrConfig,
+    #[serde(default)]
+    pub omop: OmopConfig,
+    #[serde(default)]
+    pub csv: CsvConfig,
+    /// Thresholds for observations beyond the fixed vitals, `[[observations]]`
+    #[serde(default)]
+    pub observations: Vec<ObservationThreshold>,
 }
 
-/// Collection of all medical thresholds
+/// Collection of all medical thresholds; temperatures are in °C and
+/// glucose in mg/dL, the units records are converted to
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Thresholds {
     #[serde(rename = "critical_hr")]
@@ -30,6 +57,42 @@ pub struct Thresholds {
     pub fever: f32,
     pub hypoglycemia: f32,
     pub hyperglycemia: f32,
+    /// SpO2 (%) below this is hypoxaemia
+    #[serde(default = "default_hypoxemia")]
+    pub hypoxemia: u32,
+    #[serde(rename = "critical_rr", default)]
+    pub respiratory_rate: CriticalRr,
+    /// BMI (kg/m²) bounds of the normal range
+    #[serde(default = "default_underweight_bmi")]
+    pub underweight_bmi: f32,
+    #[serde(default = "default_obesity_bmi")]
+    pub obesity_bmi: f32,
+}
+
+fn default_hypoxemia() -> u32 {
+    92
+}
+
+fn default_underweight_bmi() -> f32 {
+    18.5
+}
+
+fn default_obesity_bmi() -> f32 {
+    30.0
+}
+
+/// Respiratory rate thresholds (breaths/min); the defaults are the NEWS2
+/// bounds that already score 2 or more points
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CriticalRr {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Default for CriticalRr {
+    fn default() -> Self {
+        Self { min: 9, max: 20 }
+    }
 }
 
 /// Heart rate thresholds (bpm)
@@ -46,11 +109,187 @@ pub struct HypertensiveCrisis {
     pub diastolic: u32,
 }
 
+/// Range of an additional observation, such as a pain score, carried in
+/// an extra CSV column or JSON `observations`; values outside it are alerts
+/// in validation and risks in the risk prediction
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObservationThreshold {
+    /// Observation name, as the column header or JSON key
+    pub name: String,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// What a value outside the range means, e.g. `Severe pain`
+    pub label: String,
+    /// Report as a critical alert rather than a warning
+    #[serde(default)]
+    pub critical: bool,
+}
+
+impl ObservationThreshold {
+    pub fn contains(&self, value: f64) -> bool {
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+}
+
+/// Patient identity settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IdentityConfig {
+    /// Identifier system records are grouped by, e.g. the KVNR system
+    /// `http://fhir.de/sid/gkv/kvid-10`; defaults to AktenAkrobat's own
+    /// patient numbers
+    #[serde(default)]
+    pub primary_system: Option<String>,
+}
+
+impl IdentityConfig {
+    pub fn primary_system(&self) -> &str {
+        self.primary_system.as_deref().unwrap_or(identity::LOCAL_SYSTEM)
+    }
+}
+
+/// Measurement timestamp settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeConfig {
+    /// IANA time zone of timestamps given without a UTC offset
+    pub timezone: String,
+    /// chrono formats tried in order after ISO 8601 (`2024-12-01`,
+    /// `2024-12-01T08:30:00+01:00`); a format without an offset is read in
+    /// `timezone`, a date-only one as that whole day
+    pub formats: Vec<String>,
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        let formats = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%d.%m.%Y %H:%M:%S", "%d.%m.%Y %H:%M", "%d.%m.%Y"];
+        Self {
+            timezone: "Europe/Berlin".to_string(),
+            formats: formats.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+impl TimeConfig {
+    /// The configured time zone; `validate` has checked its name
+    pub fn tz(&self) -> Tz {
+        self.timezone.parse().unwrap_or(Tz::UTC)
+    }
+}
+
+/// CSV input settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CsvConfig {
+    /// Dialect of CSV files read; told from each file when not given
+    pub dialect: Option<Dialect>,
+    pub mapping: ColumnMapping,
+}
+
+/// How the columns of foreign CSV files become record fields. Headers are
+/// matched ignoring case and surrounding spaces; columns that are neither
+/// mapped, ignored nor named like a record field are kept as observations
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColumnMapping {
+    /// Record field per column header, e.g. `"Puls" = "heart_rate"`
+    pub columns: BTreeMap<String, String>,
+    /// Columns holding several fields, e.g. `120/80` for both blood pressures
+    pub combined: Vec<CombinedColumn>,
+    /// Columns to skip rather than keep as observations
+    pub ignore: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CombinedColumn {
+    pub column: String,
+    /// Record fields of the parts, in order
+    pub fields: Vec<String>,
+    #[serde(default = "CombinedColumn::default_separator")]
+    pub separator: String,
+}
+
+impl CombinedColumn {
+    fn default_separator() -> String {
+        "/".to_string()
+    }
+}
+
+impl ColumnMapping {
+    /// Reads a mapping file, laid out like `[csv.mapping]` of the config
+    /// file, which replaces the configured mapping
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let mapping: ColumnMapping = toml::from_str(&fs::read_to_string(path)?)?;
+        mapping.validate()?;
+        Ok(mapping)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let targets = self.columns.values().chain(self.combined.iter().flat_map(|c| &c.fields));
+        if let Some(field) = targets.clone().find(|field| !csv_io::is_record_field(field)) {
+            return Err(ConfigError::InvalidMapping(format!("'{}' is not a record field", field)));
+        }
+        if let Some(combined) = self.combined.iter().find(|c| c.fields.len() < 2 || c.separator.is_empty()) {
+            return Err(ConfigError::InvalidMapping(format!(
+                "combined column '{}' needs a separator and at least two fields",
+                combined.column
+            )));
+        }
+        let mut columns = BTreeSet::new();
+        let sources = self.columns.keys().chain(self.combined.iter().map(|c| &c.column)).chain(&self.ignore);
+        if let Some(column) = sources.map(|c| c.trim().to_lowercase()).find(|c| !columns.insert(c.clone())) {
+            return Err(ConfigError::InvalidMapping(format!("column '{}' is given more than once", column)));
+        }
+        Ok(())
+    }
+}
+
+/// FHIR output settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FhirConfig {
+    /// Superseded by `[identity] primary_system`, which it still sets when
+    /// that is not given
+    #[serde(default, skip_serializing)]
+    pub pid_system: Option<String>,
+}
+
+/// openEHR composition settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpenEhrConfig {
+    /// Operational template the compositions are validated against
+    pub template_id: String,
+    pub composer: String,
+    pub health_care_facility: Option<String>,
+    /// ISO 639-1 language and ISO 3166-1 territory codes
+    pub language: String,
+    pub territory: String,
+    /// Namespace of the patient numbers in the subject reference
+    pub subject_namespace: String,
+}
+
+impl Default for OpenEhrConfig {
+    fn default() -> Self {
+        Self {
+            template_id: "AktenAkrobat Vital Signs".to_string(),
+            composer: "AktenAkrobat".to_string(),
+            health_care_facility: None,
+            language: "de".to_string(),
+            territory: "DE".to_string(),
+            subject_namespace: "aktenakrobat".to_string(),
+        }
+    }
+}
+
 impl ThresholdConfig {
     /// Loads and validates configuration from a TOML file
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
         let content = fs::read_to_string(path)?;
-        let config: ThresholdConfig = toml::from_str(&content)?;
+        let mut config: ThresholdConfig = toml::from_str(&content)?;
+        if config.identity.primary_system.is_none() {
+            config.identity.primary_system = config.fhir.pid_system.take();
+        }
         config.validate()?;
         Ok(config)
     }
@@ -65,8 +304,8 @@ impl ThresholdConfig {
         }
 
         // Validate blood pressure thresholds
-        if self.thresholds.blood_pressure.systolic <= 0
-            || self.thresholds.blood_pressure.diastolic <= 0
+        if self.thresholds.blood_pressure.systolic == 0
+            || self.thresholds.blood_pressure.diastolic == 0
         {
             return Err(ConfigError::InvalidThreshold(
                 "Blood pressure values must be positive".to_string(),
@@ -80,6 +319,33 @@ impl ThresholdConfig {
             ));
         }
 
+        // Validate respiratory thresholds
+        if self.thresholds.respiratory_rate.min >= self.thresholds.respiratory_rate.max {
+            return Err(ConfigError::InvalidThreshold(
+                "Respiratory rate min must be less than max".to_string(),
+            ));
+        }
+        if self.thresholds.hypoxemia == 0 || self.thresholds.hypoxemia > 100 {
+            return Err(ConfigError::InvalidThreshold(
+                "Hypoxemia threshold must be a saturation between 1 and 100%".to_string(),
+            ));
+        }
+
+        // Validate BMI thresholds
+        if self.thresholds.underweight_bmi >= self.thresholds.obesity_bmi {
+            return Err(ConfigError::InvalidThreshold(
+                "Underweight BMI must be lower than obesity BMI".to_string(),
+            ));
+        }
+
+        // Validate timestamp settings
+        if self.time.timezone.parse::<Tz>().is_err() {
+            return Err(ConfigError::InvalidTime(format!("unknown time zone '{}'", self.time.timezone)));
+        }
+        if let Some(format) = self.time.formats.iter().find(|f| StrftimeItems::new(f).any(|i| i == Item::Error)) {
+            return Err(ConfigError::InvalidTime(format!("invalid timestamp format '{}'", format)));
+        }
+
         // Validate glucose thresholds
         if self.thresholds.hypoglycemia >= self.thresholds.hyperglycemia {
             return Err(ConfigError::InvalidThreshold(
@@ -87,10 +353,102 @@ impl ThresholdConfig {
             ));
         }
 
+        // Thresholds in another unit would misclassify every record
+        let in_unit = |field, value: f32, unit| units::detect(field, f64::from(value)) == Some(unit);
+        if [self.thresholds.hypothermia, self.thresholds.fever]
+            .iter()
+            .any(|t| in_unit(VitalField::Temperature, *t, Unit::Fahrenheit))
+        {
+            return Err(ConfigError::InvalidThreshold(
+                "Temperature thresholds must be given in °C, not °F".to_string(),
+            ));
+        }
+        if [self.thresholds.hypoglycemia, self.thresholds.hyperglycemia]
+            .iter()
+            .any(|t| in_unit(VitalField::BloodSugar, *t, Unit::MillimolesPerLiter))
+        {
+            return Err(ConfigError::InvalidThreshold(
+                "Glucose thresholds must be given in mg/dL, not mmol/L (3.9 mmol/L is 70 mg/dL)".to_string(),
+            ));
+        }
+
+        // Validate the CSV column mapping
+        self.csv.mapping.validate()?;
+
+        // Validate observation thresholds
+        for threshold in &self.observations {
+            if threshold.name.trim().is_empty() || csv_io::is_record_field(&threshold.name) {
+                return Err(ConfigError::InvalidThreshold(format!(
+                    "Observation threshold '{}' must name an observation that is not a record field",
+                    threshold.name
+                )));
+            }
+            match (threshold.min, threshold.max) {
+                (None, None) => {
+                    return Err(ConfigError::InvalidThreshold(format!(
+                        "Observation threshold '{}' needs a min or a max",
+                        threshold.name
+                    )));
+                }
+                (Some(min), Some(max)) if min >= max => {
+                    return Err(ConfigError::InvalidThreshold(format!(
+                        "Observation threshold '{}' min must be less than max",
+                        threshold.name
+                    )));
+                }
+                _ => {}
+            }
+        }
+
         Ok(())
     }
 }
 
+/// OMOP CDM export settings; a table given in the config file replaces the default one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OmopConfig {
+    /// Type concept of measurements and observation periods (32817 = EHR)
+    pub type_concept_id: i64,
+    /// Standard measurement concept per LOINC code
+    pub measurement_concepts: BTreeMap<String, i64>,
+    /// Standard unit concept per UCUM code
+    pub unit_concepts: BTreeMap<String, i64>,
+}
+
+impl Default for OmopConfig {
+    fn default() -> Self {
+        let measurement_concepts = [
+            ("8867-4", 3027018),
+            ("8480-6", 3004249),
+            ("8462-4", 3012888),
+            ("8310-5", 3020891),
+            ("2339-0", 3000483),
+            ("59408-5", 40762499),
+            ("9279-1", 3024171),
+            ("29463-7", 3025315),
+            ("8302-2", 3036277),
+            ("39156-5", 3038553),
+        ];
+        let unit_concepts = [
+            ("/min", 8541),
+            ("mm[Hg]", 8876),
+            ("Cel", 586323),
+            ("mg/dL", 8840),
+            ("mmol/L", 8753),
+            ("%", 8554),
+            ("kg", 9529),
+            ("cm", 8582),
+            ("kg/m2", 9531),
+        ];
+        Self {
+            type_concept_id: 32817,
+            measurement_concepts: measurement_concepts.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+            unit_concepts: unit_concepts.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,9 +464,20 @@ mod tests {
                 },
                 hypothermia: 35.0,
                 fever: 38.0,
-                hypoglycemia: 3.9,
-                hyperglycemia: 7.0,
+                hypoglycemia: 70.0,
+                hyperglycemia: 126.0,
+                hypoxemia: 92,
+                respiratory_rate: CriticalRr::default(),
+                underweight_bmi: 18.5,
+                obesity_bmi: 30.0,
             },
+            identity: IdentityConfig::default(),
+            time: TimeConfig::default(),
+            fhir: FhirConfig::default(),
+            openehr: OpenEhrConfig::default(),
+            omop: OmopConfig::default(),
+            csv: CsvConfig::default(),
+            observations: vec![],
         };
 
         assert!(config.validate().is_ok());
@@ -127,9 +496,94 @@ mod tests {
                 fever: 38.0,
                 hypoglycemia: 7.0, // Invalid (higher than hyperglycemia)
                 hyperglycemia: 3.9,
+                hypoxemia: 92,
+                respiratory_rate: CriticalRr::default(),
+                underweight_bmi: 18.5,
+                obesity_bmi: 30.0,
             },
+            identity: IdentityConfig::default(),
+            time: TimeConfig::default(),
+            fhir: FhirConfig::default(),
+            openehr: OpenEhrConfig::default(),
+            omop: OmopConfig::default(),
+            csv: CsvConfig::default(),
+            observations: vec![],
         };
 
         assert!(invalid_config.validate().is_err());
+
+        // Ordered correctly, but in mmol/L
+        let mut mmol_config = invalid_config;
+        mmol_config.thresholds.heart_rate = CriticalHr { min: 60, max: 100 };
+        (mmol_config.thresholds.hypoglycemia, mmol_config.thresholds.hyperglycemia) = (3.9, 7.0);
+        assert!(matches!(mmol_config.validate(), Err(ConfigError::InvalidThreshold(m)) if m.contains("mg/dL")));
+    }
+
+    #[test]
+    fn test_legacy_pid_system_sets_primary_identifier() {
+        let file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        let thresholds = "[thresholds]\ncritical_hr = { min = 50, max = 90 }\n\
+            hypertensive_crisis = { systolic = 150, diastolic = 100 }\n\
+            hypothermia = 35.0\nfever = 38.0\nhypoglycemia = 70.0\nhyperglycemia = 400.0\n";
+        fs::write(file.path(), format!("{}[fhir]\npid_system = \"urn:oid:1.2.276.0.1\"\n", thresholds)).unwrap();
+        assert_eq!(ThresholdConfig::load(file.path()).unwrap().identity.primary_system(), "urn:oid:1.2.276.0.1");
+
+        fs::write(file.path(), thresholds).unwrap();
+        assert_eq!(ThresholdConfig::load(file.path()).unwrap().identity.primary_system(), identity::LOCAL_SYSTEM);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_time_settings_are_validated() {
+        let file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        let thresholds = "[thresholds]\ncritical_hr = { min = 50, max = 90 }\n\
+            hypertensive_crisis = { systolic = 150, diastolic = 100 }\n\
+            hypothermia = 35.0\nfever = 38.0\nhypoglycemia = 70.0\nhyperglycemia = 400.0\n";
+        fs::write(file.path(), format!("{}[time]\ntimezone = \"UTC\"\nformats = [\"%d/%m/%Y %H:%M\"]\n", thresholds)).unwrap();
+        let config = ThresholdConfig::load(file.path()).unwrap();
+        assert_eq!((config.time.tz(), config.time.formats.len()), (Tz::UTC, 1));
+
+        fs::write(file.path(), format!("{}[time]\ntimezone = \"Europe/Berln\"\n", thresholds)).unwrap();
+        assert!(matches!(ThresholdConfig::load(file.path()), Err(ConfigError::InvalidTime(_))));
+        fs::write(file.path(), format!("{}[time]\nformats = [\"%d.%m.%Y %Q\"]\n", thresholds)).unwrap();
+        assert!(matches!(ThresholdConfig::load(file.path()), Err(ConfigError::InvalidTime(_))));
+    }
+
+    #[test]
+    fn test_column_mapping_is_validated() {
+        let file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        fs::write(
+            file.path(),
+            "ignore = [\"Bemerkung\"]\n[columns]\nPuls = \"heart_rate\"\nDatum = \"date\"\n\
+             [[combined]]\ncolumn = \"RR\"\nfields = [\"bp_systolic\", \"bp_diastolic\"]\n",
+        )
+        .unwrap();
+        let mapping = ColumnMapping::load(file.path()).unwrap();
+        assert_eq!((mapping.columns.len(), mapping.combined[0].separator.as_str()), (2, "/"));
+
+        fs::write(file.path(), "[columns]\nPuls = \"pulse\"\n").unwrap();
+        assert!(matches!(ColumnMapping::load(file.path()), Err(ConfigError::InvalidMapping(_))));
+        fs::write(file.path(), "ignore = [\"puls\"]\n[columns]\nPuls = \"heart_rate\"\n").unwrap();
+        assert!(matches!(ColumnMapping::load(file.path()), Err(ConfigError::InvalidMapping(_))));
+    }
+
+    #[test]
+    fn test_observation_thresholds_are_validated() {
+        let file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        let thresholds = "[thresholds]\ncritical_hr = { min = 50, max = 90 }\n\
+            hypertensive_crisis = { systolic = 150, diastolic = 100 }\n\
+            hypothermia = 35.0\nfever = 38.0\nhypoglycemia = 70.0\nhyperglycemia = 400.0\n";
+        let observation = |range: &str| {
+            format!("{}[[observations]]\nname = \"pain_score\"\nlabel = \"Severe pain\"\n{}\n", thresholds, range)
+        };
+        fs::write(file.path(), observation("max = 6")).unwrap();
+        let config = ThresholdConfig::load(file.path()).unwrap();
+        assert_eq!((config.observations[0].contains(6.0), config.observations[0].contains(7.0)), (true, false));
+
+        fs::write(file.path(), observation("")).unwrap();
+        assert!(matches!(ThresholdConfig::load(file.path()), Err(ConfigError::InvalidThreshold(_))));
+        fs::write(file.path(), observation("min = 8\nmax = 2")).unwrap();
+        assert!(matches!(ThresholdConfig::load(file.path()), Err(ConfigError::InvalidThreshold(_))));
+        fs::write(file.path(), observation("max = 6").replace("pain_score", "heart_rate")).unwrap();
+        assert!(matches!(ThresholdConfig::load(file.path()), Err(ConfigError::InvalidThreshold(_))));
+    }
+}
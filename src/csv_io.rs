@@ -0,0 +1,521 @@
+use crate::config::{ColumnMapping, CsvConfig};
+use crate::observations::{ObservationValue, Observations};
+use crate::provenance::Provenance;
+use crate::timestamp::Timestamp;
+use crate::{AktenError, PatientRecord};
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::sync::OnceLock;
+use tracing::warn;
+
+static SETTINGS: OnceLock<CsvConfig> = OnceLock::new();
+
+/// Fields a record cannot be read without; `timestamp` is usually a `date` column
+const REQUIRED_FIELDS: [&str; 2] = ["patient_id", "timestamp"];
+
+/// Record fields holding decimals, the only ones read and written with a
+/// decimal comma in the German dialect
+const DECIMAL_FIELDS: [&str; 4] = ["temperature", "blood_sugar", "weight", "height"];
+
+const UTF8_BOM: &str = "\u{feff}";
+
+/// Delimiter and notation of numbers and dates in a CSV file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    /// Comma, decimal point and ISO 8601 timestamps
+    #[default]
+    Standard,
+    /// Semicolon, decimal comma and `DD.MM.YYYY HH:MM` timestamps, as German
+    /// Excel and practice software write them
+    German,
+}
+
+impl Dialect {
+    pub fn parse(value: &str) -> Result<Self, AktenError> {
+        match value.to_lowercase().as_str() {
+            "standard" => Ok(Self::Standard),
+            "german" | "de" => Ok(Self::German),
+            other => Err(AktenError::CsvDialect(format!(
+                "unsupported dialect '{}' (expected standard or german)",
+                other
+            ))),
+        }
+    }
+
+    fn delimiter(&self) -> u8 {
+        match self {
+            Self::Standard => b',',
+            Self::German => b';',
+        }
+    }
+
+    /// The dialect of a file told by its header row: more semicolons than
+    /// commas mean German
+    pub fn sniff(text: &str) -> Self {
+        let header = text.lines().next().unwrap_or_default();
+        if header.matches(';').count() > header.matches(',').count() {
+            Self::German
+        } else {
+            Self::Standard
+        }
+    }
+}
+
+/// Character encoding of written CSV files; files read are UTF-8 if they
+/// decode as such, Windows-1252 otherwise
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Windows1252,
+}
+
+impl Encoding {
+    pub fn parse(value: &str) -> Result<Self, AktenError> {
+        match value.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(Self::Utf8),
+            "windows-1252" | "cp1252" | "ansi" => Ok(Self::Windows1252),
+            other => Err(AktenError::CsvDialect(format!(
+                "unsupported encoding '{}' (expected utf-8 or windows-1252)",
+                other
+            ))),
+        }
+    }
+
+    /// `text` in this encoding; characters Windows-1252 lacks become `?`
+    fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            Self::Utf8 => text.as_bytes().to_vec(),
+            Self::Windows1252 => text.chars().map(to_windows_1252).collect(),
+        }
+    }
+}
+
+/// Text of a CSV file without its byte order mark
+fn decode(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(text) => match text.strip_prefix(UTF8_BOM) {
+            Some(rest) => rest.to_string(),
+            None => text,
+        },
+        Err(e) => e.into_bytes().into_iter().map(from_windows_1252).collect(),
+    }
+}
+
+/// Windows-1252 is Latin-1 apart from 0x80-0x9F; the five unassigned
+/// positions there are kept as the C1 controls
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+fn from_windows_1252(byte: u8) -> char {
+    match byte {
+        0x80..=0x9f => WINDOWS_1252_HIGH[(byte - 0x80) as usize],
+        b => b as char,
+    }
+}
+
+fn to_windows_1252(c: char) -> u8 {
+    match u32::from(c) {
+        code @ (0..=0x7f | 0xa0..=0xff) => code as u8,
+        _ => WINDOWS_1252_HIGH.iter().position(|h| *h == c).map_or(b'?', |i| 0x80 + i as u8),
+    }
+}
+
+/// Use the column mapping and dialect of `config` for all CSV files read
+/// from now on; without this call headers must be record fields and the
+/// dialect is told from each file. Only the first call counts
+pub fn configure(config: &CsvConfig) {
+    let _ = SETTINGS.set(config.clone());
+}
+
+fn settings() -> &'static CsvConfig {
+    SETTINGS.get_or_init(CsvConfig::default)
+}
+
+/// Names of the record fields as CSV columns
+fn record_fields() -> &'static BTreeSet<String> {
+    static FIELDS: OnceLock<BTreeSet<String>> = OnceLock::new();
+    // Fields only written when set must be set to show up
+    let record = PatientRecord { provenance: Some(Provenance::new("", "")), ..Default::default() };
+    FIELDS.get_or_init(|| match serde_json::to_value(record) {
+        Ok(serde_json::Value::Object(fields)) => fields.keys().cloned().collect(),
+        _ => BTreeSet::new(),
+    })
+}
+
+/// The record field a column name stands for, if any; `date` is read as `timestamp`
+fn record_field(name: &str) -> Option<String> {
+    match name.trim().to_lowercase().as_str() {
+        "date" => Some("timestamp".to_string()),
+        name => record_fields().get(name).cloned(),
+    }
+}
+
+pub fn is_record_field(name: &str) -> bool {
+    record_field(name).is_some()
+}
+
+/// What becomes of one column
+#[derive(Debug, PartialEq)]
+enum Target {
+    Field(String),
+    Split { fields: Vec<String>, separator: String },
+    /// An observation beyond the fixed vitals, named by the header
+    Observation(String),
+    Skip,
+}
+
+/// The targets of a file's columns and the record fields they fill
+#[derive(Debug)]
+struct Plan {
+    targets: Vec<Target>,
+    headers: StringRecord,
+}
+
+impl Plan {
+    fn new(mapping: &ColumnMapping, headers: &StringRecord, source: &str) -> Result<Self, AktenError> {
+        let key = |name: &str| name.trim().to_lowercase();
+        let mut targets = vec![];
+        let mut observations = vec![];
+        for header in headers {
+            let target = if let Some(field) = mapping.columns.iter().find(|(c, _)| key(c) == key(header)) {
+                Target::Field(record_field(field.1).unwrap_or_default())
+            } else if let Some(combined) = mapping.combined.iter().find(|c| key(&c.column) == key(header)) {
+                Target::Split {
+                    fields: combined.fields.iter().filter_map(|f| record_field(f)).collect(),
+                    separator: combined.separator.clone(),
+                }
+            } else if mapping.ignore.iter().any(|c| key(c) == key(header)) {
+                Target::Skip
+            } else if let Some(field) = record_field(header) {
+                Target::Field(field)
+            } else {
+                observations.push(header);
+                Target::Observation(header.trim().to_string())
+            };
+            targets.push(target);
+        }
+
+        let mut headers = StringRecord::new();
+        for target in &targets {
+            match target {
+                Target::Field(field) => headers.push_field(field),
+                Target::Split { fields, .. } => fields.iter().for_each(|f| headers.push_field(f)),
+                Target::Observation(_) | Target::Skip => {}
+            }
+        }
+        let mut seen = BTreeSet::new();
+        if let Some(field) = headers.iter().find(|f| !seen.insert(*f)) {
+            return Err(AktenError::CsvMapping(format!("'{}' has more than one column for {}", source, field)));
+        }
+        let missing: Vec<&str> = REQUIRED_FIELDS.into_iter().filter(|f| !seen.contains(f)).collect();
+        if !missing.is_empty() {
+            return Err(AktenError::CsvMapping(format!(
+                "'{}' has no column for the required fields {}; unmapped columns: {}",
+                source,
+                missing.join(", "),
+                if observations.is_empty() { "none".to_string() } else { observations.join(", ") }
+            )));
+        }
+        Ok(Self { targets, headers })
+    }
+
+    /// `row` as values of `headers`, decimals with a point; `line` is for errors
+    fn apply(&self, row: &StringRecord, line: u64, source: &str, dialect: Dialect) -> Result<StringRecord, AktenError> {
+        let mut values = StringRecord::new();
+        for (index, target) in self.targets.iter().enumerate() {
+            let value = row.get(index).unwrap_or("").trim();
+            match target {
+                Target::Field(_) => values.push_field(value),
+                Target::Split { fields, .. } if value.is_empty() => fields.iter().for_each(|_| values.push_field("")),
+                Target::Split { fields, separator } => {
+                    let parts: Vec<&str> = value.split(separator.as_str()).map(str::trim).collect();
+                    if parts.len() != fields.len() {
+                        return Err(AktenError::CsvMapping(format!(
+                            "'{}' line {}: '{}' does not split at '{}' into {}",
+                            source,
+                            line,
+                            value,
+                            separator,
+                            fields.join(", ")
+                        )));
+                    }
+                    parts.into_iter().for_each(|part| values.push_field(part));
+                }
+                Target::Observation(_) | Target::Skip => {}
+            }
+        }
+        if dialect == Dialect::German {
+            values = values
+                .iter()
+                .zip(&self.headers)
+                .map(|(value, field)| if DECIMAL_FIELDS.contains(&field) { value.replace(',', ".") } else { value.to_string() })
+                .collect();
+        }
+        Ok(values)
+    }
+
+    /// The non-empty observation columns of `row`
+    fn observations(&self, row: &StringRecord, dialect: Dialect) -> Observations {
+        let mut observations = Observations::default();
+        for (index, target) in self.targets.iter().enumerate() {
+            let value = row.get(index).unwrap_or("").trim();
+            if let (Target::Observation(name), false) = (target, value.is_empty()) {
+                let value = match (dialect, ObservationValue::parse(&value.replace(',', "."))) {
+                    (Dialect::German, number @ ObservationValue::Number(_)) => number,
+                    _ => ObservationValue::parse(value),
+                };
+                observations.insert(name, value);
+            }
+        }
+        observations
+    }
+}
+
+/// Read records from CSV with a header row in the configured or sniffed
+/// dialect, mapping its columns to record fields with the configured
+/// `[csv.mapping]`; `source` names the file in messages
+pub fn read_records<R: Read>(reader: R, source: &str) -> Result<Vec<PatientRecord>, AktenError> {
+    read_with(reader, source, &settings().mapping, settings().dialect)
+}
+
+fn read_with<R: Read>(
+    mut reader: R,
+    source: &str,
+    mapping: &ColumnMapping,
+    dialect: Option<Dialect>,
+) -> Result<Vec<PatientRecord>, AktenError> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    let text = decode(bytes);
+    let dialect = dialect.unwrap_or_else(|| Dialect::sniff(&text));
+    let mut reader = ReaderBuilder::new().flexible(true).delimiter(dialect.delimiter()).from_reader(text.as_bytes());
+    let plan = Plan::new(mapping, reader.headers()?, source)?;
+    let provenance = Provenance::new(source, "csv");
+    let mut records = vec![];
+    for row in reader.records() {
+        let row = row?;
+        let line = row.position().map_or(0, |p| p.line());
+        let mut record: PatientRecord = plan.apply(&row, line, source, dialect)?.deserialize(Some(&plan.headers))?;
+        record.observations = plan.observations(&row, dialect);
+        // A provenance column, as in merged files, names the original input
+        if record.provenance.is_none() {
+            record.provenance = Some(provenance.at(format!("line {}", line)));
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// How CSV files are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CsvOutput {
+    pub dialect: Dialect,
+    pub encoding: Encoding,
+    /// Start UTF-8 files with a byte order mark, by which Excel tells the encoding
+    pub bom: bool,
+}
+
+impl CsvOutput {
+    pub fn new(dialect: Dialect, encoding: Encoding, bom: bool) -> Result<Self, AktenError> {
+        if bom && encoding != Encoding::Utf8 {
+            return Err(AktenError::CsvDialect("a byte order mark is only written with UTF-8".to_string()));
+        }
+        Ok(Self { dialect, encoding, bom })
+    }
+}
+
+/// Write `records` with a header row as `output` asks; observations follow
+/// the record fields, one column each
+pub fn write_records<W: Write>(
+    mut writer: W,
+    records: &[PatientRecord],
+    output: &CsvOutput,
+    flexible: bool,
+) -> Result<(), AktenError> {
+    let mut standard = WriterBuilder::new().has_headers(true).flexible(flexible).from_writer(vec![]);
+    for record in records {
+        standard.serialize(PatientRecord { observations: Observations::default(), ..record.clone() })?;
+    }
+    let mut standard = standard.into_inner().map_err(|e| e.into_error())?;
+    if records.iter().any(|r| !r.observations.is_empty()) {
+        standard = with_observations(&standard, records)?;
+    }
+
+    let mut text = String::new();
+    if output.bom {
+        text.push_str(UTF8_BOM);
+    }
+    match output.dialect {
+        Dialect::Standard => text.push_str(&String::from_utf8_lossy(&standard)),
+        Dialect::German => text.push_str(&to_german(&standard, flexible)?),
+    }
+    let bytes = output.encoding.encode(&text);
+    if output.encoding == Encoding::Windows1252 && bytes.iter().filter(|b| **b == b'?').count() > text.matches('?').count() {
+        warn!("Characters without a Windows-1252 code were written as '?'");
+    }
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Standard CSV of `records` with a column per observation name appended
+fn with_observations(standard: &[u8], records: &[PatientRecord]) -> Result<Vec<u8>, AktenError> {
+    let names: BTreeSet<&String> = records.iter().flat_map(|r| r.observations.iter().map(|(name, _)| name)).collect();
+    let mut reader = ReaderBuilder::new().flexible(true).from_reader(standard);
+    let mut writer = WriterBuilder::new().from_writer(vec![]);
+    let mut headers = reader.headers()?.clone();
+    let width = headers.len();
+    names.iter().for_each(|name| headers.push_field(name));
+    writer.write_record(&headers)?;
+    for (row, record) in reader.records().zip(records) {
+        let mut row = row?;
+        while row.len() < width {
+            row.push_field("");
+        }
+        for name in &names {
+            row.push_field(&record.observations.get(name).map(ToString::to_string).unwrap_or_default());
+        }
+        writer.write_record(&row)?;
+    }
+    Ok(writer.into_inner().map_err(|e| e.into_error())?)
+}
+
+/// Standard CSV rewritten with semicolons, decimal commas (in numeric
+/// observations as well) and German timestamps
+fn to_german(standard: &[u8], flexible: bool) -> Result<String, AktenError> {
+    let mut reader = ReaderBuilder::new().flexible(flexible).from_reader(standard);
+    let mut writer = WriterBuilder::new().flexible(flexible).delimiter(Dialect::German.delimiter()).from_writer(vec![]);
+    let headers = reader.headers()?.clone();
+    if !headers.is_empty() {
+        writer.write_record(&headers)?;
+    }
+    for row in reader.records() {
+        let row: Vec<String> = row?
+            .iter()
+            .zip(&headers)
+            .map(|(value, field)| match field {
+                "timestamp" => Timestamp::parse(value).map_or_else(|_| value.to_string(), |t| t.to_german()),
+                field if DECIMAL_FIELDS.contains(&field) => value.replace('.', ","),
+                field if !is_record_field(field) && matches!(ObservationValue::parse(value), ObservationValue::Number(_)) => {
+                    value.replace('.', ",")
+                }
+                _ => value.to_string(),
+            })
+            .collect();
+        writer.write_record(&row)?;
+    }
+    let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CombinedColumn;
+
+    fn clinic_mapping() -> ColumnMapping {
+        ColumnMapping {
+            columns: [("Pat-Nr", "patient_id"), ("Datum", "date"), ("Puls", "heart_rate"), ("Blutzucker", "blood_sugar")]
+                .into_iter()
+                .map(|(c, f)| (c.to_string(), f.to_string()))
+                .collect(),
+            combined: vec![CombinedColumn {
+                column: "RR".to_string(),
+                fields: vec!["bp_systolic".to_string(), "bp_diastolic".to_string()],
+                separator: "/".to_string(),
+            }],
+            ignore: vec!["Bemerkung".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_foreign_headers_are_mapped() {
+        let csv = "Pat-Nr,Datum,Puls,RR,Blutzucker,Bemerkung,spo2\n\
+                   17,2024-06-14,72,120/80,95,nüchtern,97\n\
+                   17,2024-06-15,,,,,\n";
+        let records = read_with(csv.as_bytes(), "clinic.csv", &clinic_mapping(), None).unwrap();
+        assert_eq!(records.len(), 2);
+        let first = &records[0];
+        assert_eq!((first.patient_id.as_str(), first.heart_rate, first.spo2), ("17", Some(72), Some(97)));
+        assert_eq!((first.bp_systolic, first.bp_diastolic, first.blood_sugar), (Some(120), Some(80), Some(95.0)));
+        assert_eq!((records[1].heart_rate, records[1].bp_systolic), (None, None));
+
+        // Files with the record's own headers need no mapping
+        let native = "patient_id,date,heart_rate\n3,2024-06-14,80\n";
+        assert_eq!(read_with(native.as_bytes(), "native.csv", &ColumnMapping::default(), None).unwrap()[0].heart_rate, Some(80));
+    }
+
+    #[test]
+    fn test_unmapped_required_fields_and_bad_values_are_reported() {
+        let csv = "Patient,Messzeit,Puls\n17,2024-06-14,72\n";
+        let error = read_with(csv.as_bytes(), "clinic.csv", &clinic_mapping(), None).unwrap_err().to_string();
+        assert!(error.contains("patient_id, timestamp") && error.contains("Patient, Messzeit"), "{}", error);
+
+        let csv = "Pat-Nr,Datum,RR\n17,2024-06-14,120\n";
+        let error = read_with(csv.as_bytes(), "clinic.csv", &clinic_mapping(), None).unwrap_err().to_string();
+        assert!(error.contains("line 2"), "{}", error);
+
+        let csv = "Pat-Nr,patient_id,Datum\n17,17,2024-06-14\n";
+        assert!(read_with(csv.as_bytes(), "clinic.csv", &clinic_mapping(), None).is_err());
+    }
+
+    #[test]
+    fn test_german_excel_files_round_trip() {
+        // Windows-1252, as German Excel saves "CSV (Trennzeichen-getrennt)"
+        let mut file = b"Pat-Nr;Datum;Temp;Blutzucker;Bemerkung\r\n17;26.05.2025 08:15;36,6;94;".to_vec();
+        file.extend(b"n\xfcchtern\r\n");
+        let mut mapping = clinic_mapping();
+        mapping.columns.insert("Temp".to_string(), "temperature".to_string());
+        let records = read_with(file.as_slice(), "excel.csv", &mapping, None).unwrap();
+        assert_eq!((records[0].temperature, records[0].blood_sugar), (Some(36.6), Some(94.0)));
+        assert_eq!(records[0].timestamp.to_string(), "2025-05-26T08:15:00+02:00");
+        assert_eq!(decode(b"n\xfcchtern \x80".to_vec()), "nüchtern €");
+
+        let output = CsvOutput::new(Dialect::German, Encoding::Windows1252, false).unwrap();
+        let mut written = vec![];
+        write_records(&mut written, &records, &output, false).unwrap();
+        let text = decode(written.clone());
+        assert!(text.contains(";26.05.2025 08:15;") && text.contains(";36,6;"), "{}", text);
+        let again = read_with(written.as_slice(), "export.csv", &ColumnMapping::default(), None).unwrap();
+        assert_eq!((again[0].timestamp, again[0].temperature), (records[0].timestamp, Some(36.6)));
+
+        let mut written = vec![];
+        write_records(&mut written, &records, &CsvOutput::new(Dialect::Standard, Encoding::Utf8, true).unwrap(), false).unwrap();
+        assert!(written.starts_with(UTF8_BOM.as_bytes()));
+        assert_eq!(read_with(written.as_slice(), "bom.csv", &ColumnMapping::default(), None).unwrap()[0].patient_id, "17");
+        assert!(CsvOutput::new(Dialect::German, Encoding::Windows1252, true).is_err());
+    }
+    #[test]
+    fn test_unknown_columns_are_kept_as_observations() {
+        let csv = "Pat-Nr,Datum,Puls,Schmerz,Geräte-Nr\n17,2024-06-14,72,4,0042\n18,2024-06-14,80,,0043\n";
+        let records = read_with(csv.as_bytes(), "clinic.csv", &clinic_mapping(), None).unwrap();
+        assert_eq!(records[0].observations.get("Schmerz"), Some(&ObservationValue::Number(4.0)));
+        assert_eq!(records[0].observations.get("Geräte-Nr").map(ToString::to_string).as_deref(), Some("0042"));
+        assert_eq!(records[1].observations.get("Schmerz"), None, "empty cells are not observations");
+
+        let mut written = vec![];
+        write_records(&mut written, &records, &CsvOutput::default(), false).unwrap();
+        let text = String::from_utf8(written.clone()).unwrap();
+        assert!(text.lines().next().unwrap().ends_with(",Geräte-Nr,Schmerz"), "{}", text);
+        let again = read_with(written.as_slice(), "merged.csv", &ColumnMapping::default(), None).unwrap();
+        assert_eq!(
+            again.iter().map(|r| r.observations.clone()).collect::<Vec<_>>(),
+            records.iter().map(|r| r.observations.clone()).collect::<Vec<_>>()
+        );
+
+        // Numeric observations take the decimal comma in the German dialect
+        let mut records = records;
+        records[0].observations.insert("Schmerz", ObservationValue::parse("4.5"));
+        let mut written = vec![];
+        write_records(&mut written, &records, &CsvOutput::new(Dialect::German, Encoding::Utf8, false).unwrap(), false).unwrap();
+        assert!(String::from_utf8(written.clone()).unwrap().contains(";0042;4,5"));
+        let again = read_with(written.as_slice(), "excel.csv", &ColumnMapping::default(), None).unwrap();
+        assert_eq!(again[0].observations.get("Schmerz"), Some(&ObservationValue::Number(4.5)));
+    }
+}
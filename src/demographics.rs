@@ -0,0 +1,194 @@
+use crate::fhir;
+use crate::identity::{self, Identifier, Identifiers};
+use crate::{AktenError, PatientRecord};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Age (years) from which adult reference values, such as the BMI
+/// categories, apply
+pub const ADULT_AGE: u32 = 18;
+
+/// Administrative sex, written as the FHIR `Patient.gender` code; read from
+/// the code, its first letter or the German w/m/d in any case
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", rename_all = "lowercase")]
+pub enum Sex {
+    Female,
+    Male,
+    Other,
+    Unknown,
+}
+
+impl TryFrom<String> for Sex {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value).ok_or_else(|| format!("'{}' is not a sex (female, male, other or unknown)", value))
+    }
+}
+
+impl Sex {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Female => "female",
+            Self::Male => "male",
+            Self::Other => "other",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "female" | "f" | "w" | "weiblich" => Some(Self::Female),
+            "male" | "m" | "männlich" => Some(Self::Male),
+            "other" | "o" | "d" | "divers" | "x" => Some(Self::Other),
+            "unknown" | "u" | "unbekannt" => Some(Self::Unknown),
+            _ => None,
+        }
+    }
+}
+
+/// Birth date and sex of one patient, found by its `patient_id` (the
+/// patient number, as in the vital records) or any of its identifiers
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Demographics {
+    pub patient_id: String,
+    #[serde(default)]
+    pub identifiers: Identifiers,
+    /// `YYYY-MM-DD`
+    #[serde(default)]
+    pub birth_date: Option<NaiveDate>,
+    #[serde(default)]
+    pub sex: Option<Sex>,
+}
+
+impl Demographics {
+    fn keys(&self) -> impl Iterator<Item = Identifier> + '_ {
+        let own = Some(Identifier::new(identity::LOCAL_SYSTEM, &self.patient_id)).filter(|_| !self.patient_id.is_empty());
+        own.into_iter().chain(self.identifiers.iter().cloned())
+    }
+}
+
+/// Read demographics from a CSV or JSON file with the `Demographics` columns,
+/// or from the Patient resources of a FHIR Bundle
+pub fn load_demographics(path: &str) -> Result<Vec<Demographics>, AktenError> {
+    if !Path::new(path).exists() {
+        return Err(AktenError::InvalidPath(path.into()));
+    }
+    info!(path, "Loading demographics");
+    match path.rsplit('.').next() {
+        Some("csv") => csv::Reader::from_reader(File::open(path)?)
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(Into::into),
+        Some("json") => serde_json::from_reader(File::open(path)?).map_err(Into::into),
+        Some("fhir") => fhir::parse_patients(&fs::read_to_string(path)?),
+        _ => Err(AktenError::Demographics(format!("'{}' is not a .csv, .json or .fhir file", path))),
+    }
+}
+
+/// Outcome of joining demographics onto records
+#[derive(Debug, Default)]
+pub struct DemographicsReport {
+    /// Records that got a birth date or sex
+    pub joined: usize,
+    /// Patients without an entry, by `patient_id`
+    pub missing: Vec<String>,
+}
+
+/// Fill in birth date and sex of each record from the entry sharing one of
+/// its identifiers; what a record already carries is kept
+pub fn join_demographics(
+    records: &mut [PatientRecord],
+    demographics: &[Demographics],
+    primary_system: &str,
+) -> DemographicsReport {
+    let mut index: HashMap<Identifier, &Demographics> = HashMap::new();
+    for entry in demographics {
+        for key in entry.keys() {
+            index.entry(key).or_insert(entry);
+        }
+    }
+
+    let mut report = DemographicsReport::default();
+    let mut missing = BTreeSet::new();
+    for record in records.iter_mut() {
+        let primary = identity::patient_identifier(record, primary_system);
+        let Some(entry) = std::iter::once(&primary)
+            .chain(record.identifiers.iter())
+            .find_map(|key| index.get(key))
+        else {
+            missing.insert(record.patient_id.clone());
+            continue;
+        };
+        let before = (record.birth_date, record.sex);
+        record.birth_date = record.birth_date.or(entry.birth_date);
+        record.sex = record.sex.or(entry.sex);
+        if (record.birth_date, record.sex) != before {
+            report.joined += 1;
+        }
+    }
+    report.missing = missing.into_iter().collect();
+    for patient_id in &report.missing {
+        warn!("No demographics for patient {}", patient_id);
+    }
+    info!("Joined demographics onto {} records", report.joined);
+    report
+}
+
+/// Whether adult reference values apply; patients of unknown age are taken
+/// to be adults
+pub fn is_adult(record: &PatientRecord) -> bool {
+    record.age().is_none_or(|age| age >= ADULT_AGE)
+}
+
+/// Patients none of whose records carry a birth date or a sex, in order
+pub fn missing_patients(records: &[PatientRecord]) -> Vec<&str> {
+    let known: BTreeSet<&str> = records
+        .iter()
+        .filter(|r| r.birth_date.is_some() || r.sex.is_some())
+        .map(|r| r.patient_id.as_str())
+        .collect();
+    let all: BTreeSet<&str> = records.iter().map(|r| r.patient_id.as_str()).collect();
+    all.difference(&known).copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamp::Timestamp;
+
+    #[test]
+    fn test_join_by_patient_number_or_identifier() {
+        let demographics: Vec<Demographics> = csv::Reader::from_reader(
+            "patient_id,identifiers,birth_date,sex\n\
+             17,,1950-06-15,w\n\
+             ,http://fhir.de/sid/gkv/kvid-10|A123456789,2010-01-01,male\n"
+                .as_bytes(),
+        )
+        .deserialize()
+        .collect::<Result<_, _>>()
+        .unwrap();
+        let record = |patient_id: &str, identifiers: &str| PatientRecord {
+            patient_id: patient_id.to_string(),
+            identifiers: Identifiers::try_from(identifiers.to_string()).unwrap(),
+            timestamp: Timestamp::parse("2024-06-14").unwrap(),
+            ..Default::default()
+        };
+        let mut records = [
+            record("17", ""),
+            record("A123456789", &format!("{}|A123456789", identity::KVNR_SYSTEM)),
+            record("99", ""),
+        ];
+
+        let report = join_demographics(&mut records, &demographics, identity::KVNR_SYSTEM);
+        assert_eq!((report.joined, report.missing.as_slice()), (2, ["99".to_string()].as_slice()));
+        assert_eq!((records[0].sex, records[0].age()), (Some(Sex::Female), Some(73)));
+        assert_eq!((records[1].sex, records[1].age()), (Some(Sex::Male), Some(14)));
+        assert_eq!(missing_patients(&records), vec!["99"]);
+    }
+}
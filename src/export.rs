@@ -1,29 +1,68 @@
 use crate::{AktenError, PatientRecord};
+use crate::config::{IdentityConfig, OmopConfig, OpenEhrConfig, Thresholds};
+use crate::fhir::{self, BundleType};
+use crate::hl7::{self, MessageGrouping};
+#[cfg(feature = "parquet")]
+use crate::columnar::{self, ExportKind};
+use crate::csv_io::{self, CsvOutput};
+use crate::omop;
+use crate::openehr::{self, CompositionFormat};
+use crate::profiles::FhirProfile;
 use std::fs::File;
-use csv::WriterBuilder;
+use std::io::Write;
+use std::path::Path;
 use serde::{Serialize}; // ✅ Fix missing macro for #[derive(Serialize)]
-use serde_json;
 use chrono::Utc;
 
-/// Export data in supported formats (CSV/JSON)
+/// Format-specific export settings
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    pub bundle_type: BundleType,
+    pub profile: FhirProfile,
+    pub hl7_grouping: MessageGrouping,
+    /// Thresholds for HL7 abnormal flags; no flags without them
+    pub thresholds: Option<Thresholds>,
+    pub openehr: OpenEhrConfig,
+    pub omop: OmopConfig,
+    /// System of `patient_id`, written alongside it where a format carries identifier systems
+    pub identity: IdentityConfig,
+    pub csv: CsvOutput,
+}
+
+/// Export data in supported formats (CSV/JSON/FHIR/FHIR Bulk Data NDJSON/HL7 v2/openEHR/OMOP CDM/Parquet)
 pub fn export_data(
     records: &[PatientRecord],
     format: &str,
     output_path: &str,
     medical_mode: bool,
+    options: &ExportOptions,
 ) -> Result<(), AktenError> {
     match format.to_lowercase().as_str() {
-        "csv" => export_csv(records, output_path, medical_mode),
+        "csv" => export_csv(records, output_path, medical_mode, &options.csv),
         "json" => export_json(records, output_path, medical_mode),
+        "fhir" => export_fhir(records, output_path, options),
+        "ndjson" => export_fhir_ndjson(records, output_path, options),
+        "hl7" => export_hl7(records, output_path, options),
+        "openehr" => export_openehr(records, output_path, CompositionFormat::Canonical, &options.openehr),
+        "openehr-flat" => export_openehr(records, output_path, CompositionFormat::Flat, &options.openehr),
+        "omop" => export_omop(records, output_path, &options.omop),
+        #[cfg(feature = "parquet")]
+        "parquet" => export_parquet(records, output_path, ExportKind::Records),
         _ => Err(AktenError::UnsupportedFormat),
     }
 }
 
-/// Export AI-ready JSON with additional metadata
+/// Export AI-ready JSON with additional metadata; a `.parquet` output keeps
+/// the metadata in the file footer instead of repeating it per record
 pub fn export_ai_data(
     records: &[PatientRecord],
     output_path: &str,
 ) -> Result<(), AktenError> {
+    #[cfg(feature = "parquet")]
+    if output_path.ends_with(".parquet") {
+        return export_parquet(records, output_path, ExportKind::Ai);
+    }
+
     #[derive(Serialize)]
     struct AiExportRecord<'a> {
         record: &'a PatientRecord,
@@ -56,18 +95,11 @@ fn export_csv(
     records: &[PatientRecord],
     output_path: &str,
     medical_mode: bool,
+    output: &CsvOutput,
 ) -> Result<(), AktenError> {
     let file = File::create(output_path)?;
-    let mut writer = WriterBuilder::new()
-        .has_headers(true)
-        .flexible(medical_mode) // Allow variable columns in medical mode
-        .from_writer(file);
-
-    for record in records {
-        writer.serialize(record)?;
-    }
-
-    writer.flush()?;
+    // Allow variable columns in medical mode
+    csv_io::write_records(file, records, output, medical_mode)?;
 
     let mode_prefix = if medical_mode { "🩺 Medical" } else { "📄 Standard" };
     println!(
@@ -113,3 +145,127 @@ fn export_json(
     );
     Ok(())
 }
+
+/// FHIR R4 Bundle export implementation
+fn export_fhir(
+    records: &[PatientRecord],
+    output_path: &str,
+    options: &ExportOptions,
+) -> Result<(), AktenError> {
+    let bundle = fhir::records_to_bundle(
+        records,
+        options.bundle_type,
+        &options.profile,
+        options.identity.primary_system(),
+    )?;
+    let file = File::create(output_path)?;
+    serde_json::to_writer_pretty(file, &bundle)?;
+
+    println!(
+        "🔥 FHIR {:?} bundle export complete: {} records to '{}'",
+        options.bundle_type,
+        records.len(),
+        output_path
+    );
+    Ok(())
+}
+
+/// FHIR Bulk Data export implementation (`output_path` is a directory)
+fn export_fhir_ndjson(
+    records: &[PatientRecord],
+    output_path: &str,
+    options: &ExportOptions,
+) -> Result<(), AktenError> {
+    fhir::write_bulk_data(records, Path::new(output_path), &options.profile, options.identity.primary_system())?;
+
+    println!(
+        "🔥 FHIR Bulk Data export complete: {} records to '{}/'",
+        records.len(),
+        output_path
+    );
+    Ok(())
+}
+
+/// HL7 v2.5 ORU^R01 export implementation
+fn export_hl7(
+    records: &[PatientRecord],
+    output_path: &str,
+    options: &ExportOptions,
+) -> Result<(), AktenError> {
+    let messages = hl7::records_to_messages(
+        records,
+        options.hl7_grouping,
+        options.thresholds.as_ref(),
+        options.identity.primary_system(),
+    );
+    let mut file = File::create(output_path)?;
+    for message in &messages {
+        // Segments end in CR; messages go on separate lines for readability
+        file.write_all(message.as_bytes())?;
+        file.write_all(b"\n")?;
+    }
+
+    println!(
+        "🏥 HL7 v2 export complete: {} records in {} ORU^R01 messages to '{}'",
+        records.len(),
+        messages.len(),
+        output_path
+    );
+    Ok(())
+}
+
+/// openEHR composition export implementation (a JSON array of compositions)
+fn export_openehr(
+    records: &[PatientRecord],
+    output_path: &str,
+    format: CompositionFormat,
+    config: &OpenEhrConfig,
+) -> Result<(), AktenError> {
+    let compositions = openehr::records_to_compositions(records, format, config)?;
+    let file = File::create(output_path)?;
+    serde_json::to_writer_pretty(file, &compositions)?;
+
+    println!(
+        "🧬 openEHR {:?} export complete: {} compositions for template '{}' to '{}'",
+        format,
+        compositions.len(),
+        config.template_id,
+        output_path
+    );
+    Ok(())
+}
+
+/// OMOP CDM v5.4 export implementation (`output_path` is a directory)
+fn export_omop(
+    records: &[PatientRecord],
+    output_path: &str,
+    config: &OmopConfig,
+) -> Result<(), AktenError> {
+    let tables = omop::write_tables(records, Path::new(output_path), config)?;
+
+    println!(
+        "🔬 OMOP CDM export complete: {} persons, {} measurements to '{}/'",
+        tables.persons.len(),
+        tables.measurements.len(),
+        output_path
+    );
+    Ok(())
+}
+
+/// Apache Parquet export implementation
+#[cfg(feature = "parquet")]
+fn export_parquet(
+    records: &[PatientRecord],
+    output_path: &str,
+    kind: ExportKind,
+) -> Result<(), AktenError> {
+    columnar::write_parquet(records, output_path, kind)?;
+
+    println!(
+        "📦 Parquet export complete: {} records (schema {}) to '{}'",
+        records.len(),
+        columnar::SCHEMA_VERSION,
+        output_path
+    );
+    Ok(())
+}
@@ -1,13 +1,1700 @@
-use crate::{AktenError, PatientRecord};
-use std::fs::File;
-use std::io::Read;
-use serde_json::Value;
+use crate::profiles::{self, FhirProfile};
+use crate::risk::RiskKind;
+use crate::validate::{Finding, FindingKind};
+use crate::demographics::{Demographics, Sex};
+use crate::identity;
+use crate::timestamp::Timestamp;
+use crate::units;
+use crate::{fnv1a, AktenError, Consciousness, PatientRecord};
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 
-/// Dummy FHIR-to-records converter for now
+/// LOINC codes for the vital signs carried by `PatientRecord`
+pub const LOINC_HEART_RATE: &str = "8867-4";
+pub const LOINC_BP_SYSTOLIC: &str = "8480-6";
+pub const LOINC_BP_DIASTOLIC: &str = "8462-4";
+pub const LOINC_BODY_TEMPERATURE: &str = "8310-5";
+pub const LOINC_GLUCOSE_MASS: &str = "2339-0";
+pub const LOINC_GLUCOSE_MOLES: &str = "15074-8";
+pub const LOINC_STEPS: &str = "55423-8";
+pub const LOINC_BP_PANEL: &str = "85354-9";
+pub const LOINC_SPO2: &str = "59408-5";
+/// Generic SpO2 code the vital-signs profile also accepts
+pub const LOINC_SPO2_ARTERIAL: &str = "2708-6";
+pub const LOINC_RESPIRATORY_RATE: &str = "9279-1";
+pub const LOINC_BODY_WEIGHT: &str = "29463-7";
+pub const LOINC_BODY_HEIGHT: &str = "8302-2";
+pub const LOINC_BMI: &str = "39156-5";
+pub const LOINC_RESPONSIVENESS: &str = "67775-7";
+
+/// SNOMED CT finding for a patient on supplemental oxygen, exported with valueBoolean
+const SNOMED_ON_OXYGEN: &str = "371825009";
+
+const LOINC_SYSTEM: &str = "http://loinc.org";
+const SNOMED_SYSTEM: &str = "http://snomed.info/sct";
+const UCUM_SYSTEM: &str = "http://unitsofmeasure.org";
+const OBSERVATION_CATEGORY_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/observation-category";
+
+/// Extensions linking OperationOutcome issues to the patient and Observation they concern
+const EXT_ISSUE_SUBJECT: &str = "https://github.com/OSBORNEAMOLLO/AktenAkrobat/fhir/StructureDefinition/issue-subject";
+const EXT_ISSUE_FOCUS: &str = "https://github.com/OSBORNEAMOLLO/AktenAkrobat/fhir/StructureDefinition/issue-focus";
+
+/// Bundle types accepted as input
+const SUPPORTED_BUNDLE_TYPES: [&str; 3] = ["searchset", "collection", "transaction"];
+
+/// Manifest file of a Bulk Data export directory
+pub const BULK_MANIFEST: &str = "manifest.json";
+
+/// Minimal FHIR R4 Bundle model (only the parts we read)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bundle {
+    resource_type: String,
+    #[serde(rename = "type")]
+    bundle_type: Option<String>,
+    #[serde(default)]
+    entry: Vec<BundleEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BundleEntry {
+    full_url: Option<String>,
+    resource: Option<Resource>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "resourceType")]
+enum Resource {
+    Patient(Patient),
+    Observation(Box<Observation>),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Patient {
+    id: Option<String>,
+    #[serde(default)]
+    identifier: Vec<Identifier>,
+    gender: Option<String>,
+    birth_date: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    system: Option<String>,
+    value: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Observation {
+    id: Option<String>,
+    status: Option<String>,
+    #[serde(default)]
+    code: CodeableConcept,
+    subject: Option<Reference>,
+    effective_date_time: Option<String>,
+    effective_instant: Option<String>,
+    effective_period: Option<Period>,
+    value_quantity: Option<Quantity>,
+    value_boolean: Option<bool>,
+    value_codeable_concept: Option<CodeableConcept>,
+    #[serde(default)]
+    component: Vec<ObservationComponent>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObservationComponent {
+    #[serde(default)]
+    code: CodeableConcept,
+    value_quantity: Option<Quantity>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CodeableConcept {
+    #[serde(default)]
+    coding: Vec<Coding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Coding {
+    system: Option<String>,
+    code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Reference {
+    reference: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Period {
+    start: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Quantity {
+    value: Option<f64>,
+    code: Option<String>,
+    unit: Option<String>,
+}
+
+/// Outcome of a FHIR import: what was mapped and what had to be skipped
+#[derive(Debug, Default)]
+pub struct FhirImportReport {
+    pub observations_mapped: usize,
+    pub unmapped: Vec<String>,
+    pub incomplete: Vec<String>,
+}
+
+/// Vital signs collected for one patient on one day
+#[derive(Debug, Default)]
+pub struct PartialRecord {
+    heart_rate: Option<f64>,
+    bp_systolic: Option<f64>,
+    bp_diastolic: Option<f64>,
+    temperature: Option<f64>,
+    blood_sugar: Option<f64>,
+    steps: Option<f64>,
+    spo2: Option<f64>,
+    respiratory_rate: Option<f64>,
+    weight: Option<f64>,
+    height: Option<f64>,
+    /// Imported for completeness; records derive BMI from weight and height
+    bmi: Option<f64>,
+    supplemental_oxygen: Option<bool>,
+    consciousness: Option<Consciousness>,
+}
+
+impl PartialRecord {
+    fn value(&self, field: VitalField) -> Option<f64> {
+        match field {
+            VitalField::HeartRate => self.heart_rate,
+            VitalField::BpSystolic => self.bp_systolic,
+            VitalField::BpDiastolic => self.bp_diastolic,
+            VitalField::Temperature => self.temperature,
+            VitalField::BloodSugar => self.blood_sugar,
+            VitalField::Steps => self.steps,
+            VitalField::Spo2 => self.spo2,
+            VitalField::RespiratoryRate => self.respiratory_rate,
+            VitalField::Weight => self.weight,
+            VitalField::Height => self.height,
+            VitalField::Bmi => self.bmi,
+        }
+    }
+}
+
+/// Record field a LOINC code maps onto
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VitalField {
+    HeartRate,
+    BpSystolic,
+    BpDiastolic,
+    Temperature,
+    BloodSugar,
+    Steps,
+    Spo2,
+    RespiratoryRate,
+    Weight,
+    Height,
+    /// Derived from weight and height
+    Bmi,
+}
+
+impl VitalField {
+    pub const ALL: [VitalField; 11] = [
+        Self::HeartRate,
+        Self::BpSystolic,
+        Self::BpDiastolic,
+        Self::Temperature,
+        Self::BloodSugar,
+        Self::Steps,
+        Self::Spo2,
+        Self::RespiratoryRate,
+        Self::Weight,
+        Self::Height,
+        Self::Bmi,
+    ];
+
+    pub fn from_loinc(code: &str) -> Option<Self> {
+        match code {
+            LOINC_HEART_RATE => Some(Self::HeartRate),
+            LOINC_BP_SYSTOLIC => Some(Self::BpSystolic),
+            LOINC_BP_DIASTOLIC => Some(Self::BpDiastolic),
+            LOINC_BODY_TEMPERATURE => Some(Self::Temperature),
+            LOINC_GLUCOSE_MASS | LOINC_GLUCOSE_MOLES => Some(Self::BloodSugar),
+            LOINC_STEPS => Some(Self::Steps),
+            LOINC_SPO2 | LOINC_SPO2_ARTERIAL => Some(Self::Spo2),
+            LOINC_RESPIRATORY_RATE => Some(Self::RespiratoryRate),
+            LOINC_BODY_WEIGHT => Some(Self::Weight),
+            LOINC_BODY_HEIGHT => Some(Self::Height),
+            LOINC_BMI => Some(Self::Bmi),
+            _ => None,
+        }
+    }
+
+    pub fn slot<'a>(&self, partial: &'a mut PartialRecord) -> &'a mut Option<f64> {
+        match self {
+            Self::HeartRate => &mut partial.heart_rate,
+            Self::BpSystolic => &mut partial.bp_systolic,
+            Self::BpDiastolic => &mut partial.bp_diastolic,
+            Self::Temperature => &mut partial.temperature,
+            Self::BloodSugar => &mut partial.blood_sugar,
+            Self::Steps => &mut partial.steps,
+            Self::Spo2 => &mut partial.spo2,
+            Self::RespiratoryRate => &mut partial.respiratory_rate,
+            Self::Weight => &mut partial.weight,
+            Self::Height => &mut partial.height,
+            Self::Bmi => &mut partial.bmi,
+        }
+    }
+}
+
+/// Convert a FHIR R4 Bundle file into patient records
 pub fn convert_fhir_to_records(mut file: File) -> Result<Vec<PatientRecord>, AktenError> {
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    let _json: Value = serde_json::from_str(&contents)?;
-    // TODO: Parse and convert to PatientRecord
-    Ok(vec![]) // Return empty list as placeholder
+
+    let (records, report) = parse_bundle(&contents)?;
+    log_import_report(&records, &report);
+    Ok(records)
+}
+
+fn log_import_report(records: &[PatientRecord], report: &FhirImportReport) {
+    for issue in &report.unmapped {
+        warn!("FHIR observation not mapped: {}", issue);
+    }
+    for issue in &report.incomplete {
+        warn!("FHIR record incomplete: {}", issue);
+    }
+    info!(
+        "FHIR import: {} observations mapped into {} records ({} unmapped, {} incomplete)",
+        report.observations_mapped,
+        records.len(),
+        report.unmapped.len(),
+        report.incomplete.len()
+    );
+}
+
+/// Parse a Bundle and group its vital-sign Observations by subject and effective time
+pub fn parse_bundle(contents: &str) -> Result<(Vec<PatientRecord>, FhirImportReport), AktenError> {
+    let bundle = read_bundle(contents)?;
+
+    // Patients first, so observations can reference them in any entry order
+    let mut builder = RecordBuilder::default();
+    for entry in &bundle.entry {
+        if let Some(Resource::Patient(patient)) = &entry.resource {
+            builder.add_patient(patient, entry.full_url.as_deref());
+        }
+    }
+    for entry in bundle.entry {
+        if let Some(Resource::Observation(observation)) = entry.resource {
+            builder.add_observation(*observation, entry.full_url);
+        }
+    }
+
+    Ok(builder.finish())
+}
+
+/// Birth date, sex and identifiers of the Patients in a Bundle
+pub fn parse_patients(contents: &str) -> Result<Vec<Demographics>, AktenError> {
+    let bundle = read_bundle(contents)?;
+    Ok(bundle
+        .entry
+        .iter()
+        .filter_map(|entry| match &entry.resource {
+            Some(Resource::Patient(patient)) => patient_demographics(patient),
+            _ => None,
+        })
+        .collect())
+}
+
+/// A Bundle of one of the supported types
+fn read_bundle(contents: &str) -> Result<Bundle, AktenError> {
+    let bundle: Bundle = serde_json::from_str(contents)?;
+    if bundle.resource_type != "Bundle" {
+        return Err(AktenError::Fhir(format!(
+            "expected a Bundle, found {}",
+            bundle.resource_type
+        )));
+    }
+    let bundle_type = bundle.bundle_type.as_deref().unwrap_or_default();
+    if !SUPPORTED_BUNDLE_TYPES.contains(&bundle_type) {
+        return Err(AktenError::Fhir(format!(
+            "unsupported Bundle type '{}' (expected searchset, collection or transaction)",
+            bundle_type
+        )));
+    }
+    Ok(bundle)
+}
+
+/// Groups vital-sign Observations into one partial record per patient and measurement time
+#[derive(Debug, Default)]
+struct RecordBuilder {
+    /// Patients may be referenced as "Patient/<id>" or via the entry fullUrl (urn:uuid:...)
+    patients: HashMap<String, String>,
+    /// `Patient.identifier`s with a system, birth date and sex, per patient id
+    demographics: HashMap<String, Demographics>,
+    grouped: BTreeMap<(String, Timestamp), PartialRecord>,
+    report: FhirImportReport,
+}
+
+impl RecordBuilder {
+    fn add_patient(&mut self, patient: &Patient, full_url: Option<&str>) {
+        let Some(demographics) = patient_demographics(patient) else {
+            return;
+        };
+        let patient_id = demographics.patient_id.clone();
+        self.demographics.insert(patient_id.clone(), demographics);
+        if let Some(id) = &patient.id {
+            self.patients.insert(format!("Patient/{}", id), patient_id.clone());
+        }
+        if let Some(full_url) = full_url {
+            self.patients.insert(full_url.to_string(), patient_id);
+        }
+    }
+
+    /// `source` (entry fullUrl or file position) labels Observations without an id
+    fn add_observation(&mut self, observation: Observation, source: Option<String>) {
+        let report = &mut self.report;
+        let label = observation
+            .id
+            .as_deref()
+            .map(|id| format!("Observation/{}", id))
+            .or(source)
+            .unwrap_or_else(|| "Observation (no id)".to_string());
+
+        if matches!(
+            observation.status.as_deref(),
+            Some("entered-in-error") | Some("cancelled")
+        ) {
+            report.unmapped.push(format!("{}: status is {}", label, observation.status.unwrap_or_default()));
+            return;
+        }
+
+        let Some(patient_id) = resolve_subject(&observation, &self.patients) else {
+            report.unmapped.push(format!("{}: subject cannot be resolved to a patient", label));
+            return;
+        };
+        let Some(timestamp) = effective_time(&observation) else {
+            report.unmapped.push(format!("{}: no effective time", label));
+            return;
+        };
+
+        if let Some(value) = coded_value(&observation) {
+            let partial = self.grouped.entry((patient_id, timestamp)).or_default();
+            match value {
+                CodedValue::SupplementalOxygen(on_oxygen) => partial.supplemental_oxygen = Some(on_oxygen),
+                CodedValue::Consciousness(level) => partial.consciousness = Some(level),
+            }
+            report.observations_mapped += 1;
+            return;
+        }
+
+        let mut values = vec![];
+        if let Some((code, field)) = loinc_code(&observation.code).and_then(|c| Some((c, VitalField::from_loinc(c)?))) {
+            values.push((field, code, observation.value_quantity.as_ref()));
+        }
+        for component in &observation.component {
+            if let Some((code, field)) = loinc_code(&component.code).and_then(|c| Some((c, VitalField::from_loinc(c)?))) {
+                values.push((field, code, component.value_quantity.as_ref()));
+            }
+        }
+        if values.is_empty() {
+            report.unmapped.push(format!("{}: no supported LOINC vital-sign code", label));
+            return;
+        }
+
+        let partial = self.grouped.entry((patient_id, timestamp)).or_default();
+        for (field, code, quantity) in values {
+            let Some((value, quantity)) = quantity.and_then(|q| Some((q.value?, q))) else {
+                report.unmapped.push(format!("{}: {:?} has no numeric value", label, field));
+                continue;
+            };
+            let value = match units::convert(field, value, quantity_unit(code, quantity)) {
+                Ok(value) => value,
+                Err(issue) => {
+                    report.unmapped.push(format!("{}: {}", label, issue));
+                    continue;
+                }
+            };
+            let slot = field.slot(partial);
+            if slot.is_some() {
+                report.unmapped.push(format!("{}: duplicate {:?} value ignored", label, field));
+            } else {
+                *slot = Some(value);
+                report.observations_mapped += 1;
+            }
+        }
+    }
+
+    fn finish(self) -> (Vec<PatientRecord>, FhirImportReport) {
+        let mut report = self.report;
+        let mut records = complete_records(self.grouped, &mut report.incomplete);
+        for record in &mut records {
+            if let Some(demographics) = self.demographics.get(&record.patient_id) {
+                record.identifiers = demographics.identifiers.clone();
+                record.birth_date = demographics.birth_date;
+                record.sex = demographics.sex;
+            }
+        }
+        (records, report)
+    }
+}
+
+/// Turn grouped partial records into `PatientRecord`s; records that lack some
+/// vitals are kept and noted in `incomplete`, readings without any value are dropped
+pub fn complete_records(grouped: BTreeMap<(String, Timestamp), PartialRecord>, incomplete: &mut Vec<String>) -> Vec<PatientRecord> {
+    let mut records = vec![];
+    for ((patient_id, timestamp), partial) in grouped {
+        let has_coded = partial.supplemental_oxygen.is_some() || partial.consciousness.is_some();
+        if !has_coded && VitalField::ALL.iter().all(|field| partial.value(*field).is_none()) {
+            incomplete.push(format!("Patient {} ({}): no usable values, skipped", patient_id, timestamp));
+            continue;
+        }
+        let (record, missing) = complete_record(&patient_id, timestamp, &partial);
+        if !missing.is_empty() {
+            incomplete.push(format!("Patient {} ({}): missing {}", patient_id, timestamp, missing.join(", ")));
+        }
+        records.push(record);
+    }
+    records
+}
+
+/// Vitals every record is expected to carry; activity data is often not
+/// recorded alongside them
+const REQUIRED_VITALS: [(VitalField, &str); 5] = [
+    (VitalField::HeartRate, "heart_rate"),
+    (VitalField::BpSystolic, "bp_systolic"),
+    (VitalField::BpDiastolic, "bp_diastolic"),
+    (VitalField::Temperature, "temperature"),
+    (VitalField::BloodSugar, "blood_sugar"),
+];
+
+/// Build a `PatientRecord` along with the names of the vitals it is missing
+pub fn complete_record(patient_id: &str, timestamp: Timestamp, partial: &PartialRecord) -> (PatientRecord, Vec<&'static str>) {
+    let mut record = PatientRecord {
+        patient_id: patient_id.to_string(),
+        timestamp,
+        ..Default::default()
+    };
+    for field in VitalField::ALL {
+        if let Some(value) = partial.value(field) {
+            record.set_measurement(field, value);
+        }
+    }
+    record.supplemental_oxygen = partial.supplemental_oxygen;
+    record.consciousness = partial.consciousness;
+    let missing = REQUIRED_VITALS
+        .iter()
+        .filter(|(field, _)| record.measurement(*field).is_none())
+        .map(|(_, name)| *name)
+        .collect();
+    (record, missing)
+}
+
+/// Values carried as a boolean or a code rather than a Quantity
+enum CodedValue {
+    SupplementalOxygen(bool),
+    Consciousness(Consciousness),
+}
+
+fn coded_value(observation: &Observation) -> Option<CodedValue> {
+    if let Some(on_oxygen) = observation.value_boolean {
+        if system_code(&observation.code, SNOMED_SYSTEM) == Some(SNOMED_ON_OXYGEN) {
+            return Some(CodedValue::SupplementalOxygen(on_oxygen));
+        }
+    }
+    if loinc_code(&observation.code) == Some(LOINC_RESPONSIVENESS) {
+        let answer = system_code(observation.value_codeable_concept.as_ref()?, SNOMED_SYSTEM)?;
+        let level = AVPU_LEVELS.into_iter().find(|level| avpu_answer(*level).0 == answer)?;
+        return Some(CodedValue::Consciousness(level));
+    }
+    None
+}
+
+/// SNOMED CT answers for the AVPU levels
+const AVPU_LEVELS: [Consciousness; 4] = [
+    Consciousness::Alert,
+    Consciousness::Voice,
+    Consciousness::Pain,
+    Consciousness::Unresponsive,
+];
+
+fn avpu_answer(level: Consciousness) -> (&'static str, &'static str) {
+    match level {
+        Consciousness::Alert => ("248234008", "Mentally alert"),
+        Consciousness::Voice => ("300202002", "Responds to voice"),
+        Consciousness::Pain => ("450847001", "Responds to pain"),
+        Consciousness::Unresponsive => ("422768004", "Unresponsive"),
+    }
+}
+
+/// First code of a CodeableConcept in the given system
+fn system_code<'a>(concept: &'a CodeableConcept, system: &str) -> Option<&'a str> {
+    concept
+        .coding
+        .iter()
+        .find(|c| c.system.as_deref() == Some(system))
+        .and_then(|c| c.code.as_deref())
+}
+
+/// First LOINC code of a CodeableConcept
+fn loinc_code(concept: &CodeableConcept) -> Option<&str> {
+    system_code(concept, LOINC_SYSTEM)
+}
+
+/// UCUM code of a Quantity, or its unit text; a glucose code without either
+/// implies its unit
+fn quantity_unit<'a>(loinc: &str, quantity: &'a Quantity) -> &'a str {
+    match quantity.code.as_deref().or(quantity.unit.as_deref()) {
+        Some(unit) => unit,
+        None if loinc == LOINC_GLUCOSE_MOLES => "mmol/L",
+        None if loinc == LOINC_GLUCOSE_MASS => "mg/dL",
+        None => "",
+    }
+}
+
+/// Patient id, identifiers with a system, and the birth date if given to the day
+fn patient_demographics(patient: &Patient) -> Option<Demographics> {
+    Some(Demographics {
+        patient_id: patient_id(patient)?,
+        identifiers: patient
+            .identifier
+            .iter()
+            .filter_map(|i| Some(identity::Identifier::new(i.system.as_deref()?, i.value.as_deref()?)))
+            .collect(),
+        birth_date: patient.birth_date.as_deref().and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()),
+        sex: patient.gender.as_deref().and_then(Sex::parse),
+    })
+}
+
+/// Patient id from `Patient.id` or, without one, the first identifier value;
+/// the configured primary identifier replaces it on load
+fn patient_id(patient: &Patient) -> Option<String> {
+    patient
+        .id
+        .clone()
+        .or_else(|| patient.identifier.iter().find_map(|i| i.value.clone()))
+        .filter(|id| !id.is_empty())
+}
+
+fn resolve_subject(observation: &Observation, patients: &HashMap<String, String>) -> Option<String> {
+    let reference = observation.subject.as_ref()?.reference.as_deref()?;
+    if let Some(id) = patients.get(reference) {
+        return Some(id.clone());
+    }
+    Some(reference.strip_prefix("Patient/")?.to_string()).filter(|id| !id.is_empty())
+}
+
+/// Effective time of the observation; a dateTime given to the day only
+/// stays date-only, partial dates (year, year-month) are not usable
+fn effective_time(observation: &Observation) -> Option<Timestamp> {
+    let effective = observation
+        .effective_date_time
+        .as_deref()
+        .or(observation.effective_instant.as_deref())
+        .or(observation.effective_period.as_ref().and_then(|p| p.start.as_deref()))?;
+    Timestamp::parse_iso(effective)
+}
+
+/// Bundle type written by the FHIR export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BundleType {
+    /// Plain collection of resources, e.g. for archiving or file exchange
+    #[default]
+    Collection,
+    /// Transaction with PUT requests, ready to POST to a FHIR server base
+    Transaction,
+}
+
+impl BundleType {
+    pub fn parse(value: &str) -> Result<Self, AktenError> {
+        match value.to_lowercase().as_str() {
+            "collection" => Ok(Self::Collection),
+            "transaction" => Ok(Self::Transaction),
+            other => Err(AktenError::Fhir(format!(
+                "unsupported bundle type '{}' (expected collection or transaction)",
+                other
+            ))),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Collection => "collection",
+            Self::Transaction => "transaction",
+        }
+    }
+}
+
+/// LOINC coding, UCUM unit and category of one exported measurement
+pub struct VitalCoding {
+    pub loinc: &'static str,
+    pub display: &'static str,
+    pub ucum: &'static str,
+    pub unit: &'static str,
+    pub category: &'static str,
+}
+
+const HEART_RATE: VitalCoding = VitalCoding {
+    loinc: LOINC_HEART_RATE,
+    display: "Heart rate",
+    ucum: "/min",
+    unit: "beats/minute",
+    category: "vital-signs",
+};
+const BP_SYSTOLIC: VitalCoding = VitalCoding {
+    loinc: LOINC_BP_SYSTOLIC,
+    display: "Systolic blood pressure",
+    ucum: "mm[Hg]",
+    unit: "mmHg",
+    category: "vital-signs",
+};
+const BP_DIASTOLIC: VitalCoding = VitalCoding {
+    loinc: LOINC_BP_DIASTOLIC,
+    display: "Diastolic blood pressure",
+    ucum: "mm[Hg]",
+    unit: "mmHg",
+    category: "vital-signs",
+};
+const BODY_TEMPERATURE: VitalCoding = VitalCoding {
+    loinc: LOINC_BODY_TEMPERATURE,
+    display: "Body temperature",
+    ucum: "Cel",
+    unit: "°C",
+    category: "vital-signs",
+};
+const GLUCOSE_MASS: VitalCoding = VitalCoding {
+    loinc: LOINC_GLUCOSE_MASS,
+    display: "Glucose [Mass/volume] in Blood",
+    ucum: "mg/dL",
+    unit: "mg/dL",
+    category: "laboratory",
+};
+const STEPS: VitalCoding = VitalCoding {
+    loinc: LOINC_STEPS,
+    display: "Number of steps in unspecified time Pedometer",
+    ucum: "{steps}",
+    unit: "steps",
+    category: "activity",
+};
+
+const SPO2: VitalCoding = VitalCoding {
+    loinc: LOINC_SPO2,
+    display: "Oxygen saturation in Arterial blood by Pulse oximetry",
+    ucum: "%",
+    unit: "%",
+    category: "vital-signs",
+};
+const RESPIRATORY_RATE: VitalCoding = VitalCoding {
+    loinc: LOINC_RESPIRATORY_RATE,
+    display: "Respiratory rate",
+    ucum: "/min",
+    unit: "breaths/minute",
+    category: "vital-signs",
+};
+const BODY_WEIGHT: VitalCoding = VitalCoding {
+    loinc: LOINC_BODY_WEIGHT,
+    display: "Body weight",
+    ucum: "kg",
+    unit: "kg",
+    category: "vital-signs",
+};
+const BODY_HEIGHT: VitalCoding = VitalCoding {
+    loinc: LOINC_BODY_HEIGHT,
+    display: "Body height",
+    ucum: "cm",
+    unit: "cm",
+    category: "vital-signs",
+};
+const BMI: VitalCoding = VitalCoding {
+    loinc: LOINC_BMI,
+    display: "Body mass index (BMI) [Ratio]",
+    ucum: "kg/m2",
+    unit: "kg/m2",
+    category: "vital-signs",
+};
+
+/// Build a FHIR R4 Bundle with one Patient per `patient_id` and one
+/// Observation per measurement (blood pressure as a single panel with
+/// systolic/diastolic components, as the vital-signs profile requires)
+pub fn records_to_bundle(
+    records: &[PatientRecord],
+    bundle_type: BundleType,
+    profile: &FhirProfile,
+    primary_system: &str,
+) -> Result<Value, AktenError> {
+    let resources: Vec<Value> = profiled(patient_resources(records, primary_system), profile)
+        .chain(profiled(observation_resources(records), profile))
+        .collect();
+    profiles::ensure_conformant(&resources)?;
+
+    let entries: Vec<Value> = resources
+        .into_iter()
+        .map(|resource| bundle_entry(resource, bundle_type))
+        .collect();
+
+    Ok(json!({
+        "resourceType": "Bundle",
+        "type": bundle_type.as_str(),
+        "timestamp": Utc::now().to_rfc3339(),
+        "entry": entries,
+    }))
+}
+
+fn profiled<'a>(
+    resources: impl Iterator<Item = Value> + 'a,
+    profile: &'a FhirProfile,
+) -> impl Iterator<Item = Value> + 'a {
+    resources.map(move |mut resource| {
+        profile.apply(&mut resource);
+        resource
+    })
+}
+
+/// One Patient resource per distinct `patient_id`, in first-seen order; the
+/// patient id is listed under its system (`primary_system` if the record
+/// carries that identifier), followed by the others
+fn patient_resources<'a>(records: &'a [PatientRecord], primary_system: &'a str) -> impl Iterator<Item = Value> + 'a {
+    let mut patients = BTreeSet::new();
+    records
+        .iter()
+        .filter(move |record| patients.insert(record.patient_id.as_str()))
+        .map(move |record| {
+            let primary = identity::patient_identifier(record, primary_system);
+            let identifiers: Vec<Value> = std::iter::once(&primary)
+                .chain(record.identifiers.iter().filter(|i| **i != primary))
+                .map(|i| json!({"system": i.system, "value": i.value}))
+                .collect();
+            let mut patient = json!({
+                "resourceType": "Patient",
+                "id": resource_id(&record.patient_id),
+                "identifier": identifiers,
+            });
+            if let Some(sex) = record.sex {
+                patient["gender"] = json!(sex.code());
+            }
+            if let Some(birth_date) = record.birth_date {
+                patient["birthDate"] = json!(birth_date.format("%Y-%m-%d").to_string());
+            }
+            patient
+        })
+}
+
+/// Reference to the Patient resource written for `patient_id`
+fn patient_reference(patient_id: &str) -> String {
+    format!("Patient/{}", resource_id(patient_id))
+}
+
+/// Observation resources for all records, in record order
+fn observation_resources(records: &[PatientRecord]) -> impl Iterator<Item = Value> + '_ {
+    records
+        .iter()
+        .zip(occurrences(records))
+        .flat_map(|(record, occurrence)| record_observations(record, &observation_prefix(record, occurrence)))
+}
+
+/// Position of each record among the records of the same patient and time,
+/// from 1; the export numbers their Observations by it to keep ids unique
+pub fn occurrences(records: &[PatientRecord]) -> Vec<usize> {
+    let mut seen: HashMap<(&str, Timestamp), usize> = HashMap::new();
+    records
+        .iter()
+        .map(|record| {
+            let count = seen.entry((record.patient_id.as_str(), record.timestamp)).or_default();
+            *count += 1;
+            *count
+        })
+        .collect()
+}
+
+/// Id prefix of the Observations of `record` at `occurrence`
+fn observation_prefix(record: &PatientRecord, occurrence: usize) -> String {
+    match occurrence {
+        0 | 1 => record_key(record),
+        n => format!("{}-{}", record_key(record), n),
+    }
+}
+
+/// Vital-sign Observations for a single record; unrecorded values are left out
+fn record_observations(record: &PatientRecord, prefix: &str) -> Vec<Value> {
+    let mut observations = vec![];
+    if let Some(heart_rate) = record.heart_rate {
+        observations.push(observation(prefix, record, &HEART_RATE, json!(heart_rate)));
+    }
+
+    let components: Vec<Value> = [(&BP_SYSTOLIC, record.bp_systolic), (&BP_DIASTOLIC, record.bp_diastolic)]
+        .into_iter()
+        .filter_map(|(coding, value)| value.map(|value| component(coding, json!(value))))
+        .collect();
+    if !components.is_empty() {
+        let mut bp_panel = observation_base(
+            &format!("{}-{}", prefix, LOINC_BP_PANEL),
+            record,
+            LOINC_BP_PANEL,
+            "Blood pressure panel with all children optional",
+            "vital-signs",
+        );
+        bp_panel["component"] = json!(components);
+        observations.push(bp_panel);
+    }
+
+    if let Some(temperature) = record.temperature {
+        observations.push(observation(prefix, record, &BODY_TEMPERATURE, json!(f32_value(temperature))));
+    }
+    if let Some(blood_sugar) = record.blood_sugar {
+        observations.push(observation(prefix, record, &GLUCOSE_MASS, json!(f32_value(blood_sugar))));
+    }
+    if let Some(steps) = record.steps {
+        observations.push(observation(prefix, record, &STEPS, json!(steps)));
+    }
+    for (field, value) in [
+        (VitalField::Spo2, record.spo2.map(|v| json!(v))),
+        (VitalField::RespiratoryRate, record.respiratory_rate.map(|v| json!(v))),
+        (VitalField::Weight, record.weight.map(|v| json!(f32_value(v)))),
+        (VitalField::Height, record.height.map(|v| json!(f32_value(v)))),
+        (VitalField::Bmi, record.bmi().map(|v| json!(v))),
+    ] {
+        if let Some(value) = value {
+            observations.push(observation(prefix, record, vital_coding(field), value));
+        }
+    }
+
+    if let Some(on_oxygen) = record.supplemental_oxygen {
+        let mut oxygen = observation_base(
+            &format!("{}-{}", prefix, SNOMED_ON_OXYGEN),
+            record,
+            SNOMED_ON_OXYGEN,
+            "Patient on oxygen",
+            "vital-signs",
+        );
+        oxygen["code"]["coding"][0]["system"] = json!(SNOMED_SYSTEM);
+        oxygen["valueBoolean"] = json!(on_oxygen);
+        observations.push(oxygen);
+    }
+    if let Some(level) = record.consciousness {
+        let mut responsiveness = observation_base(
+            &format!("{}-{}", prefix, LOINC_RESPONSIVENESS),
+            record,
+            LOINC_RESPONSIVENESS,
+            "Level of responsiveness",
+            "survey",
+        );
+        let (code, display) = avpu_answer(level);
+        responsiveness["valueCodeableConcept"] = json!({
+            "coding": [{"system": SNOMED_SYSTEM, "code": code, "display": display}],
+            "text": level.letter(),
+        });
+        observations.push(responsiveness);
+    }
+    observations
+}
+
+fn observation(prefix: &str, record: &PatientRecord, coding: &VitalCoding, value: Value) -> Value {
+    let id = format!("{}-{}", prefix, coding.loinc);
+    let mut observation = observation_base(&id, record, coding.loinc, coding.display, coding.category);
+    observation["valueQuantity"] = quantity(coding, value);
+    observation
+}
+
+fn observation_base(id: &str, record: &PatientRecord, loinc: &str, display: &str, category: &str) -> Value {
+    json!({
+        "resourceType": "Observation",
+        "id": resource_id(id),
+        "status": "final",
+        "category": [{
+            "coding": [{"system": OBSERVATION_CATEGORY_SYSTEM, "code": category}]
+        }],
+        "code": {
+            "coding": [{"system": LOINC_SYSTEM, "code": loinc, "display": display}],
+            "text": display,
+        },
+        "subject": {"reference": patient_reference(&record.patient_id)},
+        "effectiveDateTime": record.timestamp.to_string(),
+    })
+}
+
+fn component(coding: &VitalCoding, value: Value) -> Value {
+    json!({
+        "code": {
+            "coding": [{"system": LOINC_SYSTEM, "code": coding.loinc, "display": coding.display}],
+            "text": coding.display,
+        },
+        "valueQuantity": quantity(coding, value),
+    })
+}
+
+fn quantity(coding: &VitalCoding, value: Value) -> Value {
+    json!({
+        "value": value,
+        "unit": coding.unit,
+        "system": UCUM_SYSTEM,
+        "code": coding.ucum,
+    })
+}
+
+fn bundle_entry(resource: Value, bundle_type: BundleType) -> Value {
+    match bundle_type {
+        BundleType::Collection => json!({ "resource": resource }),
+        BundleType::Transaction => {
+            // PUT with client-assigned ids keeps re-imports idempotent
+            let url = format!(
+                "{}/{}",
+                resource["resourceType"].as_str().unwrap_or_default(),
+                resource["id"].as_str().unwrap_or_default()
+            );
+            json!({
+                "resource": resource,
+                "request": {"method": "PUT", "url": url},
+            })
+        }
+    }
+}
+
+/// Reference to the Observation the export writes for `field` of `record`,
+/// the `occurrence`th of its patient and time (see `occurrences`). Blood
+/// pressure values point at the panel that carries both components.
+pub fn observation_reference(record: &PatientRecord, occurrence: usize, field: VitalField) -> String {
+    let loinc = match field {
+        VitalField::BpSystolic | VitalField::BpDiastolic => LOINC_BP_PANEL,
+        _ => vital_coding(field).loinc,
+    };
+    format!("Observation/{}-{}", observation_prefix(record, occurrence), loinc)
+}
+
+/// Validation findings as a collection Bundle: one OperationOutcome for
+/// data-quality problems and one DetectedIssue per clinical alert
+pub fn findings_to_bundle(findings: &[Finding]) -> Value {
+    // Validation runs in parallel, so restore a stable order first
+    let mut findings: Vec<&Finding> = findings.iter().collect();
+    findings.sort_by(|a, b| {
+        (&a.record.patient_id, &a.record.timestamp, &a.message).cmp(&(&b.record.patient_id, &b.record.timestamp, &b.message))
+    });
+
+    let mut entries = vec![];
+    let data_issues: Vec<Value> = findings
+        .iter()
+        .filter(|f| f.kind == FindingKind::DataQuality)
+        .map(|f| operation_outcome_issue(f))
+        .collect();
+    if !data_issues.is_empty() {
+        entries.push(json!({
+            "resource": {
+                "resourceType": "OperationOutcome",
+                "id": "validation-data-quality",
+                "issue": data_issues,
+            }
+        }));
+    }
+
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for finding in findings.iter().filter(|f| f.kind == FindingKind::Clinical) {
+        let base = format!("{}-issue", record_key(&finding.record));
+        let count = seen.entry(base.clone()).or_default();
+        *count += 1;
+        entries.push(json!({ "resource": detected_issue(finding, &format!("{}-{}", base, count)) }));
+    }
+
+    json!({
+        "resourceType": "Bundle",
+        "type": "collection",
+        "timestamp": Utc::now().to_rfc3339(),
+        "entry": entries,
+    })
+}
+
+fn operation_outcome_issue(finding: &Finding) -> Value {
+    let mut extension = vec![json!({
+        "url": EXT_ISSUE_SUBJECT,
+        "valueReference": {"reference": patient_reference(&finding.record.patient_id)},
+    })];
+    let mut expression = vec![];
+    for reference in finding_observations(finding) {
+        extension.push(json!({
+            "url": EXT_ISSUE_FOCUS,
+            "valueReference": {"reference": reference},
+        }));
+    }
+    for field in &finding.fields {
+        let path = match field {
+            VitalField::BpSystolic | VitalField::BpDiastolic => "Observation.component.valueQuantity",
+            _ => "Observation.valueQuantity",
+        };
+        if !expression.contains(&path) {
+            expression.push(path);
+        }
+    }
+
+    json!({
+        "extension": extension,
+        "severity": if finding.critical { "error" } else { "warning" },
+        "code": "value",
+        "diagnostics": format!("{} | Patient {} ({})", finding.message, finding.record.patient_id, finding.record.timestamp),
+        "expression": expression,
+    })
+}
+
+fn detected_issue(finding: &Finding, id: &str) -> Value {
+    let implicated: Vec<Value> = finding_observations(finding)
+        .into_iter()
+        .map(|reference| json!({"reference": reference}))
+        .collect();
+
+    json!({
+        "resourceType": "DetectedIssue",
+        "id": id,
+        "status": "final",
+        "code": {"text": finding.message},
+        "severity": if finding.critical { "high" } else { "moderate" },
+        "patient": {"reference": patient_reference(&finding.record.patient_id)},
+        "identifiedDateTime": finding.record.timestamp.to_string(),
+        "implicated": implicated,
+        "detail": finding.message,
+    })
+}
+
+/// Distinct Observation references of a finding (both BP fields share one panel)
+fn finding_observations(finding: &Finding) -> Vec<String> {
+    let mut references = vec![];
+    for field in &finding.fields {
+        let reference = observation_reference(&finding.record, finding.occurrence, *field);
+        if !references.contains(&reference) {
+            references.push(reference);
+        }
+    }
+    references
+}
+
+/// SNOMED CT code and display for a predicted risk outcome
+fn risk_outcome(risk: RiskKind) -> (&'static str, &'static str) {
+    match risk {
+        RiskKind::Tachycardia => ("3424008", "Tachycardia"),
+        RiskKind::Bradycardia => ("48867003", "Bradycardia"),
+        RiskKind::HypertensiveCrisis => ("706882009", "Hypertensive crisis"),
+        RiskKind::Fever => ("386661006", "Fever"),
+        RiskKind::Hyperglycemia => ("80394007", "Hyperglycemia"),
+        RiskKind::Hypoglycemia => ("302866003", "Hypoglycemia"),
+        RiskKind::Hypoxemia => ("389087006", "Hypoxemia"),
+        RiskKind::Tachypnea => ("271823003", "Tachypnea"),
+        RiskKind::Bradypnea => ("86684002", "Bradypnea"),
+        RiskKind::ReducedConsciousness => ("3006004", "Disturbance of consciousness"),
+        RiskKind::Obesity => ("414916001", "Obesity"),
+        RiskKind::Underweight => ("248342006", "Underweight"),
+    }
+}
+
+/// Flagged records, each with its occurrence (see `occurrences`), its
+/// vital sign risks and the labels of the `[[observations]]` thresholds it
+/// is outside of, as a collection Bundle with one RiskAssessment per record
+pub fn risks_to_bundle(flagged: &[(&PatientRecord, usize, Vec<RiskKind>, Vec<String>)]) -> Value {
+    let mut entries = vec![];
+    for (record, occurrence, risks, observation_risks) in flagged {
+        entries.push(json!({ "resource": risk_assessment(record, *occurrence, risks, observation_risks) }));
+    }
+
+    json!({
+        "resourceType": "Bundle",
+        "type": "collection",
+        "timestamp": Utc::now().to_rfc3339(),
+        "entry": entries,
+    })
+}
+
+fn risk_assessment(record: &PatientRecord, occurrence: usize, risks: &[RiskKind], observation_risks: &[String]) -> Value {
+    let id = format!("{}-risk", observation_prefix(record, occurrence));
+    let mut basis = vec![];
+    for field in risks.iter().flat_map(|risk| risk.basis()) {
+        let reference = observation_reference(record, occurrence, *field);
+        if !basis.contains(&reference) {
+            basis.push(reference);
+        }
+    }
+    let basis: Vec<Value> = basis.into_iter().map(|reference| json!({"reference": reference})).collect();
+
+    let mut predictions: Vec<Value> = risks
+        .iter()
+        .map(|risk| {
+            let (code, display) = risk_outcome(*risk);
+            json!({
+                "outcome": {
+                    "coding": [{"system": SNOMED_SYSTEM, "code": code, "display": display}],
+                    "text": risk.label(),
+                }
+            })
+        })
+        .collect();
+    // Configured thresholds have no code of their own, and the observations
+    // they check are not exported, so they add neither coding nor basis
+    predictions.extend(observation_risks.iter().map(|label| json!({"outcome": {"text": label}})));
+
+    json!({
+        "resourceType": "RiskAssessment",
+        "id": id,
+        "status": "final",
+        "method": {"text": "AktenAkrobat threshold rules"},
+        "subject": {"reference": patient_reference(&record.patient_id)},
+        "occurrenceDateTime": record.timestamp.to_string(),
+        "basis": basis,
+        "prediction": predictions,
+    })
+}
+
+/// Bulk Data manifest, as returned by a completed `$export` status request
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BulkManifest {
+    transaction_time: String,
+    #[serde(default)]
+    request: String,
+    #[serde(default)]
+    requires_access_token: bool,
+    output: Vec<BulkOutputFile>,
+    #[serde(default)]
+    error: Vec<BulkOutputFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BulkOutputFile {
+    #[serde(rename = "type")]
+    resource_type: String,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<usize>,
+}
+
+/// Read a FHIR Bulk Data export directory into patient records
+pub fn load_bulk_data(dir: &Path) -> Result<Vec<PatientRecord>, AktenError> {
+    let (records, report) = parse_bulk_data(dir)?;
+    log_import_report(&records, &report);
+    Ok(records)
+}
+
+/// Stream the NDJSON files of a Bulk Data export line by line,
+/// all Patient files before any Observation file
+pub fn parse_bulk_data(dir: &Path) -> Result<(Vec<PatientRecord>, FhirImportReport), AktenError> {
+    let files = bulk_files(dir)?;
+    let mut builder = RecordBuilder::default();
+    for resource_type in ["Patient", "Observation"] {
+        for (_, path) in files.iter().filter(|(t, _)| t == resource_type) {
+            read_ndjson(path, &mut builder)?;
+        }
+    }
+    for (resource_type, path) in files.iter().filter(|(t, _)| t != "Patient" && t != "Observation") {
+        info!("Skipping Bulk Data file {} ({} resources are not imported)", path.display(), resource_type);
+    }
+
+    Ok(builder.finish())
+}
+
+/// NDJSON files listed in the manifest, or `<Type>*.ndjson` files when there is none
+fn bulk_files(dir: &Path) -> Result<Vec<(String, PathBuf)>, AktenError> {
+    let manifest_path = dir.join(BULK_MANIFEST);
+    if manifest_path.exists() {
+        let manifest: BulkManifest = serde_json::from_reader(File::open(&manifest_path)?)?;
+        return Ok(manifest
+            .output
+            .into_iter()
+            .map(|file| {
+                // Servers list absolute download URLs; locally only the file name matters
+                let name = file.url.rsplit('/').next().unwrap_or_default().to_string();
+                (file.resource_type, dir.join(name))
+            })
+            .collect());
+    }
+
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("ndjson") {
+            continue;
+        }
+        // "Observation.ndjson", "Observation-2.ndjson", "Observation.003.ndjson"
+        let resource_type = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.split(['.', '-', '_']).next())
+            .unwrap_or_default()
+            .to_string();
+        files.push((resource_type, path));
+    }
+    if files.is_empty() {
+        return Err(AktenError::Fhir(format!(
+            "no {} or .ndjson files found in {}",
+            BULK_MANIFEST,
+            dir.display()
+        )));
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn read_ndjson(path: &Path, builder: &mut RecordBuilder) -> Result<(), AktenError> {
+    let reader = BufReader::new(File::open(path)?);
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let position = format!("{}:{}", path.display(), index + 1);
+        let resource: Resource = serde_json::from_str(&line)
+            .map_err(|e| AktenError::Fhir(format!("{}: {}", position, e)))?;
+        match resource {
+            Resource::Patient(patient) => builder.add_patient(&patient, None),
+            Resource::Observation(observation) => builder.add_observation(*observation, Some(position)),
+            Resource::Other => {}
+        }
+    }
+    Ok(())
+}
+
+/// Write records as a Bulk Data export: `Patient.ndjson`, `Observation.ndjson`
+/// and a manifest, one resource per line
+pub fn write_bulk_data(
+    records: &[PatientRecord],
+    dir: &Path,
+    profile: &FhirProfile,
+    primary_system: &str,
+) -> Result<(), AktenError> {
+    // Resources are cheap to rebuild, so check them in a first pass instead of holding them
+    profiles::ensure_conformant(
+        profiled(patient_resources(records, primary_system), profile)
+            .chain(profiled(observation_resources(records), profile)),
+    )?;
+
+    fs::create_dir_all(dir)?;
+    let output = vec![
+        write_ndjson(dir, "Patient", profiled(patient_resources(records, primary_system), profile))?,
+        write_ndjson(dir, "Observation", profiled(observation_resources(records), profile))?,
+    ];
+
+    let manifest = BulkManifest {
+        transaction_time: Utc::now().to_rfc3339(),
+        request: "$export".to_string(),
+        requires_access_token: false,
+        output,
+        error: vec![],
+    };
+    serde_json::to_writer_pretty(File::create(dir.join(BULK_MANIFEST))?, &manifest)?;
+    Ok(())
+}
+
+fn write_ndjson(
+    dir: &Path,
+    resource_type: &str,
+    resources: impl Iterator<Item = Value>,
+) -> Result<BulkOutputFile, AktenError> {
+    let name = format!("{}.ndjson", resource_type);
+    let mut writer = BufWriter::new(File::create(dir.join(&name))?);
+    let mut count = 0;
+    for resource in resources {
+        serde_json::to_writer(&mut writer, &resource)?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+    writer.flush()?;
+
+    Ok(BulkOutputFile {
+        resource_type: resource_type.to_string(),
+        url: name,
+        count: Some(count),
+    })
+}
+
+/// LOINC coding and UCUM unit of one measured field, in its canonical unit
+pub fn vital_coding(field: VitalField) -> &'static VitalCoding {
+    match field {
+        VitalField::HeartRate => &HEART_RATE,
+        VitalField::BpSystolic => &BP_SYSTOLIC,
+        VitalField::BpDiastolic => &BP_DIASTOLIC,
+        VitalField::Temperature => &BODY_TEMPERATURE,
+        VitalField::BloodSugar => &GLUCOSE_MASS,
+        VitalField::Steps => &STEPS,
+        VitalField::Spo2 => &SPO2,
+        VitalField::RespiratoryRate => &RESPIRATORY_RATE,
+        VitalField::Weight => &BODY_WEIGHT,
+        VitalField::Height => &BODY_HEIGHT,
+        VitalField::Bmi => &BMI,
+    }
+}
+
+/// Id part shared by the resources of one record: a hash of patient id and
+/// measurement time as written, so UUID patients and RFC 3339 times with
+/// either offset sign still leave room for the suffixes within 64 characters
+fn record_key(record: &PatientRecord) -> String {
+    format!("{:016x}", fnv1a(format!("{}|{}", record.patient_id, record.timestamp).as_bytes()))
+}
+
+/// FHIR ids allow only `[A-Za-z0-9\-\.]{1,64}`; other ids are replaced by
+/// a hash of their own, as any shortening or replacing of characters would
+/// let different patients share an id
+fn resource_id(raw: &str) -> String {
+    let valid = (1..=64).contains(&raw.len()) && raw.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    if valid {
+        raw.to_string()
+    } else {
+        format!("{:016x}", fnv1a(raw.as_bytes()))
+    }
+}
+
+/// Shortest decimal form of an f32 (avoids 36.59999847 in the output)
+fn f32_value(value: f32) -> f64 {
+    value.to_string().parse().unwrap_or(value as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation(id: &str, subject: &str, code: &str, value: f64) -> String {
+        format!(
+            r#"{{"resource": {{"resourceType": "Observation", "id": "{id}", "status": "final",
+                "code": {{"coding": [{{"system": "http://loinc.org", "code": "{code}"}}]}},
+                "subject": {{"reference": "{subject}"}},
+                "effectiveDateTime": "2024-12-01T08:30:00+01:00",
+                "valueQuantity": {{"value": {value}}}}}}}"#
+        )
+    }
+
+    fn blood_pressure(id: &str, subject: &str) -> String {
+        format!(
+            r#"{{"resource": {{"resourceType": "Observation", "id": "{id}", "status": "final",
+                "code": {{"coding": [{{"system": "http://loinc.org", "code": "85354-9"}}]}},
+                "subject": {{"reference": "{subject}"}},
+                "effectiveDateTime": "2024-12-01T08:30:00+01:00",
+                "component": [
+                    {{"code": {{"coding": [{{"system": "http://loinc.org", "code": "8480-6"}}]}}, "valueQuantity": {{"value": 120}}}},
+                    {{"code": {{"coding": [{{"system": "http://loinc.org", "code": "8462-4"}}]}}, "valueQuantity": {{"value": 80}}}}
+                ]}}}}"#
+        )
+    }
+
+    #[test]
+    fn test_transaction_bundle_groups_by_subject_and_date() {
+        let entries = [
+            r#"{"fullUrl": "urn:uuid:p1", "resource": {"resourceType": "Patient", "id": "1"}}"#.to_string(),
+            observation("hr", "urn:uuid:p1", "8867-4", 78.0),
+            blood_pressure("bp", "urn:uuid:p1"),
+            observation("temp", "Patient/1", "8310-5", 36.6),
+            observation("glu", "Patient/1", "2339-0", 92.0),
+            observation("steps", "Patient/1", "55423-8", 4500.0),
+        ];
+        let bundle = format!(
+            r#"{{"resourceType": "Bundle", "type": "transaction", "entry": [{}]}}"#,
+            entries.join(",")
+        );
+
+        let (records, report) = parse_bundle(&bundle).unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.patient_id, "1");
+        assert_eq!(record.timestamp.to_string(), "2024-12-01T08:30:00+01:00");
+        assert_eq!((record.heart_rate, record.bp_systolic, record.bp_diastolic), (Some(78), Some(120), Some(80)));
+        assert_eq!(record.steps, Some(4500));
+        assert_eq!(report.observations_mapped, 6);
+        assert!(report.unmapped.is_empty() && report.incomplete.is_empty());
+    }
+
+    #[test]
+    fn test_unmapped_and_incomplete_are_reported() {
+        let entries = [
+            observation("hr", "Patient/2", "8867-4", 102.0),
+            observation("creatinine", "Patient/2", "2160-0", 0.9),
+            observation("anon", "Patient/abc", "8867-4", 70.0),
+        ];
+        let bundle = format!(
+            r#"{{"resourceType": "Bundle", "type": "searchset", "entry": [{}]}}"#,
+            entries.join(",")
+        );
+
+        // Partial records are kept; their missing vitals are reported
+        let (records, report) = parse_bundle(&bundle).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].heart_rate, records[0].bp_systolic), (Some(102), None));
+        assert_eq!(records[1].patient_id, "abc");
+        assert_eq!(report.unmapped.len(), 1);
+        assert_eq!(report.incomplete.len(), 2);
+        assert!(report.incomplete[0].contains("bp_systolic"));
+    }
+
+    #[test]
+    fn test_values_are_converted_to_record_units() {
+        let entries = [
+            observation("glu", "Patient/1", "15074-8", 5.2),
+            observation("temp", "Patient/1", "8310-5", 98.6),
+            observation("glu2", "Patient/2", "2339-0", 5.2),
+            observation("temp2", "Patient/2", "8310-5", 36.6).replace(r#""value": 36.6"#, r#""value": 36.6, "code": "mmol/L""#),
+        ];
+        let bundle = format!(r#"{{"resourceType": "Bundle", "type": "collection", "entry": [{}]}}"#, entries.join(","));
+
+        let (records, report) = parse_bundle(&bundle).unwrap();
+        assert_eq!((records[0].blood_sugar, records[0].temperature), (Some(93.7), Some(37.0)));
+        assert_eq!(records[0].blood_sugar_unit, Some(units::Unit::MilligramsPerDeciliter));
+        // Read as mg/dL by its code, however low; the temperature's unit does not fit
+        assert_eq!((records[1].blood_sugar, records[1].temperature), (Some(5.2), None));
+        assert_eq!(report.unmapped.len(), 1, "{:?}", report.unmapped);
+    }
+
+    #[test]
+    fn test_rejects_non_bundle() {
+        let result = parse_bundle(r#"{"resourceType": "Patient", "id": "1"}"#);
+        assert!(matches!(result, Err(AktenError::Fhir(_))));
+    }
+
+    fn sample_record(patient_id: &str) -> PatientRecord {
+        PatientRecord {
+            patient_id: patient_id.to_string(),
+            timestamp: Timestamp::parse("2024-12-01").unwrap(),
+            heart_rate: Some(78),
+            bp_systolic: Some(120),
+            bp_diastolic: Some(80),
+            temperature: Some(36.6),
+            blood_sugar: Some(92.0),
+            steps: Some(4500),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_exported_bundle_round_trips() {
+        let records = vec![sample_record("1"), sample_record("2")];
+        let bundle = records_to_bundle(&records, BundleType::Collection, &FhirProfile::Core, identity::LOCAL_SYSTEM).unwrap();
+
+        let (imported, report) = parse_bundle(&bundle.to_string()).unwrap();
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[1].patient_id, "2");
+        assert_eq!(imported[0].temperature, Some(36.6));
+        assert_eq!(imported[0].blood_sugar, Some(92.0));
+        assert!(report.unmapped.is_empty() && report.incomplete.is_empty());
+    }
+
+    #[test]
+    fn test_extended_vitals_round_trip() {
+        let mut record = sample_record("3");
+        record.spo2 = Some(91);
+        record.respiratory_rate = Some(24);
+        record.supplemental_oxygen = Some(true);
+        record.consciousness = Some(Consciousness::Voice);
+        (record.weight, record.height) = (Some(70.0), Some(175.0));
+        let bundle = records_to_bundle(&[record], BundleType::Collection, &FhirProfile::Core, identity::LOCAL_SYSTEM).unwrap();
+
+        let (imported, report) = parse_bundle(&bundle.to_string()).unwrap();
+        let imported = &imported[0];
+        assert_eq!((imported.spo2, imported.respiratory_rate), (Some(91), Some(24)));
+        assert_eq!((imported.supplemental_oxygen, imported.consciousness), (Some(true), Some(Consciousness::Voice)));
+        assert_eq!((imported.weight, imported.height, imported.bmi()), (Some(70.0), Some(175.0), Some(22.9)));
+        assert!(report.unmapped.is_empty(), "{:?}", report.unmapped);
+    }
+
+    #[test]
+    fn test_identifiers_round_trip() {
+        let mut record = sample_record("KH-0815/7");
+        record.identifiers = [
+            identity::Identifier::new("urn:oid:1.2.276.0.1", "KH-0815/7"),
+            identity::Identifier::new(identity::KVNR_SYSTEM, "A123456789"),
+        ]
+        .into_iter()
+        .collect();
+        let bundle = records_to_bundle(&[record], BundleType::Collection, &FhirProfile::Core, "urn:oid:1.2.276.0.1").unwrap();
+
+        let patient = &bundle["entry"][0]["resource"];
+        let id = resource_id("KH-0815/7");
+        assert_eq!(patient["id"], id.as_str());
+        assert_eq!(patient["identifier"][0]["system"], "urn:oid:1.2.276.0.1");
+        assert_eq!(patient["identifier"][1]["value"], "A123456789");
+        assert_eq!(patient["identifier"].as_array().unwrap().len(), 2);
+        assert_eq!(bundle["entry"][1]["resource"]["subject"]["reference"], format!("Patient/{}", id));
+
+        let (imported, _) = parse_bundle(&bundle.to_string()).unwrap();
+        assert_eq!(imported[0].patient_id, id);
+        assert_eq!(imported[0].identifiers.get("urn:oid:1.2.276.0.1"), Some("KH-0815/7"));
+        assert_eq!(imported[0].identifiers.get(identity::KVNR_SYSTEM), Some("A123456789"));
+    }
+
+    #[test]
+    fn test_patients_keep_distinct_resource_ids() {
+        let long = "urn:oid:1.2.276.0.76.3.1.131.1.5.1.2.3.4.5.6.7.8.9.10.11.12.13.14.15.16.17.18.19.20";
+        let ids = ["KH-0815/7", "KH-0815-7", &format!("{}.1", long), &format!("{}.2", long)];
+        let records: Vec<PatientRecord> = ids.iter().map(|id| sample_record(id)).collect();
+        let bundle = records_to_bundle(&records, BundleType::Collection, &FhirProfile::Core, identity::LOCAL_SYSTEM).unwrap();
+
+        let patients: Vec<&Value> = bundle["entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| &entry["resource"])
+            .filter(|resource| resource["resourceType"] == "Patient")
+            .collect();
+        let patient_ids: BTreeSet<&str> = patients.iter().map(|patient| patient["id"].as_str().unwrap()).collect();
+        assert_eq!(patient_ids.len(), 4);
+        assert!(patient_ids.contains("KH-0815-7"), "valid ids are kept as they are");
+        assert!(patient_ids.iter().all(|id| id.len() <= 64));
+        let references: BTreeSet<String> = bundle["entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|entry| entry["resource"]["subject"]["reference"].as_str().map(str::to_string))
+            .collect();
+        assert_eq!(references, patient_ids.iter().map(|id| format!("Patient/{}", id)).collect());
+    }
+
+    #[test]
+    fn test_patient_demographics_round_trip() {
+        let mut record = sample_record("7");
+        record.birth_date = NaiveDate::from_ymd_opt(1950, 6, 15);
+        record.sex = Some(Sex::Female);
+        let bundle = records_to_bundle(&[record], BundleType::Collection, &FhirProfile::Core, identity::LOCAL_SYSTEM).unwrap();
+
+        let patient = &bundle["entry"][0]["resource"];
+        assert_eq!((patient["gender"].as_str(), patient["birthDate"].as_str()), (Some("female"), Some("1950-06-15")));
+        let (imported, _) = parse_bundle(&bundle.to_string()).unwrap();
+        assert_eq!((imported[0].birth_date, imported[0].sex), (NaiveDate::from_ymd_opt(1950, 6, 15), Some(Sex::Female)));
+        let demographics = parse_patients(&bundle.to_string()).unwrap();
+        assert_eq!((demographics[0].patient_id.as_str(), demographics[0].sex), ("7", Some(Sex::Female)));
+    }
+
+    #[test]
+    fn test_transaction_bundle_uses_put_requests() {
+        let records = vec![sample_record("1"), sample_record("1")];
+        let bundle = records_to_bundle(&records, BundleType::Transaction, &FhirProfile::Core, identity::LOCAL_SYSTEM).unwrap();
+
+        assert_eq!(bundle["type"], "transaction");
+        let entries = bundle["entry"].as_array().unwrap();
+        // One patient, five observations per record
+        assert_eq!(entries.len(), 11);
+        assert_eq!(entries[0]["request"]["url"], "Patient/1");
+        let urls: BTreeSet<&str> = entries.iter().map(|e| e["request"]["url"].as_str().unwrap()).collect();
+        assert_eq!(urls.len(), entries.len(), "resource ids must be unique");
+        assert!(urls.contains(format!("Observation/{}-2-2339-0", record_key(&records[0])).as_str()));
+    }
+
+    #[test]
+    fn test_uuid_patients_get_unique_observation_ids() {
+        let record = |timestamp: &str| PatientRecord {
+            patient_id: "3f2504e0-4f89-11d3-9a0c-0305e82c3301".to_string(),
+            timestamp: Timestamp::parse(timestamp).unwrap(),
+            heart_rate: Some(78),
+            bp_systolic: Some(120),
+            bp_diastolic: Some(80),
+            temperature: Some(36.6),
+            ..Default::default()
+        };
+        let records = vec![record("2024-12-01T08:30:00+01:00"), record("2024-12-01T08:30:00-01:00")];
+        let bundle = records_to_bundle(&records, BundleType::Transaction, &FhirProfile::Core, identity::LOCAL_SYSTEM).unwrap();
+
+        let ids: Vec<&str> = bundle["entry"].as_array().unwrap().iter().map(|e| e["resource"]["id"].as_str().unwrap()).collect();
+        assert_eq!(ids.len(), 7);
+        assert_eq!(ids.iter().collect::<BTreeSet<_>>().len(), ids.len(), "{:?}", ids);
+        assert!(ids.iter().all(|id| id.len() <= 64));
+        assert!(ids.contains(&"3f2504e0-4f89-11d3-9a0c-0305e82c3301"));
+        let temperature = observation_reference(&records[1], 1, VitalField::Temperature);
+        assert!(ids.iter().any(|id| temperature == format!("Observation/{}", id)) && temperature.ends_with("-8310-5"));
+    }
+
+    #[test]
+    fn test_bulk_data_round_trips() -> Result<(), AktenError> {
+        let dir = tempfile::tempdir()?;
+        let records = vec![sample_record("1"), sample_record("2"), sample_record("1")];
+        write_bulk_data(&records, dir.path(), &FhirProfile::Core, identity::LOCAL_SYSTEM)?;
+
+        let observations = fs::read_to_string(dir.path().join("Observation.ndjson"))?;
+        assert_eq!(observations.lines().count(), 15);
+        let manifest: BulkManifest = serde_json::from_reader(File::open(dir.path().join(BULK_MANIFEST))?)?;
+        assert_eq!(manifest.output[0].count, Some(2));
+
+        let (imported, report) = parse_bulk_data(dir.path())?;
+        // Both records of patient 1 fall on the same day, the second one's values are duplicates
+        assert_eq!(imported.len(), 2);
+        assert_eq!(report.unmapped.len(), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_data_without_manifest_reports_bad_line() -> Result<(), AktenError> {
+        let dir = tempfile::tempdir()?;
+        fs::write(
+            dir.path().join("Observation.ndjson"),
+            "{\"resourceType\": \"Observation\", \"status\": \"final\"}\n\nnot json\n",
+        )?;
+
+        let err = parse_bulk_data(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("Observation.ndjson:3"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_findings_bundle_references_observations() {
+        let record = sample_record("7");
+        let finding = |kind, critical, fields: Vec<VitalField>| Finding {
+            kind,
+            critical,
+            message: "test".to_string(),
+            fields,
+            record: record.clone(),
+            occurrence: 1,
+        };
+        let findings = vec![
+            finding(FindingKind::Clinical, true, vec![VitalField::BpSystolic, VitalField::BpDiastolic]),
+            finding(FindingKind::Clinical, false, vec![VitalField::BloodSugar]),
+            finding(FindingKind::DataQuality, false, vec![VitalField::HeartRate]),
+        ];
+
+        let bundle = findings_to_bundle(&findings);
+        let entries = bundle["entry"].as_array().unwrap();
+        assert_eq!(entries.len(), 3);
+
+        let outcome = &entries[0]["resource"];
+        assert_eq!(outcome["resourceType"], "OperationOutcome");
+        let key = record_key(&record);
+        assert_eq!(outcome["issue"][0]["extension"][1]["valueReference"]["reference"], format!("Observation/{}-8867-4", key));
+
+        let crisis = &entries[1]["resource"];
+        assert_eq!(crisis["severity"], "high");
+        assert_eq!(crisis["patient"]["reference"], "Patient/7");
+        assert_eq!(crisis["implicated"].as_array().unwrap().len(), 1);
+        assert_eq!(crisis["implicated"][0]["reference"], format!("Observation/{}-85354-9", key));
+        assert_eq!(entries[2]["resource"]["severity"], "moderate");
+        assert_eq!(entries[2]["resource"]["implicated"][0]["reference"], format!("Observation/{}-2339-0", key));
+    }
+
+    #[test]
+    fn test_risk_assessment_codes_outcomes_and_basis() {
+        let record = sample_record("3");
+        let flagged = vec![(&record, 1, vec![RiskKind::Tachycardia, RiskKind::Fever], vec!["Severe pain".to_string()])];
+
+        let bundle = risks_to_bundle(&flagged);
+        let assessment = &bundle["entry"][0]["resource"];
+        assert_eq!(assessment["resourceType"], "RiskAssessment");
+        assert_eq!(assessment["occurrenceDateTime"], "2024-12-01");
+        assert_eq!(assessment["subject"]["reference"], "Patient/3");
+        assert_eq!(assessment["prediction"][0]["outcome"]["coding"][0]["code"], "3424008");
+        assert_eq!(assessment["prediction"][1]["outcome"]["text"], "Fever");
+        assert_eq!(assessment["prediction"][2]["outcome"], json!({"text": "Severe pain"}));
+        assert_eq!(assessment["basis"][1]["reference"], format!("Observation/{}-8310-5", record_key(&record)));
+    }
+
+    #[test]
+    fn test_german_profile_bundle_conforms_and_round_trips() {
+        let profile = FhirProfile::parse("de").unwrap();
+        let records = vec![sample_record("1"), sample_record("2")];
+        let bundle = records_to_bundle(&records, BundleType::Collection, &profile, identity::LOCAL_SYSTEM).unwrap();
+
+        let entries = bundle["entry"].as_array().unwrap();
+        assert_eq!(entries[0]["resource"]["identifier"][0]["value"], "1");
+        assert_eq!(entries[2]["resource"]["code"]["text"], "Herzfrequenz");
+
+        let (imported, report) = parse_bundle(&bundle.to_string()).unwrap();
+        assert_eq!(imported.len(), 2);
+        assert!(report.unmapped.is_empty());
+    }
 }
@@ -0,0 +1,510 @@
+use crate::fhir::{self, PartialRecord, VitalField};
+use crate::timestamp::Timestamp;
+use crate::units;
+use crate::{AktenError, PatientRecord};
+use chrono::{NaiveDate, NaiveTime};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use tracing::{info, warn};
+
+/// Field identifiers used by the import
+const FIELD_RECORD_TYPE: &str = "8000";
+const FIELD_CHARSET: &str = "9206";
+const FIELD_PATIENT_NUMBER: &str = "3000";
+const FIELD_EXAMINATION_DATE: &str = "6200";
+const FIELD_EXAMINATION_TIME: &str = "6201";
+const FIELD_TEST_ID: &str = "8410";
+const FIELD_TEST_NAME: &str = "8411";
+const FIELD_RESULT: &str = "8420";
+const FIELD_UNIT: &str = "8421";
+
+/// Three length digits, four field identifier digits and CR LF
+const MIN_LINE_LENGTH: usize = 9;
+
+/// Test identifiers (8410) written by common practice devices; LOINC codes are accepted too
+/// ("RR" is Riva-Rocci blood pressure; the respiratory rate is "AF", Atemfrequenz)
+const TEST_IDS: [(&str, VitalField); 20] = [
+    ("HF", VitalField::HeartRate),
+    ("HR", VitalField::HeartRate),
+    ("PULS", VitalField::HeartRate),
+    ("RRS", VitalField::BpSystolic),
+    ("SYS", VitalField::BpSystolic),
+    ("RRSYS", VitalField::BpSystolic),
+    ("RRD", VitalField::BpDiastolic),
+    ("DIA", VitalField::BpDiastolic),
+    ("RRDIA", VitalField::BpDiastolic),
+    ("TEMP", VitalField::Temperature),
+    ("KT", VitalField::Temperature),
+    ("BZ", VitalField::BloodSugar),
+    ("GLU", VitalField::BloodSugar),
+    ("SCHRITTE", VitalField::Steps),
+    ("SPO2", VitalField::Spo2),
+    ("SAO2", VitalField::Spo2),
+    ("AF", VitalField::RespiratoryRate),
+    ("GEW", VitalField::Weight),
+    ("GEWICHT", VitalField::Weight),
+    ("GROESSE", VitalField::Height),
+];
+
+/// Combined "120/80" blood pressure results
+const BP_TEST_IDS: [&str; 2] = ["RR", "NIBP"];
+
+/// Character set declared in field 9206
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Charset {
+    /// 1: 7-bit ASCII with the German DIN 66003 replacements
+    Din66003,
+    /// 2: IBM code page 437, the GDT default
+    #[default]
+    Cp437,
+    /// 3: ISO 8859-15 (ISO 8859-1 in GDT 2.1, identical for letters)
+    Iso8859_15,
+}
+
+impl Charset {
+    pub fn from_field(value: &str) -> Option<Self> {
+        match value.trim() {
+            "1" => Some(Self::Din66003),
+            "2" => Some(Self::Cp437),
+            "3" => Some(Self::Iso8859_15),
+            _ => None,
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        bytes
+            .iter()
+            .map(|&b| match self {
+                Self::Din66003 => din66003(b),
+                Self::Cp437 if b >= 0x80 => CP437_HIGH[(b - 0x80) as usize],
+                Self::Iso8859_15 => iso8859_15(b),
+                Self::Cp437 => b as char,
+            })
+            .collect()
+    }
+}
+
+fn din66003(byte: u8) -> char {
+    match byte {
+        b'@' => '§',
+        b'[' => 'Ä',
+        b'\\' => 'Ö',
+        b']' => 'Ü',
+        b'{' => 'ä',
+        b'|' => 'ö',
+        b'}' => 'ü',
+        b'~' => 'ß',
+        b => b as char,
+    }
+}
+
+/// ISO 8859-15 is Latin-1 apart from eight positions
+fn iso8859_15(byte: u8) -> char {
+    match byte {
+        0xa4 => '€',
+        0xa6 => 'Š',
+        0xa8 => 'š',
+        0xb4 => 'Ž',
+        0xb8 => 'ž',
+        0xbc => 'Œ',
+        0xbd => 'œ',
+        0xbe => 'Ÿ',
+        b => b as char,
+    }
+}
+
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// One `LLLFFFF<content>CR LF` line with its content still in the file's encoding
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub number: usize,
+    pub field: String,
+    pub content: Vec<u8>,
+}
+
+/// Split a GDT/LDT file into lines, checking each declared length against the
+/// bytes actually present (the length counts itself, the field id and CR LF)
+pub fn read_lines(bytes: &[u8]) -> Result<Vec<Line>, AktenError> {
+    let mut lines = vec![];
+    // A DOS end-of-file marker after the last line is tolerated
+    let bytes = bytes.strip_suffix(&[0x1a]).unwrap_or(bytes);
+
+    for (index, raw) in bytes.split_inclusive(|&b| b == b'\n').enumerate() {
+        let number = index + 1;
+        let error = |message: String| AktenError::Gdt(format!("line {}: {}", number, message));
+
+        if raw.len() < MIN_LINE_LENGTH {
+            return Err(error(format!(
+                "{} bytes is shorter than the {}-byte minimum",
+                raw.len(),
+                MIN_LINE_LENGTH
+            )));
+        }
+        let prefix = String::from_utf8_lossy(&raw[..3]);
+        let declared: usize = prefix
+            .parse()
+            .map_err(|_| error(format!("length prefix '{}' is not three digits", prefix)))?;
+        if declared != raw.len() {
+            return Err(error(format!(
+                "declared length {:03} but the line is {} bytes",
+                declared,
+                raw.len()
+            )));
+        }
+        if !raw.ends_with(b"\r\n") {
+            return Err(error("line does not end with CR LF".to_string()));
+        }
+        let field = &raw[3..7];
+        if !field.iter().all(u8::is_ascii_digit) {
+            return Err(error(format!(
+                "field identifier '{}' is not four digits",
+                String::from_utf8_lossy(field)
+            )));
+        }
+
+        lines.push(Line {
+            number,
+            field: String::from_utf8_lossy(field).into_owned(),
+            content: raw[7..raw.len() - 2].to_vec(),
+        });
+    }
+    Ok(lines)
+}
+
+/// Outcome of a GDT import
+#[derive(Debug, Default)]
+pub struct GdtImportReport {
+    pub records: usize,
+    pub observations_mapped: usize,
+    pub issues: Vec<String>,
+    pub incomplete: Vec<String>,
+}
+
+/// Convert a GDT 2.1/3.0 device file into patient records
+pub fn convert_gdt_to_records(mut file: File) -> Result<Vec<PatientRecord>, AktenError> {
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+
+    let (records, report) = parse_gdt(&bytes)?;
+    for issue in &report.issues {
+        warn!("GDT {}", issue);
+    }
+    for issue in &report.incomplete {
+        warn!("GDT record incomplete: {}", issue);
+    }
+    info!(
+        "GDT import: {} records, {} observations mapped into {} patient records ({} issues, {} incomplete)",
+        report.records,
+        report.observations_mapped,
+        records.len(),
+        report.issues.len(),
+        report.incomplete.len()
+    );
+    Ok(records)
+}
+
+/// A test (8410) with the result lines that follow it
+#[derive(Debug, Default)]
+struct Test {
+    line: usize,
+    id: String,
+    name: String,
+    result: Option<String>,
+    unit: String,
+    date: Option<Timestamp>,
+}
+
+/// One GDT record, started by field 8000
+#[derive(Debug, Default)]
+struct GdtRecord {
+    line: usize,
+    patient: Option<String>,
+    date: Option<Timestamp>,
+    current_date: Option<Timestamp>,
+    tests: Vec<Test>,
+}
+
+/// Parse GDT records and group their test results by patient and examination time
+pub fn parse_gdt(bytes: &[u8]) -> Result<(Vec<PatientRecord>, GdtImportReport), AktenError> {
+    let lines = read_lines(bytes)?;
+    let charset = match lines.iter().find(|l| l.field == FIELD_CHARSET) {
+        Some(line) => {
+            let value = String::from_utf8_lossy(&line.content);
+            Charset::from_field(&value).ok_or_else(|| {
+                AktenError::Gdt(format!("line {}: unknown character set '{}' in field 9206", line.number, value))
+            })?
+        }
+        None => Charset::default(),
+    };
+
+    let mut gdt_records: Vec<GdtRecord> = vec![];
+    for line in &lines {
+        let content = charset.decode(&line.content).trim().to_string();
+        if line.field == FIELD_RECORD_TYPE {
+            gdt_records.push(GdtRecord {
+                line: line.number,
+                ..Default::default()
+            });
+            continue;
+        }
+        let Some(record) = gdt_records.last_mut() else {
+            return Err(AktenError::Gdt(format!(
+                "line {}: field {} before the first record type (8000)",
+                line.number, line.field
+            )));
+        };
+        match line.field.as_str() {
+            FIELD_PATIENT_NUMBER => record.patient = Some(content),
+            FIELD_EXAMINATION_DATE => {
+                let date = gdt_date(&content).ok_or_else(|| {
+                    AktenError::Gdt(format!("line {}: date '{}' is not TTMMJJJJ", line.number, content))
+                })?;
+                // The first 6200 dates the whole record; later ones date the tests after them
+                let date = Timestamp::from_date(date);
+                record.date.get_or_insert(date);
+                record.current_date = Some(date);
+            }
+            // 6201 adds the time of day to the 6200 before it
+            FIELD_EXAMINATION_TIME => {
+                let Some(date) = record.current_date.filter(Timestamp::is_date_only) else {
+                    return Err(AktenError::Gdt(format!("line {}: time (6201) without a date (6200)", line.number)));
+                };
+                let timestamp = NaiveTime::parse_from_str(&format!("{:0<6}", content), "%H%M%S")
+                    .ok()
+                    .and_then(|time| Timestamp::from_local(date.date().and_time(time)))
+                    .ok_or_else(|| AktenError::Gdt(format!("line {}: time '{}' is not HHMMSS", line.number, content)))?;
+                if record.date == Some(date) {
+                    record.date = Some(timestamp);
+                }
+                record.current_date = Some(timestamp);
+            }
+            FIELD_TEST_ID => record.tests.push(Test {
+                line: line.number,
+                id: content,
+                date: record.current_date,
+                ..Default::default()
+            }),
+            FIELD_TEST_NAME | FIELD_RESULT | FIELD_UNIT => {
+                let Some(test) = record.tests.last_mut() else {
+                    return Err(AktenError::Gdt(format!(
+                        "line {}: field {} outside a test (8410)",
+                        line.number, line.field
+                    )));
+                };
+                match line.field.as_str() {
+                    FIELD_TEST_NAME => test.name = content,
+                    FIELD_RESULT => test.result = Some(content),
+                    _ => test.unit = content,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut report = GdtImportReport {
+        records: gdt_records.len(),
+        ..Default::default()
+    };
+    let mut grouped: BTreeMap<(String, Timestamp), PartialRecord> = BTreeMap::new();
+    for record in &gdt_records {
+        map_record(record, &mut grouped, &mut report);
+    }
+
+    let records = fhir::complete_records(grouped, &mut report.incomplete);
+    Ok((records, report))
+}
+
+fn map_record(
+    record: &GdtRecord,
+    grouped: &mut BTreeMap<(String, Timestamp), PartialRecord>,
+    report: &mut GdtImportReport,
+) {
+    let label = format!("record at line {}", record.line);
+    if record.tests.is_empty() {
+        return;
+    }
+    let Some(patient_id) = record.patient.as_deref().map(str::trim).filter(|p| !p.is_empty()) else {
+        report.issues.push(format!("{}: no patient number (3000)", label));
+        return;
+    };
+
+    for test in &record.tests {
+        let location = format!("{} test '{}' (line {})", label, test.id, test.line);
+        let Some(timestamp) = test.date.or(record.date) else {
+            report.issues.push(format!("{}: no examination date (6200)", location));
+            continue;
+        };
+        let Some(result) = test.result.as_deref() else {
+            report.issues.push(format!("{}: no result (8420)", location));
+            continue;
+        };
+        let partial = grouped.entry((patient_id.to_string(), timestamp)).or_default();
+        map_test(test, result, &location, partial, report);
+    }
+}
+
+fn map_test(test: &Test, result: &str, location: &str, partial: &mut PartialRecord, report: &mut GdtImportReport) {
+    let id = test.id.to_uppercase();
+    let values: Vec<(VitalField, &str)> = if BP_TEST_IDS.contains(&id.as_str()) {
+        match result.split_once('/') {
+            Some((systolic, diastolic)) => vec![(VitalField::BpSystolic, systolic), (VitalField::BpDiastolic, diastolic)],
+            None => {
+                report.issues.push(format!("{}: blood pressure '{}' is not systolic/diastolic", location, result));
+                return;
+            }
+        }
+    } else {
+        let field = TEST_IDS
+            .iter()
+            .find(|(test_id, _)| *test_id == id)
+            .map(|(_, field)| *field)
+            .or_else(|| VitalField::from_loinc(&test.id));
+        match field {
+            Some(field) => vec![(field, result)],
+            None => {
+                report.issues.push(format!("{}: '{}' is not mapped", location, test.name));
+                return;
+            }
+        }
+    };
+
+    for (field, raw) in values {
+        // German devices write decimal commas
+        let Ok(value) = raw.trim().replace(',', ".").parse::<f64>() else {
+            report.issues.push(format!("{}: {:?} result '{}' is not numeric", location, field, raw));
+            continue;
+        };
+        let value = match units::convert(field, value, &test.unit) {
+            Ok(value) => value,
+            Err(issue) => {
+                report.issues.push(format!("{}: {}", location, issue));
+                continue;
+            }
+        };
+        let slot = field.slot(partial);
+        if slot.is_some() {
+            report.issues.push(format!("{}: duplicate {:?} value ignored", location, field));
+        } else {
+            *slot = Some(value);
+            report.observations_mapped += 1;
+        }
+    }
+}
+
+/// `TTMMJJJJ`
+fn gdt_date(raw: &str) -> Option<NaiveDate> {
+    if raw.len() != 8 || !raw.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    NaiveDate::parse_from_str(raw, "%d%m%Y").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a GDT line with its length prefix
+    fn line(field: &str, content: &[u8]) -> Vec<u8> {
+        let mut bytes = format!("{:03}{}", content.len() + MIN_LINE_LENGTH, field).into_bytes();
+        bytes.extend_from_slice(content);
+        bytes.extend_from_slice(b"\r\n");
+        bytes
+    }
+
+    fn gdt(fields: &[(&str, &[u8])]) -> Vec<u8> {
+        fields.iter().flat_map(|(field, content)| line(field, content)).collect()
+    }
+
+    #[test]
+    fn test_gdt_results_become_records() {
+        let bytes = gdt(&[
+            ("8000", b"6310"),
+            ("9206", b"2"),
+            ("3000", b"4711"),
+            // "Müller" in CP437
+            ("3101", b"M\x81ller"),
+            ("6200", b"01122024"),
+            ("8410", b"HF"),
+            ("8420", b"78"),
+            ("8421", b"/min"),
+            ("8410", b"RR"),
+            ("8420", b"120/80"),
+            ("8410", b"TEMP"),
+            ("8420", b"36,6"),
+            ("8421", b"\xf8C"),
+            ("8410", b"BZ"),
+            ("8420", b"92"),
+            ("8410", b"HS"),
+            ("8411", b"Harns\x84ure"),
+            ("8420", b"5,2"),
+        ]);
+        let (records, report) = parse_gdt(&bytes).unwrap();
+
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!((record.patient_id.as_str(), record.timestamp.to_string().as_str()), ("4711", "2024-12-01"));
+        assert_eq!((record.heart_rate, record.bp_systolic, record.bp_diastolic), (Some(78), Some(120), Some(80)));
+        assert!((record.temperature.unwrap() - 36.6).abs() < f32::EPSILON);
+        assert_eq!(report.observations_mapped, 5);
+        assert_eq!(report.issues.len(), 1);
+        assert!(report.issues[0].ends_with("test 'HS' (line 16): 'Harnsäure' is not mapped"), "{}", report.issues[0]);
+    }
+
+    #[test]
+    fn test_charsets() {
+        assert_eq!(Charset::Cp437.decode(b"Gr\x81\xe1e \xf8C"), "Grüße °C");
+        assert_eq!(Charset::Iso8859_15.decode(b"Gr\xfc\xdfe \xa4"), "Grüße €");
+        assert_eq!(Charset::Din66003.decode(b"Gr}~e"), "Grüße");
+    }
+
+    #[test]
+    fn test_malformed_line_lengths_are_rejected() {
+        let mut bytes = gdt(&[("8000", b"6310"), ("3000", b"12")]);
+        bytes.extend_from_slice(b"0158410HF\r\n");
+        let error = read_lines(&bytes).unwrap_err().to_string();
+        assert!(error.contains("line 3: declared length 015 but the line is 11 bytes"), "{}", error);
+
+        let error = read_lines(b"01380006310\n").unwrap_err().to_string();
+        assert!(error.contains("line 1: declared length 013 but the line is 12 bytes"), "{}", error);
+
+        let error = read_lines(b"x1380006310\r\n").unwrap_err().to_string();
+        assert!(error.contains("length prefix 'x13'"), "{}", error);
+    }
+
+    #[test]
+    fn test_examination_times_separate_readings() {
+        let bytes = gdt(&[
+            ("8000", b"6310"),
+            ("3000", b"4711"),
+            ("6200", b"01122024"),
+            ("6201", b"0830"),
+            ("8410", b"HF"),
+            ("8420", b"78"),
+            ("6200", b"01122024"),
+            ("6201", b"143000"),
+            ("8410", b"HF"),
+            ("8420", b"96"),
+        ]);
+        let (records, _) = parse_gdt(&bytes).unwrap();
+
+        let readings: Vec<(String, Option<u32>)> = records.iter().map(|r| (r.timestamp.to_string(), r.heart_rate)).collect();
+        assert_eq!(
+            readings,
+            vec![
+                ("2024-12-01T08:30:00+01:00".to_string(), Some(78)),
+                ("2024-12-01T14:30:00+01:00".to_string(), Some(96)),
+            ]
+        );
+    }
+}
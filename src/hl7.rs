@@ -0,0 +1,921 @@
+use crate::config::Thresholds;
+use crate::fhir::{self, PartialRecord, VitalField, LOINC_BP_PANEL};
+use crate::identity::{self, Identifier, Identifiers};
+use crate::timestamp::Timestamp;
+use crate::units;
+use crate::validate::{STAGE_HYPERTENSION_DIASTOLIC, STAGE_HYPERTENSION_SYSTOLIC};
+use crate::{AktenError, PatientRecord};
+use chrono::{FixedOffset, NaiveDate, NaiveTime, TimeZone, Utc};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use tracing::{info, warn};
+
+/// MLLP block framing characters, stripped if a captured stream is saved as-is
+const MLLP_START: char = '\u{0b}';
+const MLLP_END: char = '\u{1c}';
+
+/// IEEE 11073 MDC codes emitted by bedside monitors, with their reference ids
+const MDC_CODES: [(&str, &str, VitalField); 12] = [
+    ("147842", "MDC_ECG_HEART_RATE", VitalField::HeartRate),
+    ("149530", "MDC_PULS_OXIM_PULS_RATE", VitalField::HeartRate),
+    ("150021", "MDC_PRESS_BLD_NONINV_SYS", VitalField::BpSystolic),
+    ("150022", "MDC_PRESS_BLD_NONINV_DIA", VitalField::BpDiastolic),
+    ("150344", "MDC_TEMP", VitalField::Temperature),
+    ("150364", "MDC_TEMP_BODY", VitalField::Temperature),
+    ("160184", "MDC_CONC_GLU_CAPILLARY_WHOLEBLOOD", VitalField::BloodSugar),
+    ("150456", "MDC_PULS_OXIM_SAT_O2", VitalField::Spo2),
+    ("151562", "MDC_RESP_RATE", VitalField::RespiratoryRate),
+    ("188736", "MDC_MASS_BODY_ACTUAL", VitalField::Weight),
+    ("188740", "MDC_LEN_BODY_ACTUAL", VitalField::Height),
+    ("188752", "MDC_RATIO_MASS_BODY_LEN_SQ", VitalField::Bmi),
+];
+
+/// Separator and escape characters declared in MSH-1 and MSH-2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delimiters {
+    pub field: char,
+    pub component: char,
+    pub repetition: char,
+    pub escape: char,
+    pub subcomponent: char,
+}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Self {
+            field: '|',
+            component: '^',
+            repetition: '~',
+            escape: '\\',
+            subcomponent: '&',
+        }
+    }
+}
+
+impl Delimiters {
+    /// MSH-2 as written in a message header
+    pub fn encoding_characters(&self) -> String {
+        [self.component, self.repetition, self.escape, self.subcomponent]
+            .iter()
+            .collect()
+    }
+
+    /// Escape a value for use inside a field
+    pub fn escape(&self, value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            let sequence = match c {
+                c if c == self.field => "F",
+                c if c == self.component => "S",
+                c if c == self.subcomponent => "T",
+                c if c == self.repetition => "R",
+                c if c == self.escape => "E",
+                '\r' | '\n' => ".br",
+                _ => {
+                    escaped.push(c);
+                    continue;
+                }
+            };
+            escaped.push(self.escape);
+            escaped.push_str(sequence);
+            escaped.push(self.escape);
+        }
+        escaped
+    }
+
+    /// Resolve escape sequences (`\F\`, `\S\`, `\T\`, `\R\`, `\E\`, `\Xhh..\`, `\.br\`);
+    /// formatting sequences such as `\H\` and `\N\` are dropped
+    pub fn unescape(&self, value: &str) -> String {
+        if !value.contains(self.escape) {
+            return value.to_string();
+        }
+        let mut result = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(start) = rest.find(self.escape) {
+            result.push_str(&rest[..start]);
+            let after = &rest[start + self.escape.len_utf8()..];
+            let Some(end) = after.find(self.escape) else {
+                // Unterminated sequence: keep it literally
+                result.push_str(&rest[start..]);
+                return result;
+            };
+            let sequence = &after[..end];
+            match sequence {
+                "F" => result.push(self.field),
+                "S" => result.push(self.component),
+                "T" => result.push(self.subcomponent),
+                "R" => result.push(self.repetition),
+                "E" => result.push(self.escape),
+                ".br" => result.push('\n'),
+                hex if hex.starts_with('X') => {
+                    let bytes: Vec<u8> = (1..hex.len())
+                        .step_by(2)
+                        .filter_map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                        .collect();
+                    result.push_str(&String::from_utf8_lossy(&bytes));
+                }
+                _ => {}
+            }
+            rest = &after[end + self.escape.len_utf8()..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+/// One segment with its raw (still escaped) fields
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub name: String,
+    fields: Vec<String>,
+}
+
+impl Segment {
+    /// Raw field by HL7 position (MSH-1 is the field separator itself)
+    pub fn field(&self, position: usize) -> &str {
+        let index = if self.name == "MSH" { position.saturating_sub(1) } else { position };
+        self.fields.get(index).map(String::as_str).unwrap_or_default()
+    }
+}
+
+/// A parsed HL7 v2 message
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub delimiters: Delimiters,
+    pub segments: Vec<Segment>,
+}
+
+impl Message {
+    pub fn parse(text: &str) -> Result<Self, AktenError> {
+        let text = text.trim_matches(|c: char| c == MLLP_START || c == MLLP_END || c.is_whitespace());
+        if !text.starts_with("MSH") {
+            return Err(AktenError::Hl7("message does not start with an MSH segment".to_string()));
+        }
+
+        let mut chars = text[3..].chars();
+        let field = chars
+            .next()
+            .ok_or_else(|| AktenError::Hl7("MSH segment is truncated".to_string()))?;
+        let encoding: Vec<char> = chars.take_while(|c| *c != field).collect();
+        if encoding.len() < 4 {
+            return Err(AktenError::Hl7(format!(
+                "MSH-2 must declare four encoding characters, found '{}'",
+                encoding.iter().collect::<String>()
+            )));
+        }
+        let delimiters = Delimiters {
+            field,
+            component: encoding[0],
+            repetition: encoding[1],
+            escape: encoding[2],
+            subcomponent: encoding[3],
+        };
+
+        let segments = segment_lines(text)
+            .map(|line| {
+                let fields: Vec<String> = line.split(field).map(str::to_string).collect();
+                Segment {
+                    name: fields[0].clone(),
+                    fields,
+                }
+            })
+            .collect();
+        Ok(Self { delimiters, segments })
+    }
+
+    pub fn segment(&self, name: &str) -> Option<&Segment> {
+        self.segments.iter().find(|s| s.name == name)
+    }
+
+    /// Repetitions of a field, each split into unescaped components
+    pub fn repetitions(&self, raw: &str) -> Vec<Vec<String>> {
+        if raw.is_empty() {
+            return vec![];
+        }
+        raw.split(self.delimiters.repetition)
+            .map(|repetition| self.components(repetition))
+            .collect()
+    }
+
+    /// Unescaped components of a single field repetition
+    pub fn components(&self, raw: &str) -> Vec<String> {
+        raw.split(self.delimiters.component)
+            .map(|component| {
+                // Sub-components are not used by any mapped field; keep the first
+                let first = component.split(self.delimiters.subcomponent).next().unwrap_or_default();
+                self.delimiters.unescape(first)
+            })
+            .collect()
+    }
+
+    /// MSH-9 as "ORU^R01"
+    pub fn message_type(&self) -> String {
+        let raw = self.segment("MSH").map(|msh| msh.field(9)).unwrap_or_default();
+        let components = self.components(raw);
+        components.iter().take(2).cloned().collect::<Vec<_>>().join("^")
+    }
+
+    /// MSH-10 message control id
+    pub fn control_id(&self) -> String {
+        self.segment("MSH")
+            .map(|msh| self.delimiters.unescape(msh.field(10)))
+            .unwrap_or_default()
+    }
+}
+
+fn segment_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split(['\r', '\n']).map(str::trim_end).filter(|line| !line.is_empty())
+}
+
+/// Split a file into messages at each MSH segment, ignoring batch envelopes
+pub fn split_messages(text: &str) -> Vec<String> {
+    let mut messages: Vec<String> = vec![];
+    for line in segment_lines(text) {
+        let line = line.trim_matches(|c| c == MLLP_START || c == MLLP_END);
+        if line.is_empty() || ["FHS", "BHS", "BTS", "FTS"].iter().any(|s| line.starts_with(s)) {
+            continue;
+        }
+        if line.starts_with("MSH") {
+            messages.push(String::new());
+        }
+        // Segments before the first MSH still end up in a message so they fail parsing visibly
+        let message = match messages.last_mut() {
+            Some(message) => message,
+            None => {
+                messages.push(String::new());
+                messages.last_mut().expect("just pushed")
+            }
+        };
+        message.push_str(line);
+        message.push('\r');
+    }
+    messages
+}
+
+/// HL7 v2 defaults to ASCII, but Latin-1 is common in German systems
+pub fn decode_text(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|e| e.into_bytes().iter().map(|&b| b as char).collect())
+}
+
+/// Outcome of an HL7 import, with problems located per message and segment
+#[derive(Debug, Default)]
+pub struct Hl7ImportReport {
+    pub messages: usize,
+    pub observations_mapped: usize,
+    pub issues: Vec<String>,
+    pub incomplete: Vec<String>,
+}
+
+/// Convert a file of HL7 v2 ORU^R01 messages into patient records
+pub fn convert_hl7_to_records(mut file: File) -> Result<Vec<PatientRecord>, AktenError> {
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+    let contents = decode_text(bytes);
+
+    let (records, report) = parse_messages(&contents);
+    for issue in &report.issues {
+        warn!("HL7 {}", issue);
+    }
+    for issue in &report.incomplete {
+        warn!("HL7 record incomplete: {}", issue);
+    }
+    info!(
+        "HL7 import: {} messages, {} observations mapped into {} records ({} issues, {} incomplete)",
+        report.messages,
+        report.observations_mapped,
+        records.len(),
+        report.issues.len(),
+        report.incomplete.len()
+    );
+    Ok(records)
+}
+
+/// Parse ORU^R01 messages and group their OBX results by patient and date
+pub fn parse_messages(contents: &str) -> (Vec<PatientRecord>, Hl7ImportReport) {
+    let mut report = Hl7ImportReport::default();
+    let mut grouped: BTreeMap<(String, Timestamp), PartialRecord> = BTreeMap::new();
+    let mut identifiers: BTreeMap<String, Identifiers> = BTreeMap::new();
+
+    for (index, text) in split_messages(contents).iter().enumerate() {
+        report.messages += 1;
+        let number = index + 1;
+        let message = match Message::parse(text) {
+            Ok(message) => message,
+            Err(e) => {
+                report.issues.push(format!("message {}: {}", number, e));
+                continue;
+            }
+        };
+        let label = format!("message {} ({})", number, message.control_id());
+        let message_type = message.message_type();
+        if message_type != "ORU^R01" {
+            report.issues.push(format!("{}: unsupported message type '{}', skipped", label, message_type));
+            continue;
+        }
+        map_message(&message, &label, &mut grouped, &mut identifiers, &mut report);
+    }
+
+    let mut records = fhir::complete_records(grouped, &mut report.incomplete);
+    for record in &mut records {
+        if let Some(known) = identifiers.get(&record.patient_id) {
+            record.identifiers = known.clone();
+        }
+    }
+    (records, report)
+}
+
+fn map_message(
+    message: &Message,
+    label: &str,
+    grouped: &mut BTreeMap<(String, Timestamp), PartialRecord>,
+    identifiers: &mut BTreeMap<String, Identifiers>,
+    report: &mut Hl7ImportReport,
+) {
+    let mut patient_id = None;
+    let mut request_time = None;
+
+    for (index, segment) in message.segments.iter().enumerate() {
+        let location = format!("{} segment {} ({})", label, index + 1, segment.name);
+        match segment.name.as_str() {
+            "PID" => {
+                patient_id = match patient_identity(message, segment) {
+                    Some((id, known)) => {
+                        let entry = identifiers.entry(id.clone()).or_default();
+                        for identifier in known.iter() {
+                            entry.insert(identifier.clone());
+                        }
+                        Some(id)
+                    }
+                    None => {
+                        report.issues.push(format!("{}: PID-3 has no patient identifier", location));
+                        None
+                    }
+                };
+            }
+            // OBR-7 observation date/time applies to all following OBX without their own
+            "OBR" => request_time = hl7_time(segment.field(7)),
+            "OBX" => {
+                let Some(patient_id) = patient_id.clone() else {
+                    report.issues.push(format!("{}: no patient for this result", location));
+                    continue;
+                };
+                let Some(timestamp) = hl7_time(segment.field(14)).or(request_time) else {
+                    report.issues.push(format!("{}: neither OBX-14 nor OBR-7 has a date/time", location));
+                    continue;
+                };
+                let partial = grouped.entry((patient_id, timestamp)).or_default();
+                map_observation(message, segment, &location, partial, report);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn map_observation(
+    message: &Message,
+    segment: &Segment,
+    location: &str,
+    partial: &mut PartialRecord,
+    report: &mut Hl7ImportReport,
+) {
+    // OBX-11: D(eleted), W(rong) and X (cannot be obtained) carry no usable value
+    let status = segment.field(11);
+    if matches!(status, "D" | "W" | "X") {
+        report.issues.push(format!("{}: result status {}, skipped", location, status));
+        return;
+    }
+
+    let identifier = message.components(segment.field(3));
+    let value_components = message.repetitions(segment.field(5)).into_iter().next().unwrap_or_default();
+    // SN (structured numeric) is `comparator^num1^separator^num2`, e.g. "^120^/^80"
+    let raw_value = match (segment.field(2), value_components.as_slice()) {
+        ("SN", [_, first, separator, second, ..]) if !separator.is_empty() => format!("{}/{}", first, second),
+        ("SN", [_, first, ..]) => first.clone(),
+        (_, components) => components.join(&message.delimiters.component.to_string()),
+    };
+    let unit = message.components(segment.field(6)).into_iter().next().unwrap_or_default();
+
+    // A combined "120/80" blood pressure result fills both fields
+    let values: Vec<(VitalField, &str)> = if identifier.first().map(String::as_str) == Some(LOINC_BP_PANEL) {
+        match raw_value.split_once('/') {
+            Some((systolic, diastolic)) => vec![(VitalField::BpSystolic, systolic), (VitalField::BpDiastolic, diastolic)],
+            None => {
+                report.issues.push(format!("{}: blood pressure '{}' is not systolic/diastolic", location, raw_value));
+                return;
+            }
+        }
+    } else {
+        match vital_field(&identifier) {
+            Some(field) => vec![(field, raw_value.as_str())],
+            None => {
+                report.issues.push(format!(
+                    "{}: observation identifier '{}' is not mapped",
+                    location,
+                    identifier.join("^")
+                ));
+                return;
+            }
+        }
+    };
+
+    for (field, raw) in values {
+        let Ok(value) = raw.trim().parse::<f64>() else {
+            report.issues.push(format!("{}: {:?} value '{}' is not numeric", location, field, raw));
+            continue;
+        };
+        let value = match units::convert(field, value, &unit) {
+            Ok(value) => value,
+            Err(issue) => {
+                report.issues.push(format!("{}: {}", location, issue));
+                continue;
+            }
+        };
+        let slot = field.slot(partial);
+        if slot.is_some() {
+            report.issues.push(format!("{}: duplicate {:?} value ignored", location, field));
+        } else {
+            *slot = Some(value);
+            report.observations_mapped += 1;
+        }
+    }
+}
+
+/// How exported records are grouped into ORU^R01 messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessageGrouping {
+    /// One message per record
+    #[default]
+    PerRecord,
+    /// One message per patient with an OBR group per record
+    PerPatient,
+}
+
+impl MessageGrouping {
+    pub fn parse(value: &str) -> Result<Self, AktenError> {
+        match value.to_lowercase().as_str() {
+            "record" => Ok(Self::PerRecord),
+            "patient" => Ok(Self::PerPatient),
+            other => Err(AktenError::Hl7(format!(
+                "unsupported message grouping '{}' (expected record or patient)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Fields written as OBX results, in message order. AVPU and the oxygen flag
+/// are coded values and travel through FHIR only
+const EXPORTED_FIELDS: [VitalField; 11] = [
+    VitalField::HeartRate,
+    VitalField::BpSystolic,
+    VitalField::BpDiastolic,
+    VitalField::Temperature,
+    VitalField::BloodSugar,
+    VitalField::Steps,
+    VitalField::Spo2,
+    VitalField::RespiratoryRate,
+    VitalField::Weight,
+    VitalField::Height,
+    VitalField::Bmi,
+];
+
+/// Build HL7 v2.5 ORU^R01 messages; with `thresholds`, OBX-8 carries the
+/// abnormal flags and OBX-7 the reference range used by `validate`. PID-3
+/// lists the patient id under its system, then the other identifiers
+pub fn records_to_messages(
+    records: &[PatientRecord],
+    grouping: MessageGrouping,
+    thresholds: Option<&Thresholds>,
+    primary_system: &str,
+) -> Vec<String> {
+    let groups: Vec<Vec<&PatientRecord>> = match grouping {
+        MessageGrouping::PerRecord => records.iter().map(|record| vec![record]).collect(),
+        MessageGrouping::PerPatient => {
+            let mut by_patient: BTreeMap<&str, Vec<&PatientRecord>> = BTreeMap::new();
+            for record in records {
+                by_patient.entry(record.patient_id.as_str()).or_default().push(record);
+            }
+            by_patient.into_values().collect()
+        }
+    };
+
+    let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    groups
+        .iter()
+        .enumerate()
+        .map(|(index, group)| {
+            let control_id = format!("{}{:05}", timestamp, index + 1);
+            oru_message(group, &control_id, &timestamp, thresholds, primary_system)
+        })
+        .collect()
+}
+
+fn oru_message(
+    records: &[&PatientRecord],
+    control_id: &str,
+    timestamp: &str,
+    thresholds: Option<&Thresholds>,
+    primary_system: &str,
+) -> String {
+    let delimiters = Delimiters::default();
+    let mut segments = vec![
+        format!(
+            "MSH|{}|AKTENAKROBAT||||{}||ORU^R01^ORU_R01|{}|P|2.5||||||UNICODE UTF-8",
+            delimiters.encoding_characters(),
+            timestamp,
+            control_id
+        ),
+        format!("PID|1||{}", patient_identifier_list(records[0], primary_system, &delimiters)),
+    ];
+
+    for (index, record) in records.iter().enumerate() {
+        let date = hl7_timestamp(&record.timestamp);
+        segments.push(format!(
+            "OBR|{}|||85353-1^Vital signs panel^LN|||{}||||||||||||||||||F",
+            index + 1,
+            date
+        ));
+        // Unrecorded values get no OBX; set ids stay consecutive
+        let recorded = EXPORTED_FIELDS
+            .iter()
+            .filter_map(|field| field_value(record, *field).map(|value| (field, value)));
+        for (set_id, (field, value)) in recorded.enumerate() {
+            let coding = fhir::vital_coding(*field);
+            let (range, flag) = thresholds
+                .map(|t| (reference_range(*field, t), abnormal_flag(record, *field, t)))
+                .unwrap_or_default();
+            segments.push(format!(
+                "OBX|{}|NM|{}^{}^LN||{}|{}^{}^UCUM|{}|{}|||F|||{}",
+                set_id + 1,
+                coding.loinc,
+                delimiters.escape(coding.display),
+                value,
+                delimiters.escape(coding.ucum),
+                delimiters.escape(coding.unit),
+                range,
+                flag,
+                date
+            ));
+        }
+    }
+
+    segments.join("\r") + "\r"
+}
+
+fn field_value(record: &PatientRecord, field: VitalField) -> Option<String> {
+    // f32 fields print their shortest form; widening to f64 would add digits
+    match field {
+        VitalField::Temperature => record.temperature.map(|v| v.to_string()),
+        VitalField::BloodSugar => record.blood_sugar.map(|v| v.to_string()),
+        VitalField::Weight => record.weight.map(|v| v.to_string()),
+        VitalField::Height => record.height.map(|v| v.to_string()),
+        _ => record.measurement(field).map(|v| v.to_string()),
+    }
+}
+
+/// OBX-8 abnormal flag. Values `validate` reports as critical get HH/LL,
+/// values it reports as warnings get H/L.
+fn abnormal_flag(record: &PatientRecord, field: VitalField, thresholds: &Thresholds) -> &'static str {
+    match field {
+        VitalField::HeartRate if record.heart_rate.is_some_and(|v| v > thresholds.heart_rate.max) => "HH",
+        VitalField::HeartRate if record.heart_rate.is_some_and(|v| v < thresholds.heart_rate.min) => "LL",
+        VitalField::BpSystolic if record.bp_systolic.is_some_and(|v| v >= thresholds.blood_pressure.systolic) => "HH",
+        VitalField::BpSystolic if record.bp_systolic.is_some_and(|v| v >= STAGE_HYPERTENSION_SYSTOLIC) => "H",
+        VitalField::BpDiastolic if record.bp_diastolic.is_some_and(|v| v >= thresholds.blood_pressure.diastolic) => "HH",
+        VitalField::BpDiastolic if record.bp_diastolic.is_some_and(|v| v >= STAGE_HYPERTENSION_DIASTOLIC) => "H",
+        VitalField::Temperature if record.temperature.is_some_and(|v| v > thresholds.fever) => "HH",
+        VitalField::Temperature if record.temperature.is_some_and(|v| v < thresholds.hypothermia) => "LL",
+        VitalField::BloodSugar if record.blood_sugar.is_some_and(|v| v > thresholds.hyperglycemia) => "HH",
+        VitalField::BloodSugar if record.blood_sugar.is_some_and(|v| v < thresholds.hypoglycemia) => "L",
+        VitalField::Spo2 if record.spo2.is_some_and(|v| v < thresholds.hypoxemia) => "LL",
+        VitalField::RespiratoryRate if record.respiratory_rate.is_some_and(|v| v > thresholds.respiratory_rate.max) => "HH",
+        VitalField::RespiratoryRate if record.respiratory_rate.is_some_and(|v| v < thresholds.respiratory_rate.min) => "LL",
+        VitalField::Bmi if record.bmi().is_some_and(|v| v >= f64::from(thresholds.obesity_bmi)) => "H",
+        VitalField::Bmi if record.bmi().is_some_and(|v| v < f64::from(thresholds.underweight_bmi)) => "L",
+        _ => "",
+    }
+}
+
+/// OBX-7 reference range matching the flag thresholds
+fn reference_range(field: VitalField, thresholds: &Thresholds) -> String {
+    match field {
+        VitalField::HeartRate => format!("{}-{}", thresholds.heart_rate.min, thresholds.heart_rate.max),
+        VitalField::BpSystolic => format!("<{}", STAGE_HYPERTENSION_SYSTOLIC),
+        VitalField::BpDiastolic => format!("<{}", STAGE_HYPERTENSION_DIASTOLIC),
+        VitalField::Temperature => format!("{}-{}", thresholds.hypothermia, thresholds.fever),
+        VitalField::BloodSugar => format!("{}-{}", thresholds.hypoglycemia, thresholds.hyperglycemia),
+        VitalField::Spo2 => format!("{}-100", thresholds.hypoxemia),
+        VitalField::RespiratoryRate => format!("{}-{}", thresholds.respiratory_rate.min, thresholds.respiratory_rate.max),
+        VitalField::Bmi => format!("{}-{}", thresholds.underweight_bmi, thresholds.obesity_bmi),
+        VitalField::Steps | VitalField::Weight | VitalField::Height => String::new(),
+    }
+}
+
+/// DTM `YYYYMMDD`, or `YYYYMMDDHHMMSS+ZZZZ` for measurements with a time
+fn hl7_timestamp(timestamp: &Timestamp) -> String {
+    if timestamp.is_date_only() {
+        timestamp.date().format("%Y%m%d").to_string()
+    } else {
+        timestamp.instant().format("%Y%m%d%H%M%S%z").to_string()
+    }
+}
+
+/// PID-3 repetitions `id^^^&system&URI^MR` (`&oid&ISO` for OIDs), the
+/// primary identifier first
+fn patient_identifier_list(record: &PatientRecord, primary_system: &str, delimiters: &Delimiters) -> String {
+    let primary = identity::patient_identifier(record, primary_system);
+    let others = record.identifiers.iter().filter(|i| **i != primary);
+    let repetitions: Vec<String> = std::iter::once(&primary)
+        .chain(others)
+        .map(|identifier| {
+            let (universal_id, id_type) = match identifier.system.strip_prefix("urn:oid:") {
+                Some(oid) => (oid, "ISO"),
+                None => (identifier.system.as_str(), "URI"),
+            };
+            let sub = delimiters.subcomponent;
+            format!(
+                "{}^^^{}{}{}{}^MR",
+                delimiters.escape(&identifier.value),
+                sub,
+                delimiters.escape(universal_id),
+                sub,
+                id_type
+            )
+        })
+        .collect();
+    repetitions.join(&delimiters.repetition.to_string())
+}
+
+/// Error location (`OBX^<occurrence>^5`) of the result that supplied `field`
+/// for the given patient and measurement time
+pub fn result_location(message: &Message, patient_id: &str, timestamp: &Timestamp, field: VitalField) -> Option<String> {
+    let mut current_patient = None;
+    let mut request_time = None;
+    let mut occurrence = 0;
+    for segment in &message.segments {
+        match segment.name.as_str() {
+            "PID" => current_patient = patient_identity(message, segment).map(|(id, _)| id),
+            "OBR" => request_time = hl7_time(segment.field(7)),
+            "OBX" => {
+                occurrence += 1;
+                let obx_time = hl7_time(segment.field(14)).or(request_time);
+                if current_patient.as_deref() != Some(patient_id) || obx_time.as_ref() != Some(timestamp) {
+                    continue;
+                }
+                let identifier = message.components(segment.field(3));
+                let is_panel = identifier.first().map(String::as_str) == Some(LOINC_BP_PANEL);
+                let supplies = vital_field(&identifier) == Some(field)
+                    || (is_panel && matches!(field, VitalField::BpSystolic | VitalField::BpDiastolic));
+                if supplies {
+                    return Some(format!("OBX^{}^5", occurrence));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// OBX-3 is `code^text^system^alt code^alt text^alt system`; both codings are tried
+fn vital_field(identifier: &[String]) -> Option<VitalField> {
+    [0, 3].iter().find_map(|&offset| {
+        let code = identifier.get(offset)?;
+        let text = identifier.get(offset + 1).map(String::as_str).unwrap_or_default();
+        VitalField::from_loinc(code).or_else(|| {
+            MDC_CODES
+                .iter()
+                .find(|(mdc, reference_id, _)| code == mdc || text == *reference_id || code == reference_id)
+                .map(|(_, _, field)| *field)
+        })
+    })
+}
+
+/// Patient id and identifiers from PID-3 (falling back to the deprecated
+/// PID-2): the first CX.1 is the patient id, and every repetition with an
+/// assigning authority (CX.4) is kept as an identifier of that system
+fn patient_identity(message: &Message, pid: &Segment) -> Option<(String, Identifiers)> {
+    let delimiters = &message.delimiters;
+    [pid.field(3), pid.field(2)].iter().find_map(|raw| {
+        let mut patient_id = None;
+        let mut identifiers = Identifiers::default();
+        for repetition in raw.split(delimiters.repetition) {
+            let components: Vec<&str> = repetition.split(delimiters.component).collect();
+            let value = message.components(components[0]).remove(0);
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            patient_id.get_or_insert_with(|| value.to_string());
+            if let Some(system) = components.get(3).and_then(|hd| authority_system(hd, delimiters)) {
+                identifiers.insert(Identifier::new(&system, value));
+            }
+        }
+        Some((patient_id?, identifiers))
+    })
+}
+
+/// System of an HD assigning authority: the universal id (`urn:oid:` for ISO
+/// OIDs) or, without one, the namespace id
+fn authority_system(hd: &str, delimiters: &Delimiters) -> Option<String> {
+    let parts: Vec<String> = hd.split(delimiters.subcomponent).map(|p| delimiters.unescape(p)).collect();
+    let part = |index: usize| parts.get(index).map(|p| p.trim()).filter(|p| !p.is_empty());
+    match (part(0), part(1), part(2)) {
+        (_, Some(oid), Some("ISO")) => Some(format!("urn:oid:{}", oid)),
+        (_, Some(universal_id), _) => Some(universal_id.to_string()),
+        (Some(namespace), _, _) => Some(namespace.to_string()),
+        _ => None,
+    }
+}
+
+/// DTM `YYYYMMDD[HH[MM[SS[.S]]]][+/-ZZZZ]`; a time without an offset is in
+/// the configured time zone, a bare date stays date-only
+fn hl7_time(raw: &str) -> Option<Timestamp> {
+    let raw = raw.trim();
+    let (local, offset) = match raw.get(8..).and_then(|rest| rest.find(['+', '-'])) {
+        Some(position) => raw.split_at(8 + position),
+        None => (raw, ""),
+    };
+    let digits = local.split('.').next()?;
+    if digits.len() < 8 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let date = NaiveDate::parse_from_str(&digits[..8], "%Y%m%d").ok()?;
+    if digits.len() == 8 {
+        return Some(Timestamp::from_date(date));
+    }
+    let time = format!("{:0<6}", &digits[8..]);
+    let local = date.and_time(NaiveTime::parse_from_str(time.get(..6)?, "%H%M%S").ok()?);
+    if offset.is_empty() {
+        return Timestamp::from_local(local);
+    }
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let hours: i32 = offset.get(1..3)?.parse().ok()?;
+    let minutes: i32 = offset.get(3..5)?.parse().ok()?;
+    let offset = FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))?;
+    Some(Timestamp::from(offset.from_local_datetime(&local).single()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORU: &str = "MSH|^~\\&|MONITOR|ICU|AKTEN|KLINIK|20241201083000||ORU^R01|MSG0001|P|2.5\r\
+PID|1||4711^^^KLINIK^MR~A123456789^^^GKV^KV||Muster\\S\\Test^Max\r\
+OBR|1|||85353-1^Vital signs panel^LN|||20241201083000\r\
+OBX|1|NM|8867-4^Heart rate^LN||78|/min|||||F\r\
+OBX|2|NM|150021^MDC_PRESS_BLD_NONINV_SYS^MDC||120|mm[Hg]|||||F\r\
+OBX|3|NM|150022^MDC_PRESS_BLD_NONINV_DIA^MDC||80|mm[Hg]|||||F\r\
+OBX|4|NM|8310-5^Body temperature^LN||97.9|[degF]|||||F\r\
+OBX|5|NM|2339-0^Glucose^LN||92|mg/dL|||||F|||20241201083000+0100\r\
+OBX|6|NM|2160-0^Creatinine^LN||0.9|mg/dL|||||F\r";
+
+    #[test]
+    fn test_structured_numeric_blood_pressure() {
+        let contents = "MSH|^~\\&|MON|ICU|||20241201||ORU^R01|BP1|P|2.5\rPID|1||5\rOBR|1||||||20241201\r\
+                        OBX|1|SN|85354-9^BP panel^LN||^145^/^95|mm[Hg]|||||F\r";
+        let mut grouped = BTreeMap::new();
+        let mut identifiers = BTreeMap::new();
+        let mut report = Hl7ImportReport::default();
+        let message = Message::parse(contents).unwrap();
+        map_message(&message, "message 1", &mut grouped, &mut identifiers, &mut report);
+
+        let partial = grouped.get_mut(&("5".to_string(), Timestamp::parse("2024-12-01").unwrap())).unwrap();
+        assert_eq!(*VitalField::BpSystolic.slot(partial), Some(145.0));
+        assert_eq!(*VitalField::BpDiastolic.slot(partial), Some(95.0));
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn test_oru_maps_obx_results() {
+        let (records, report) = parse_messages(ORU);
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.patient_id, "4711");
+        assert_eq!(record.timestamp.to_string(), "2024-12-01T08:30:00+01:00");
+        assert_eq!((record.heart_rate, record.bp_systolic, record.bp_diastolic), (Some(78), Some(120), Some(80)));
+        assert!((record.temperature.unwrap() - 36.6).abs() < 0.05);
+        assert_eq!(report.observations_mapped, 5);
+        assert_eq!(report.issues.len(), 1);
+        assert!(report.issues[0].starts_with("message 1 (MSG0001) segment 9 (OBX)"), "{}", report.issues[0]);
+    }
+
+    #[test]
+    fn test_escape_sequences() {
+        let delimiters = Delimiters::default();
+        assert_eq!(delimiters.unescape(&delimiters.escape("120/80 | ^~&")), "120/80 | ^~&");
+        assert_eq!(delimiters.unescape("a\\F\\b\\S\\c\\E\\d\\X4D\\e\\.br\\f\\H\\g"), "a|b^c\\dMe\nfg");
+
+        let message = Message::parse(ORU).unwrap();
+        let pid = message.segment("PID").unwrap();
+        assert_eq!(message.repetitions(pid.field(3)).len(), 2);
+        assert_eq!(message.components(pid.field(5))[0], "Muster^Test");
+    }
+
+    #[test]
+    fn test_problems_are_reported_per_message() {
+        let contents = format!(
+            "{}MSH|^~\\&|LAB|X|||20241201||ADT^A01|MSG0002|P|2.5\rPID|1||12\r\
+             MSH|^~\\&|LAB|X|||20241202||ORU^R01|MSG0003|P|2.5\rPID|1||99\rOBX|1|NM|8867-4^HR^LN||fast|/min|||||F\r",
+            ORU
+        );
+        let (records, report) = parse_messages(&contents);
+        assert_eq!(report.messages, 3);
+        assert_eq!(records.len(), 1);
+        assert!(report.issues.iter().any(|i| i.contains("MSG0002") && i.contains("ADT^A01")));
+        assert!(report.issues.iter().any(|i| i.contains("MSG0003") && i.contains("date")));
+    }
+
+    fn thresholds() -> Thresholds {
+        Thresholds {
+            heart_rate: crate::config::CriticalHr { min: 50, max: 90 },
+            blood_pressure: crate::config::HypertensiveCrisis { systolic: 150, diastolic: 100 },
+            hypothermia: 35.0,
+            fever: 38.0,
+            hypoglycemia: 70.0,
+            hyperglycemia: 400.0,
+            hypoxemia: 92,
+            respiratory_rate: crate::config::CriticalRr::default(),
+            underweight_bmi: 18.5,
+            obesity_bmi: 30.0,
+        }
+    }
+
+    #[test]
+    fn test_exported_messages_round_trip_with_flags() {
+        let (records, _) = parse_messages(ORU);
+        let mut fever = records[0].clone();
+        fever.patient_id = "4712".to_string();
+        fever.temperature = Some(39.2);
+        fever.bp_systolic = Some(145);
+        let records = vec![records[0].clone(), fever];
+
+        let messages = records_to_messages(&records, MessageGrouping::PerRecord, Some(&thresholds()), identity::LOCAL_SYSTEM);
+        assert_eq!(messages.len(), 2);
+        let message = Message::parse(&messages[1]).unwrap();
+        assert_eq!(message.message_type(), "ORU^R01");
+        assert_eq!(message.segments[0].field(18), "UNICODE UTF-8", "MSH-18 character set");
+        let flags: Vec<&str> = message
+            .segments
+            .iter()
+            .filter(|s| s.name == "OBX")
+            .map(|s| s.field(8))
+            .collect();
+        assert_eq!(flags, vec!["", "H", "", "HH", ""]);
+
+        let (imported, report) = parse_messages(&messages.concat());
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[1].bp_systolic, Some(145));
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn test_per_patient_grouping() {
+        let (records, _) = parse_messages(ORU);
+        let mut later = records[0].clone();
+        later.timestamp = Timestamp::parse("2024-12-02T09:15:00+01:00").unwrap();
+        let messages = records_to_messages(&[records[0].clone(), later], MessageGrouping::PerPatient, None, identity::LOCAL_SYSTEM);
+
+        assert_eq!(messages.len(), 1);
+        let message = Message::parse(&messages[0]).unwrap();
+        assert_eq!(message.segments.iter().filter(|s| s.name == "OBR").count(), 2);
+        assert_eq!(parse_messages(&messages[0]).0.len(), 2);
+    }
+
+    #[test]
+    fn test_patient_identifiers_round_trip_through_pid3() {
+        let (records, _) = parse_messages(ORU);
+        assert_eq!(records[0].identifiers.get("KLINIK"), Some("4711"));
+        assert_eq!(records[0].identifiers.get("GKV"), Some("A123456789"));
+
+        let mut record = records[0].clone();
+        record.identifiers = [Identifier::new(crate::identity::KVNR_SYSTEM, "A123456789"), Identifier::new("urn:oid:1.2.3", "M-77")]
+            .into_iter()
+            .collect();
+        let messages = records_to_messages(&[record], MessageGrouping::PerRecord, None, identity::LOCAL_SYSTEM);
+        let message = Message::parse(&messages[0]).unwrap();
+        assert_eq!(
+            message.segment("PID").unwrap().field(3),
+            "4711^^^&urn:aktenakrobat:pid&URI^MR~A123456789^^^&http://fhir.de/sid/gkv/kvid-10&URI^MR~M-77^^^&1.2.3&ISO^MR"
+        );
+
+        let (imported, _) = parse_messages(&messages[0]);
+        assert_eq!(imported[0].patient_id, "4711");
+        assert_eq!(imported[0].identifiers.get(crate::identity::KVNR_SYSTEM), Some("A123456789"));
+        assert_eq!(imported[0].identifiers.get("urn:oid:1.2.3"), Some("M-77"));
+    }
+
+    #[test]
+    fn test_result_times_separate_readings() {
+        let contents = "MSH|^~\\&|MON|ICU|||20241201||ORU^R01|T1|P|2.5\rPID|1||5\r\
+                        OBR|1||||||202412010830\rOBX|1|NM|8867-4^HR^LN||78|/min|||||F\r\
+                        OBR|2||||||20241201143000-0500\rOBX|1|NM|8867-4^HR^LN||96|/min|||||F\r";
+        let (records, report) = parse_messages(contents);
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+
+        let readings: Vec<String> = records.iter().map(|r| r.timestamp.to_string()).collect();
+        assert_eq!(readings, vec!["2024-12-01T08:30:00+01:00", "2024-12-01T14:30:00-05:00"]);
+        let message = Message::parse(&records_to_messages(&records[1..], MessageGrouping::PerRecord, None, identity::LOCAL_SYSTEM)[0]).unwrap();
+        assert_eq!(message.segment("OBR").unwrap().field(7), "20241201143000-0500");
+    }
+}
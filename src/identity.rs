@@ -0,0 +1,187 @@
+use crate::config::IdentityConfig;
+use crate::PatientRecord;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Namespace of AktenAkrobat's own patient numbers, the default primary system
+pub const LOCAL_SYSTEM: &str = "urn:aktenakrobat:pid";
+
+/// German statutory health insurance number (KVNR)
+pub const KVNR_SYSTEM: &str = "http://fhir.de/sid/gkv/kvid-10";
+
+/// A patient identifier: a value within a system (namespace URI or OID)
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Identifier {
+    pub system: String,
+    pub value: String,
+}
+
+impl Identifier {
+    pub fn new(system: &str, value: &str) -> Self {
+        Self {
+            system: system.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+/// Written in the FHIR token form `system|value`
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}|{}", self.system, self.value)
+    }
+}
+
+impl FromStr for Identifier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once('|') {
+            Some((system, value)) if !system.trim().is_empty() && !value.trim().is_empty() => {
+                Ok(Self::new(system.trim(), value.trim()))
+            }
+            _ => Err(format!("identifier '{}' is not system|value", s)),
+        }
+    }
+}
+
+/// Further identifiers of a patient; in CSV and JSON a single `;`-separated
+/// list such as `http://fhir.de/sid/gkv/kvid-10|A123456789;urn:oid:1.2.3|MRN-77`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Identifiers(Vec<Identifier>);
+
+impl TryFrom<String> for Identifiers {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let identifiers = value
+            .split(';')
+            .filter(|part| !part.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self(identifiers))
+    }
+}
+
+impl FromIterator<Identifier> for Identifiers {
+    fn from_iter<I: IntoIterator<Item = Identifier>>(iter: I) -> Self {
+        let mut identifiers = Self::default();
+        for identifier in iter {
+            identifiers.insert(identifier);
+        }
+        identifiers
+    }
+}
+
+impl From<Identifiers> for String {
+    fn from(identifiers: Identifiers) -> Self {
+        identifiers.to_string()
+    }
+}
+
+impl fmt::Display for Identifiers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(Identifier::to_string).collect();
+        f.write_str(&parts.join(";"))
+    }
+}
+
+impl Identifiers {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Identifier> {
+        self.0.iter()
+    }
+
+    /// Value of the identifier in `system`, if the patient has one
+    pub fn get(&self, system: &str) -> Option<&str> {
+        self.0.iter().find(|i| i.system == system).map(|i| i.value.as_str())
+    }
+
+    /// Add an identifier unless one of the same system is already known
+    pub fn insert(&mut self, identifier: Identifier) {
+        if self.get(&identifier.system).is_none() {
+            self.0.push(identifier);
+        }
+    }
+}
+
+/// Make the configured primary identifier each record's `patient_id`, so
+/// records are grouped by it. A record carrying the primary identifier among
+/// its `identifiers`, or sharing its `patient_id` with one that does, takes
+/// its value, and the id it had is kept under `LOCAL_SYSTEM`; other records
+/// keep their `patient_id`. Returns the number of records that were re-keyed
+pub fn apply_primary(records: &mut [PatientRecord], config: &IdentityConfig) -> usize {
+    let primary_system = config.primary_system();
+    let known: HashMap<String, String> = records
+        .iter()
+        .filter_map(|record| Some((record.patient_id.clone(), record.identifiers.get(primary_system)?.to_string())))
+        .collect();
+    let mut rekeyed = 0;
+    for record in records {
+        let Some(primary) = known.get(&record.patient_id).cloned() else {
+            continue;
+        };
+        record.identifiers.insert(Identifier::new(primary_system, &primary));
+        if primary == record.patient_id {
+            continue;
+        }
+        let previous = std::mem::replace(&mut record.patient_id, primary);
+        if !previous.is_empty() && primary_system != LOCAL_SYSTEM {
+            record.identifiers.insert(Identifier::new(LOCAL_SYSTEM, &previous));
+        }
+        rekeyed += 1;
+    }
+    rekeyed
+}
+
+/// The identifier `patient_id` is the value of: the primary one if the record
+/// carries it, otherwise AktenAkrobat's own patient number
+pub fn patient_identifier(record: &PatientRecord, primary_system: &str) -> Identifier {
+    match record.identifiers.get(primary_system) {
+        Some(value) if value == record.patient_id => Identifier::new(primary_system, value),
+        _ => Identifier::new(LOCAL_SYSTEM, &record.patient_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identifiers_round_trip_as_text() {
+        let identifiers = Identifiers::try_from(format!("{}|A123456789; urn:oid:1.2.3|MRN-77", KVNR_SYSTEM)).unwrap();
+        assert_eq!(identifiers.get(KVNR_SYSTEM), Some("A123456789"));
+        assert_eq!(identifiers.to_string(), format!("{}|A123456789;urn:oid:1.2.3|MRN-77", KVNR_SYSTEM));
+        assert!(Identifiers::try_from(String::new()).unwrap().is_empty());
+        assert!(Identifiers::try_from("A123456789".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_primary_identifier_becomes_patient_id() {
+        let mut records = [
+            PatientRecord {
+                patient_id: "17".to_string(),
+                identifiers: Identifiers(vec![Identifier::new(KVNR_SYSTEM, "A123456789")]),
+                ..Default::default()
+            },
+            PatientRecord { patient_id: "18".to_string(), ..Default::default() },
+            PatientRecord { patient_id: "17".to_string(), ..Default::default() },
+        ];
+        let config = IdentityConfig { primary_system: Some(KVNR_SYSTEM.to_string()) };
+        assert_eq!(apply_primary(&mut records, &config), 2);
+
+        assert_eq!(records[0].patient_id, "A123456789");
+        assert_eq!(records[0].identifiers.get(LOCAL_SYSTEM), Some("17"));
+        assert_eq!(patient_identifier(&records[0], KVNR_SYSTEM), Identifier::new(KVNR_SYSTEM, "A123456789"));
+        assert_eq!(records[1].patient_id, "18");
+        assert_eq!(patient_identifier(&records[1], KVNR_SYSTEM), Identifier::new(LOCAL_SYSTEM, "18"));
+        assert_eq!(records[2].patient_id, "A123456789");
+        assert_eq!(records[2].identifiers.get(LOCAL_SYSTEM), Some("17"));
+    }
+}
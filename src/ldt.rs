@@ -0,0 +1,429 @@
+use crate::fhir::VitalField;
+use crate::gdt::{self, Charset};
+use crate::units;
+use crate::{AktenError, PatientRecord};
+use chrono::NaiveDate;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Record types (8000) of an LDT 3 data package
+const RECORD_PACKAGE_HEADER: &str = "8220";
+const RECORD_PACKAGE_TRAILER: &str = "8221";
+const RESULT_RECORDS: [&str; 3] = ["8201", "8202", "8203"];
+
+/// Field identifiers used by the import
+const FIELD_RECORD_TYPE: &str = "8000";
+const FIELD_CHARSET: &str = "9106";
+const FIELD_PATIENT_NUMBER: &str = "3000";
+const FIELD_TEST_ID: &str = "8410";
+const FIELD_TEST_NAME: &str = "8411";
+const FIELD_RESULT: &str = "8420";
+const FIELD_UNIT: &str = "8421";
+const FIELD_LIMIT_INDICATOR: &str = "8422";
+const FIELD_REFERENCE_TEXT: &str = "8460";
+const FIELD_REFERENCE_LOW: &str = "8461";
+const FIELD_REFERENCE_HIGH: &str = "8462";
+/// Collection date (LDT 2) and timestamp date (LDT 3), then report and receipt dates
+const DATE_FIELDS: [&str; 4] = ["8432", "7278", "8302", "8301"];
+
+/// Lab test identifiers for glucose; LOINC codes are accepted too
+const GLUCOSE_TEST_IDS: [&str; 6] = ["GLU", "GLUC", "GLUK", "GLUCOSE", "BZ", "NBZ"];
+
+/// Reference range as reported by the lab
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReferenceRange {
+    pub low: Option<f64>,
+    pub high: Option<f64>,
+    /// Free-text range such as "70 - 99" when no bounds are given
+    pub text: String,
+}
+
+impl ReferenceRange {
+    pub fn is_empty(&self) -> bool {
+        self.low.is_none() && self.high.is_none() && self.text.is_empty()
+    }
+
+    /// As reported, or built from the bounds
+    pub fn describe(&self) -> String {
+        match (self.low, self.high) {
+            _ if !self.text.is_empty() => self.text.clone(),
+            (Some(low), Some(high)) => format!("{} - {}", low, high),
+            (Some(low), None) => format!("> {}", low),
+            (None, Some(high)) => format!("< {}", high),
+            (None, None) => String::new(),
+        }
+    }
+
+    pub fn contains(&self, value: f64) -> bool {
+        self.low.is_none_or(|low| value >= low) && self.high.is_none_or(|high| value <= high)
+    }
+}
+
+/// One lab result from an LDT report
+#[derive(Debug, Clone, Default)]
+pub struct LabResult {
+    pub patient_id: String,
+    /// Collection date, or the report date when that is missing
+    pub date: NaiveDate,
+    pub test_id: String,
+    pub name: String,
+    pub value: Option<f64>,
+    /// 8420 as written, also for non-numeric results such as "negativ"
+    pub raw_value: String,
+    pub unit: String,
+    pub reference: ReferenceRange,
+    /// 8422 limit indicator, e.g. "+", "++", "-", "--" or "H"/"L"
+    pub flag: Option<String>,
+}
+
+impl LabResult {
+    /// Record field this result fills, if any
+    pub fn vital_field(&self) -> Option<VitalField> {
+        let id = self.test_id.to_uppercase();
+        if GLUCOSE_TEST_IDS.contains(&id.as_str()) {
+            return Some(VitalField::BloodSugar);
+        }
+        VitalField::from_loinc(&self.test_id)
+    }
+
+    pub fn label(&self) -> &str {
+        if self.name.is_empty() { &self.test_id } else { &self.name }
+    }
+
+    /// Outside the lab's reference range, by flag or by value
+    pub fn is_abnormal(&self) -> bool {
+        match (&self.flag, self.value) {
+            (Some(flag), _) => !flag.is_empty() && flag != "N",
+            (None, Some(value)) => !self.reference.contains(value),
+            (None, None) => false,
+        }
+    }
+
+    /// Doubled indicators ("++", "--", "HH", "LL") mark critical values
+    pub fn is_critical(&self) -> bool {
+        self.flag
+            .as_deref()
+            .is_some_and(|flag| matches!(flag, "++" | "--" | "HH" | "LL" | "!"))
+    }
+}
+
+/// Outcome of an LDT import
+#[derive(Debug, Default)]
+pub struct LdtImportReport {
+    pub reports: usize,
+    pub issues: Vec<String>,
+}
+
+/// Read lab results from an LDT 3 (or LDT 2) file
+pub fn load_lab_results(path: &str) -> Result<Vec<LabResult>, AktenError> {
+    if !Path::new(path).exists() {
+        return Err(AktenError::InvalidPath(path.into()));
+    }
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let (results, report) = parse_ldt(&bytes)?;
+    for issue in &report.issues {
+        warn!("LDT {}", issue);
+    }
+    info!(
+        path,
+        "LDT import: {} lab results from {} reports ({} issues)",
+        results.len(),
+        report.reports,
+        report.issues.len()
+    );
+    Ok(results)
+}
+
+/// A result record (8201/8202/8203) while it is being read
+#[derive(Debug, Default)]
+struct Report {
+    line: usize,
+    patient: Option<String>,
+    /// Date with the position of its field in `DATE_FIELDS`
+    date: Option<(usize, NaiveDate)>,
+    tests: Vec<(usize, LabResult)>,
+}
+
+/// Parse the result records of an LDT data package
+pub fn parse_ldt(bytes: &[u8]) -> Result<(Vec<LabResult>, LdtImportReport), AktenError> {
+    let lines = gdt::read_lines(bytes).map_err(|e| match e {
+        AktenError::Gdt(message) => AktenError::Ldt(message),
+        e => e,
+    })?;
+    // LDT 3 is always ISO 8859-15; LDT 2 may declare another set in 9106
+    let charset = lines
+        .iter()
+        .find(|l| l.field == FIELD_CHARSET)
+        .and_then(|l| Charset::from_field(&String::from_utf8_lossy(&l.content)))
+        .unwrap_or(Charset::Iso8859_15);
+
+    let mut report = LdtImportReport::default();
+    let mut results = vec![];
+    let mut current: Option<Report> = None;
+
+    for line in &lines {
+        let content = charset.decode(&line.content).trim().to_string();
+        if line.field == FIELD_RECORD_TYPE {
+            if let Some(finished) = current.take() {
+                finish_report(finished, &mut results, &mut report);
+            }
+            match content.as_str() {
+                RECORD_PACKAGE_HEADER | RECORD_PACKAGE_TRAILER => {}
+                record if RESULT_RECORDS.contains(&record) => {
+                    report.reports += 1;
+                    current = Some(Report {
+                        line: line.number,
+                        ..Default::default()
+                    });
+                }
+                other => report
+                    .issues
+                    .push(format!("line {}: record type {} is not a result report, skipped", line.number, other)),
+            }
+            continue;
+        }
+        let Some(current) = current.as_mut() else {
+            continue;
+        };
+
+        let field = line.field.as_str();
+        if field == FIELD_PATIENT_NUMBER {
+            current.patient = Some(content);
+        } else if DATE_FIELDS.contains(&field) {
+            let Some(date) = ldt_date(&content) else {
+                report.issues.push(format!("line {}: date '{}' is not JJJJMMTT or TTMMJJJJ", line.number, content));
+                continue;
+            };
+            // The collection date wins over report and receipt dates
+            let rank = DATE_FIELDS.iter().position(|d| *d == field).unwrap_or_default();
+            if current.date.as_ref().is_none_or(|(best, _)| rank < *best) {
+                current.date = Some((rank, date));
+            }
+        } else if field == FIELD_TEST_ID {
+            current.tests.push((
+                line.number,
+                LabResult {
+                    test_id: content,
+                    ..Default::default()
+                },
+            ));
+        } else if let Some((_, test)) = current.tests.last_mut() {
+            match field {
+                FIELD_TEST_NAME => test.name = content,
+                FIELD_RESULT => {
+                    test.value = content.replace(',', ".").parse().ok();
+                    test.raw_value = content;
+                }
+                FIELD_UNIT => test.unit = content,
+                FIELD_LIMIT_INDICATOR => test.flag = Some(content).filter(|f| !f.is_empty()),
+                FIELD_REFERENCE_TEXT => {
+                    test.reference.text = content;
+                    if let Some((low, high)) = parse_range(&test.reference.text) {
+                        test.reference.low = test.reference.low.or(low);
+                        test.reference.high = test.reference.high.or(high);
+                    }
+                }
+                FIELD_REFERENCE_LOW => test.reference.low = content.replace(',', ".").parse().ok(),
+                FIELD_REFERENCE_HIGH => test.reference.high = content.replace(',', ".").parse().ok(),
+                _ => {}
+            }
+        }
+    }
+    if let Some(finished) = current.take() {
+        finish_report(finished, &mut results, &mut report);
+    }
+    Ok((results, report))
+}
+
+fn finish_report(finished: Report, results: &mut Vec<LabResult>, report: &mut LdtImportReport) {
+    let label = format!("report at line {}", finished.line);
+    if finished.tests.is_empty() {
+        return;
+    }
+    let Some(patient_id) = finished.patient.as_deref().map(str::trim).filter(|p| !p.is_empty()) else {
+        report.issues.push(format!("{}: no patient number (3000)", label));
+        return;
+    };
+    let Some((_, date)) = finished.date else {
+        report.issues.push(format!("{}: no collection or report date", label));
+        return;
+    };
+
+    for (line, mut result) in finished.tests {
+        if result.raw_value.is_empty() {
+            report.issues.push(format!("{} test '{}' (line {}): no result (8420)", label, result.test_id, line));
+            continue;
+        }
+        result.patient_id = patient_id.to_string();
+        result.date = date;
+        results.push(result);
+    }
+}
+
+/// Free-text ranges such as "70 - 99", "3,9-5,5", "< 5" or "> 40"
+fn parse_range(text: &str) -> Option<(Option<f64>, Option<f64>)> {
+    let number = |s: &str| s.trim().replace(',', ".").parse::<f64>().ok();
+    let text = text.trim();
+    if let Some(high) = text.strip_prefix('<') {
+        return Some((None, Some(number(high.trim_start_matches('='))?)));
+    }
+    if let Some(low) = text.strip_prefix('>') {
+        return Some((Some(number(low.trim_start_matches('='))?), None));
+    }
+    // Split at the dash between the bounds, not at a leading minus sign
+    let (low, high) = text.get(1..)?.split_once('-').map(|(l, h)| (&text[..l.len() + 1], h))?;
+    Some((Some(number(low)?), Some(number(high)?)))
+}
+
+/// LDT 3 writes `JJJJMMTT`, LDT 2 `TTMMJJJJ`; either becomes `YYYY-MM-DD`
+fn ldt_date(raw: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(raw, "%Y%m%d")
+        .or_else(|_| NaiveDate::parse_from_str(raw, "%d%m%Y"))
+        .ok()
+}
+
+/// Outcome of joining lab results onto records
+#[derive(Debug, Default)]
+pub struct LabJoinReport {
+    pub joined: usize,
+    /// Results without a record of the same patient and date
+    pub unmatched: Vec<String>,
+}
+
+/// Copy lab values that map onto a record field into the first record of the
+/// same patient on that day, converted to the record's unit; lab results
+/// replace device or manual entries
+pub fn join_lab_results(records: &mut [PatientRecord], results: &[LabResult]) -> LabJoinReport {
+    let mut join = LabJoinReport::default();
+    for result in results {
+        let (Some(field), Some(value)) = (result.vital_field(), result.value) else {
+            continue;
+        };
+        let Some(record) = records
+            .iter_mut()
+            .find(|r| r.patient_id == result.patient_id && r.timestamp.date() == result.date)
+        else {
+            join.unmatched.push(format!(
+                "{} for patient {} ({})",
+                result.label(),
+                result.patient_id,
+                result.date
+            ));
+            continue;
+        };
+        match units::convert(field, value, &result.unit) {
+            Ok(value) => {
+                record.set_measurement(field, value);
+                join.joined += 1;
+            }
+            Err(issue) => warn!("Lab result {} for patient {} not joined: {}", result.label(), result.patient_id, issue),
+        }
+    }
+    for unmatched in &join.unmatched {
+        warn!("Lab result has no matching record: {}", unmatched);
+    }
+    info!("Joined {} lab results onto records", join.joined);
+    join
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhir::LOINC_GLUCOSE_MASS;
+    use crate::timestamp::Timestamp;
+
+    fn line(field: &str, content: &str) -> Vec<u8> {
+        let content: Vec<u8> = content.chars().map(|c| c as u8).collect();
+        let mut bytes = format!("{:03}{}", content.len() + 9, field).into_bytes();
+        bytes.extend_from_slice(&content);
+        bytes.extend_from_slice(b"\r\n");
+        bytes
+    }
+
+    fn ldt(fields: &[(&str, &str)]) -> Vec<u8> {
+        fields.iter().flat_map(|(field, content)| line(field, content)).collect()
+    }
+
+    const PACKAGE: [(&str, &str); 22] = [
+        ("8000", "8220"),
+        ("0001", "LDT3.2.17"),
+        ("8000", "8201"),
+        ("3000", "7"),
+        ("3101", "Müller"),
+        ("8302", "20241203"),
+        ("7278", "20241201"),
+        ("8410", LOINC_GLUCOSE_MASS),
+        ("8411", "Glucose nüchtern"),
+        ("8420", "182"),
+        ("8421", "mg/dl"),
+        ("8422", "+"),
+        ("8460", "70 - 99"),
+        ("8410", "HBA1C"),
+        ("8411", "HbA1c"),
+        ("8420", "6,9"),
+        ("8421", "%"),
+        ("8461", "4,0"),
+        ("8462", "6,0"),
+        ("8410", "CRP"),
+        ("8000", "8221"),
+        ("9300", "3"),
+    ];
+
+    #[test]
+    fn test_ldt_results_keep_units_and_ranges() {
+        let (results, report) = parse_ldt(&ldt(&PACKAGE)).unwrap();
+        assert_eq!(report.reports, 1);
+        assert_eq!(results.len(), 2);
+        assert_eq!(report.issues.len(), 1, "{:?}", report.issues);
+
+        let glucose = &results[0];
+        assert_eq!((glucose.patient_id.as_str(), glucose.date.to_string().as_str()), ("7", "2024-12-01"));
+        assert_eq!(glucose.name, "Glucose nüchtern");
+        assert_eq!(glucose.vital_field(), Some(VitalField::BloodSugar));
+        assert_eq!((glucose.value, glucose.unit.as_str()), (Some(182.0), "mg/dl"));
+        assert_eq!((glucose.reference.low, glucose.reference.high), (Some(70.0), Some(99.0)));
+        assert!(glucose.is_abnormal() && !glucose.is_critical());
+
+        let hba1c = &results[1];
+        assert_eq!(hba1c.vital_field(), None);
+        assert_eq!(hba1c.reference, ReferenceRange { low: Some(4.0), high: Some(6.0), text: String::new() });
+        assert!(hba1c.is_abnormal());
+    }
+
+    #[test]
+    fn test_lab_values_join_onto_records() {
+        let (results, _) = parse_ldt(&ldt(&PACKAGE)).unwrap();
+        let mut records = vec![PatientRecord {
+            patient_id: "7".to_string(),
+            timestamp: Timestamp::parse("2024-12-01T09:15:00+01:00").unwrap(),
+            heart_rate: Some(70),
+            bp_systolic: Some(120),
+            bp_diastolic: Some(80),
+            temperature: Some(36.8),
+            blood_sugar: Some(0.0),
+            steps: Some(0),
+            ..Default::default()
+        }];
+        let join = join_lab_results(&mut records, &results);
+        assert_eq!(join.joined, 1);
+        assert_eq!(records[0].blood_sugar, Some(182.0));
+
+        records[0].timestamp = Timestamp::parse("2024-12-02").unwrap();
+        assert_eq!(join_lab_results(&mut records, &results).unmatched.len(), 1);
+    }
+
+    #[test]
+    fn test_reference_ranges_and_dates() {
+        assert_eq!(parse_range("3,9-5,5"), Some((Some(3.9), Some(5.5))));
+        assert_eq!(parse_range("< 5"), Some((None, Some(5.0))));
+        assert_eq!(parse_range("-1 - 1"), Some((Some(-1.0), Some(1.0))));
+        assert_eq!(parse_range("negativ"), None);
+        let december = NaiveDate::from_ymd_opt(2024, 12, 1);
+        assert_eq!(ldt_date("20241201"), december);
+        assert_eq!(ldt_date("01122024"), december);
+    }
+}
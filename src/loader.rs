@@ -0,0 +1,117 @@
+use crate::config::IdentityConfig;
+use crate::demographics::{self, Demographics};
+use crate::fhir::{self, VitalField};
+use crate::timestamp::TimeRange;
+use crate::{csv_io, gdt, hl7, identity, provenance, store, units};
+use crate::{AktenError, PatientRecord};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Extensions of the record files `read_records` accepts; stores and
+/// FHIR Bulk Data directories are read as well
+pub const RECORD_EXTENSIONS: [&str; 5] = [".csv", ".json", ".fhir", ".hl7", ".gdt"];
+
+/// How records are prepared once read
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    pub identity: IdentityConfig,
+    pub range: TimeRange,
+    /// Birth date and sex to join onto the records; none joins nothing
+    pub demographics: Vec<Demographics>,
+    /// Keep each record's provenance rather than dropping it
+    pub with_provenance: bool,
+}
+
+/// A value left out of a record because its unit could not be told
+#[derive(Debug, Clone)]
+pub struct UnitIssue {
+    pub record: PatientRecord,
+    /// Position of the record among the prepared records
+    pub index: usize,
+    pub field: VitalField,
+    pub issue: String,
+}
+
+/// Check that `path` exists and is a record file, store or directory
+pub fn validate_path(path: &str) -> Result<(), AktenError> {
+    if !Path::new(path).exists() {
+        return Err(AktenError::InvalidPath(path.into()));
+    }
+    if Path::new(path).is_dir() || store::is_store(path) {
+        return Ok(());
+    }
+    if !RECORD_EXTENSIONS.iter().any(|ext| path.ends_with(ext)) {
+        return Err(AktenError::UnsupportedFormat);
+    }
+    Ok(())
+}
+
+/// Records of `path`, each with the provenance it was stored with or else this source
+pub fn read_records(path: &str) -> Result<Vec<PatientRecord>, AktenError> {
+    validate_path(path)?;
+    info!(path, "Loading records");
+
+    let (mut records, format) = if Path::new(path).is_dir() {
+        (fhir::load_bulk_data(Path::new(path))?, "ndjson")
+    } else if store::is_store(path) {
+        (store::load_records(path)?, "sqlite")
+    } else {
+        let file = std::fs::File::open(path)?;
+        match path.rsplit('.').next() {
+            Some("json") => {
+                let mut records: Vec<PatientRecord> = serde_json::from_reader(file)?;
+                provenance::stamp_numbered(&mut records, path, "json", "record");
+                (records, "json")
+            }
+            Some("fhir") => (fhir::convert_fhir_to_records(file)?, "fhir"),
+            Some("hl7") => (hl7::convert_hl7_to_records(file)?, "hl7"),
+            Some("gdt") => (gdt::convert_gdt_to_records(file)?, "gdt"),
+            Some("csv") => (csv_io::read_records(file, path)?, "csv"),
+            _ => return Err(AktenError::UnsupportedFormat),
+        }
+    };
+    provenance::stamp(&mut records, path, format);
+    Ok(records)
+}
+
+/// Key `records` by the primary identifier, join demographics, keep those
+/// within the time range in chronological order and convert temperatures
+/// to °C and blood sugar to mg/dL. Values whose unit cannot be told are
+/// removed and returned; `source` names the input in messages
+pub fn prepare(records: &mut Vec<PatientRecord>, options: &LoadOptions, source: &str) -> Vec<UnitIssue> {
+    if !options.with_provenance {
+        provenance::strip(records);
+    }
+    let rekeyed = identity::apply_primary(records, &options.identity);
+    if rekeyed > 0 {
+        info!(source, "{} records keyed by their {} identifier", rekeyed, options.identity.primary_system());
+    }
+    if !options.demographics.is_empty() {
+        demographics::join_demographics(records, &options.demographics, options.identity.primary_system());
+    }
+    let dropped = options.range.apply(records);
+    if dropped > 0 {
+        info!(source, "{} records outside the selected time range skipped", dropped);
+    }
+    let mut issues = vec![];
+    for (index, record) in records.iter_mut().enumerate() {
+        for (field, issue) in units::normalize(record) {
+            issues.push(UnitIssue { record: record.clone(), index, field, issue });
+        }
+    }
+    issues
+}
+
+/// Load records from any supported source, prepared as `prepare` does;
+/// values whose unit cannot be told are dropped with a warning
+pub fn load_records(path: &str, options: &LoadOptions) -> Result<Vec<PatientRecord>, AktenError> {
+    let mut records = read_records(path)?;
+    warn_unit_issues(&prepare(&mut records, options, path), path);
+    Ok(records)
+}
+
+pub fn warn_unit_issues(issues: &[UnitIssue], source: &str) {
+    for UnitIssue { record, issue, .. } in issues {
+        warn!(source, "Patient {} ({}): {}; value ignored", record.patient_id, record.timestamp, issue);
+    }
+}
@@ -5,13 +5,39 @@ mod export;
 mod risk;
 mod config;
 mod fhir;
+mod profiles;
+mod hl7;
+mod gdt;
+mod ldt;
+mod openehr;
+mod store;
+mod omop;
+#[cfg(feature = "parquet")]
+mod columnar;
+mod mllp;
+mod identity;
+mod timestamp;
+mod units;
+mod demographics;
+mod csv_io;
+mod provenance;
+mod observations;
+mod loader;
 
-use std::{path::Path, time::Instant};
+use std::time::{Duration, Instant};
+use chrono::NaiveDate;
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::{info, instrument, error};
-use crate::config::ThresholdConfig;
+use tracing::{info, instrument, warn};
+use crate::config::{ColumnMapping, IdentityConfig, ThresholdConfig};
+use crate::demographics::{Demographics, Sex};
+use crate::provenance::Provenance;
+use crate::observations::Observations;
+use crate::fhir::VitalField;
+use crate::identity::Identifiers;
+use crate::timestamp::{TimeRange, Timestamp};
+use crate::units::Unit;
 
 /// Custom error type for AktenAkrobat
 #[derive(Debug, Error)]
@@ -24,7 +50,7 @@ pub enum AktenError {
     Json(#[from] serde_json::Error),
     #[error("Invalid path: {0}")]
     InvalidPath(String),
-    #[error("Unsupported file format (must be .csv, .json, or .fhir)")]
+    #[error("Unsupported file format (must be .csv, .json, .fhir, .hl7, .gdt, a .db patient store or a FHIR Bulk Data directory)")]
     UnsupportedFormat,
     #[error("Config load error: {0}")]
     ConfigError(String),
@@ -32,19 +58,210 @@ pub enum AktenError {
     ValidationError(String),
     #[error("Risk analysis error: {0}")]
     RiskError(String),
+    #[error("FHIR error: {0}")]
+    Fhir(String),
+    #[error("HL7 v2 error: {0}")]
+    Hl7(String),
+    #[error("GDT error: {0}")]
+    Gdt(String),
+    #[error("LDT error: {0}")]
+    Ldt(String),
+    #[error("openEHR error: {0}")]
+    OpenEhr(String),
+    #[error("OMOP CDM error: {0}")]
+    Omop(String),
+    #[error("Parquet error: {0}")]
+    Parquet(String),
+    #[error("Patient store error: {0}")]
+    Store(String),
+    #[error("Timestamp error: {0}")]
+    Time(String),
+    #[error("Demographics error: {0}")]
+    Demographics(String),
+    #[error("CSV mapping error: {0}")]
+    CsvMapping(String),
+    #[error("CSV dialect error: {0}")]
+    CsvDialect(String),
 }
 
-/// Patient health record structure
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Patient health record structure; measurements that were not taken are
+/// `None` (an empty CSV cell, a JSON `null` or an absent key)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PatientRecord {
-    pub patient_id: u32,
-    pub date: String,
-    pub heart_rate: u32,
-    pub bp_systolic: u32,
-    pub bp_diastolic: u32,
-    pub temperature: f32,
-    pub blood_sugar: f32,
-    pub steps: u32,
+    /// Value of the primary identifier (`[identity] primary_system`), by which
+    /// records are grouped; numeric ids, KVNRs, MRNs and UUIDs alike
+    pub patient_id: String,
+    /// Further identifiers of the patient as `system|value` pairs
+    #[serde(default)]
+    pub identifiers: Identifiers,
+    /// From the record itself or joined from a demographics file
+    #[serde(default)]
+    pub birth_date: Option<NaiveDate>,
+    #[serde(default)]
+    pub sex: Option<Sex>,
+    /// When the measurements were taken; read from a `date` column as well
+    #[serde(alias = "date")]
+    pub timestamp: Timestamp,
+    #[serde(default)]
+    pub heart_rate: Option<u32>,
+    #[serde(default)]
+    pub bp_systolic: Option<u32>,
+    #[serde(default)]
+    pub bp_diastolic: Option<u32>,
+    /// Body temperature (°C once loaded)
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Unit `temperature` was given in; without one it is told by the value
+    #[serde(default)]
+    pub temperature_unit: Option<Unit>,
+    /// Blood glucose (mg/dL once loaded)
+    #[serde(default)]
+    pub blood_sugar: Option<f32>,
+    /// Unit `blood_sugar` was given in (mg/dL or mmol/L); without one it is
+    /// told by the value
+    #[serde(default)]
+    pub blood_sugar_unit: Option<Unit>,
+    #[serde(default)]
+    pub steps: Option<u32>,
+    /// Peripheral oxygen saturation (%)
+    #[serde(default)]
+    pub spo2: Option<u32>,
+    /// Breaths per minute
+    #[serde(default)]
+    pub respiratory_rate: Option<u32>,
+    /// Whether the patient was on supplemental oxygen when measured
+    #[serde(default)]
+    pub supplemental_oxygen: Option<bool>,
+    #[serde(default)]
+    pub consciousness: Option<Consciousness>,
+    /// Body weight (kg)
+    #[serde(default)]
+    pub weight: Option<f32>,
+    /// Body height (cm)
+    #[serde(default)]
+    pub height: Option<f32>,
+    /// Input the record was first read from; written only with `--provenance`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// Observations beyond the fixed vitals, from columns no field takes
+    #[serde(default, skip_serializing_if = "Observations::is_empty")]
+    pub observations: Observations,
+}
+
+/// Level of consciousness on the AVPU scale; written as its letter, read
+/// from the letter or the word in any case
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Consciousness {
+    #[serde(rename = "A")]
+    Alert,
+    #[serde(rename = "V")]
+    Voice,
+    #[serde(rename = "P")]
+    Pain,
+    #[serde(rename = "U")]
+    Unresponsive,
+}
+
+impl TryFrom<String> for Consciousness {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value).ok_or_else(|| format!("'{}' is not an AVPU level (A, V, P or U)", value))
+    }
+}
+
+impl Consciousness {
+    pub fn letter(&self) -> &'static str {
+        match self {
+            Self::Alert => "A",
+            Self::Voice => "V",
+            Self::Pain => "P",
+            Self::Unresponsive => "U",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "a" | "alert" => Some(Self::Alert),
+            "v" | "voice" => Some(Self::Voice),
+            "p" | "pain" => Some(Self::Pain),
+            "u" | "unresponsive" => Some(Self::Unresponsive),
+            _ => None,
+        }
+    }
+}
+
+impl PatientRecord {
+    /// Value of a measured field, `None` if it was not recorded
+    pub fn measurement(&self, field: VitalField) -> Option<f64> {
+        match field {
+            VitalField::HeartRate => self.heart_rate.map(f64::from),
+            VitalField::BpSystolic => self.bp_systolic.map(f64::from),
+            VitalField::BpDiastolic => self.bp_diastolic.map(f64::from),
+            VitalField::Temperature => self.temperature.map(f64::from),
+            VitalField::BloodSugar => self.blood_sugar.map(f64::from),
+            VitalField::Steps => self.steps.map(f64::from),
+            VitalField::Spo2 => self.spo2.map(f64::from),
+            VitalField::RespiratoryRate => self.respiratory_rate.map(f64::from),
+            VitalField::Weight => self.weight.map(f64::from),
+            VitalField::Height => self.height.map(f64::from),
+            VitalField::Bmi => self.bmi(),
+        }
+    }
+
+    /// Age in whole years when the measurements were taken
+    pub fn age(&self) -> Option<u32> {
+        self.timestamp.date().years_since(self.birth_date?)
+    }
+
+    /// Body mass index (kg/m²) from weight and height, to one decimal
+    pub fn bmi(&self) -> Option<f64> {
+        let weight = f64::from(self.weight?);
+        let height = f64::from(self.height?) / 100.0;
+        if height <= 0.0 {
+            return None;
+        }
+        Some((weight / (height * height) * 10.0).round() / 10.0)
+    }
+
+    /// Set a measured field from a value in its canonical unit (°C, mg/dL);
+    /// counts, pressures and saturation are rounded to whole numbers. BMI is
+    /// always derived, so setting it has no effect
+    pub fn set_measurement(&mut self, field: VitalField, value: f64) {
+        let whole = Some(value.round() as u32);
+        match field {
+            VitalField::HeartRate => self.heart_rate = whole,
+            VitalField::BpSystolic => self.bp_systolic = whole,
+            VitalField::BpDiastolic => self.bp_diastolic = whole,
+            VitalField::Temperature => {
+                self.temperature = Some(value as f32);
+                self.temperature_unit = Some(Unit::Celsius);
+            }
+            VitalField::BloodSugar => {
+                self.blood_sugar = Some(value as f32);
+                self.blood_sugar_unit = Some(Unit::MilligramsPerDeciliter);
+            }
+            VitalField::Steps => self.steps = whole,
+            VitalField::Spo2 => self.spo2 = whole,
+            VitalField::RespiratoryRate => self.respiratory_rate = whole,
+            VitalField::Weight => self.weight = Some(value as f32),
+            VitalField::Height => self.height = Some(value as f32),
+            VitalField::Bmi => {}
+        }
+    }
+}
+
+/// Display text of an optional measurement; missing values show as "n/a"
+pub fn display_value<T: std::fmt::Display>(value: Option<T>, precision: usize) -> String {
+    value.map_or_else(|| "n/a".to_string(), |v| format!("{:.*}", precision, v))
+}
+
+/// 64-bit FNV-1a hash, stable across runs and platforms for ids derived from record keys
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x100_0000_01b3))
 }
 
 /// CLI interface definition
@@ -66,6 +283,27 @@ struct Cli {
     #[arg(long)]
     config: Option<String>,
 
+    /// Only use measurements taken at or after this time (RFC 3339 or a configured format)
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Only use measurements taken at or before this time; a date includes the whole day
+    #[arg(long)]
+    until: Option<String>,
+
+    /// Birth dates and sexes to join onto the records (.csv, .json or a FHIR Bundle of Patients)
+    #[arg(long)]
+    demographics: Option<String>,
+
+    /// Column mapping for foreign CSV headers, replacing `[csv.mapping]` of the config file
+    #[arg(long)]
+    csv_mapping: Option<String>,
+
+    /// Carry each record's source, line, format, ingestion time and tool version
+    /// into CSV/JSON output, risk results, validation alerts and the patient store
+    #[arg(long)]
+    provenance: bool,
+
     /// Enable verbose diagnostics
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
@@ -77,6 +315,12 @@ enum Commands {
     Validate {
         #[arg(help = "Input file path")]
         path: String,
+        /// Write findings as a FHIR Bundle (OperationOutcome + DetectedIssue)
+        #[arg(long)]
+        fhir_output: Option<String>,
+        /// LDT lab results to join and check against the lab's reference ranges
+        #[arg(long)]
+        lab: Option<String>,
     },
     /// Generate summary statistics
     Summarize {
@@ -85,22 +329,46 @@ enum Commands {
     },
     /// Merge record files
     MergeFiles {
-        #[arg(help = "Output file path")]
+        #[arg(help = "Output file path (.db to add to a patient store)")]
         output: String,
         #[arg(help = "Input file paths")]
         inputs: Vec<String>,
     },
     /// Export records
     Export {
-        #[arg(help = "Output format (csv|json)")]
+        #[arg(help = "Output format (csv|json|fhir|ndjson|hl7|openehr|openehr-flat|omop|parquet)")]
         format: String,
-        #[arg(help = "Output file path")]
+        #[arg(help = "Output file path (directory for ndjson and omop)")]
         output: String,
+        /// FHIR bundle type (collection|transaction)
+        #[arg(long, default_value = "collection")]
+        bundle_type: String,
+        /// FHIR profile set (core|de for ISiK/KBV)
+        #[arg(long, default_value = "core")]
+        profile: String,
+        /// HL7 message grouping (record|patient)
+        #[arg(long, default_value = "record")]
+        hl7_grouping: String,
+        /// Records to export (file or patient store)
+        #[arg(long, default_value = "mock_data/merged_output.csv")]
+        input: String,
+        /// CSV dialect (standard|german) [default: `[csv] dialect` or standard]
+        #[arg(long)]
+        dialect: Option<String>,
+        /// CSV encoding (utf-8|windows-1252)
+        #[arg(long, default_value = "utf-8")]
+        encoding: String,
+        /// Start the CSV file with a UTF-8 byte order mark for Excel
+        #[arg(long)]
+        bom: bool,
     },
     /// Export AI-ready data
     ExportAi {
-        #[arg(help = "Output file path")]
+        #[arg(help = "Output file path (.json, or .parquet)")]
         output: String,
+        /// Records to export (file or patient store)
+        #[arg(long, default_value = "mock_data/merged_output.csv")]
+        input: String,
     },
     /// Predict health risks
     PredictRisk {
@@ -114,6 +382,44 @@ enum Commands {
         #[arg(help = "Output file path")]
         output: String,
     },
+    /// Validate HL7 v2 messages received over MLLP and acknowledge them
+    Listen {
+        /// Address to bind
+        #[arg(long, default_value = "127.0.0.1:2575")]
+        bind: String,
+        /// Stop after this many messages
+        #[arg(long)]
+        max_messages: Option<usize>,
+        /// Close connections idle for this many seconds
+        #[arg(long, default_value_t = 300)]
+        read_timeout: u64,
+        /// Reject messages longer than this many bytes with AR
+        #[arg(long, default_value_t = 1 << 20)]
+        max_frame_size: usize,
+    },
+    /// Show a patient's stored record versions and validation findings
+    History {
+        #[arg(help = "Patient store path")]
+        path: String,
+        #[arg(help = "Patient ID (value of the primary identifier)")]
+        patient_id: String,
+    },
+    /// Join LDT lab results onto records by patient and date
+    JoinLab {
+        #[arg(help = "Input file path")]
+        path: String,
+        #[arg(help = "LDT lab file path")]
+        lab: String,
+        #[arg(help = "Output file path (.csv or .json)")]
+        output: String,
+    },
+    /// Export risk predictions as FHIR RiskAssessments
+    ExportRiskFhir {
+        #[arg(help = "Input file path")]
+        path: String,
+        #[arg(help = "Output file path")]
+        output: String,
+    },
 }
 
 #[instrument]
@@ -126,62 +432,129 @@ fn main() -> Result<(), AktenError> {
 
     let cli = Cli::parse();
     let config_path = cli.config.as_deref().unwrap_or("config.toml");
-    let config = ThresholdConfig::load(config_path)
+    let mut config = ThresholdConfig::load(config_path)
         .map_err(|e| AktenError::ConfigError(e.to_string()))?;
+    if let Some(path) = &cli.csv_mapping {
+        config.csv.mapping = ColumnMapping::load(path).map_err(|e| AktenError::ConfigError(e.to_string()))?;
+    }
 
     info!(?config, "Loaded configuration");
+    timestamp::configure(&config.time);
+    csv_io::configure(&config.csv);
 
     match &cli.command {
-        Commands::Validate { path } => handle_validate(path, &cli, &config),
-        Commands::Summarize { path } => handle_summarize(path, &cli),
-        Commands::MergeFiles { output, inputs } => handle_merge(output, inputs, &cli),
-        Commands::Export { format, output } => handle_export(format, output, &cli),
-        Commands::ExportAi { output } => handle_export_ai(output, &cli),
+        Commands::Validate { path, fhir_output, lab } => {
+            handle_validate(path, fhir_output.as_deref(), lab.as_deref(), &cli, &config)
+        }
+        Commands::Summarize { path } => handle_summarize(path, &cli, &config),
+        Commands::MergeFiles { output, inputs } => handle_merge(output, inputs, &cli, &config),
+        Commands::Export { format, output, bundle_type, profile, hl7_grouping, input, dialect, encoding, bom } => {
+            let dialect = match dialect {
+                Some(dialect) => csv_io::Dialect::parse(dialect)?,
+                None => config.csv.dialect.unwrap_or_default(),
+            };
+            let options = export::ExportOptions {
+                bundle_type: fhir::BundleType::parse(bundle_type)?,
+                profile: profiles::FhirProfile::parse(profile)?,
+                hl7_grouping: hl7::MessageGrouping::parse(hl7_grouping)?,
+                thresholds: Some(config.thresholds.clone()),
+                openehr: config.openehr.clone(),
+                omop: config.omop.clone(),
+                identity: config.identity.clone(),
+                csv: csv_io::CsvOutput::new(dialect, csv_io::Encoding::parse(encoding)?, *bom)?,
+            };
+            handle_export(input, format, output, &options, &cli)
+        }
+        Commands::ExportAi { output, input } => handle_export_ai(input, output, &cli, &config),
         Commands::PredictRisk { path } => handle_predict_risk(path, &cli, &config),
         Commands::ExportRiskJson { path, output } => handle_export_risk(path, output, &cli, &config),
+        Commands::ExportRiskFhir { path, output } => handle_export_risk_fhir(path, output, &cli, &config),
+        Commands::Listen { bind, max_messages, read_timeout, max_frame_size } => {
+            let options = mllp::ListenerOptions {
+                medical_mode: cli.medical_mode,
+                config: config.clone(),
+                max_messages: *max_messages,
+                read_timeout: Duration::from_secs(*read_timeout),
+                max_frame_size: *max_frame_size,
+            };
+            handle_listen(bind, &options, &cli)
+        }
+        Commands::JoinLab { path, lab, output } => handle_join_lab(path, lab, output, &cli, &config),
+        Commands::History { path, patient_id } => handle_history(path, patient_id),
     }
 }
 
 // Command handlers
-fn handle_validate(path: &str, cli: &Cli, config: &ThresholdConfig) -> Result<(), AktenError> {
+fn handle_validate(
+    path: &str,
+    fhir_output: Option<&str>,
+    lab: Option<&str>,
+    cli: &Cli,
+    config: &ThresholdConfig,
+) -> Result<(), AktenError> {
     info!(path, "Validating records");
     if cli.dry_run {
         info!("Dry run - would validate {}", path);
         return Ok(());
     }
-    validate::run_validation(path, cli.medical_mode, config)?;
+    let lab_results = lab.map(ldt::load_lab_results).transpose()?.unwrap_or_default();
+    let result = validate::run_validation(path, cli.medical_mode, config, &lab_results, &load_options(cli, &config.identity)?)?;
+    if store::is_store(path) {
+        let run_id = store::PatientStore::open(path)?.save_validation(&result, cli.medical_mode)?;
+        info!(path, run_id, "Saved validation results to the patient store");
+    }
+    if let Some(output) = fhir_output {
+        let bundle = fhir::findings_to_bundle(&result.findings);
+        serde_json::to_writer_pretty(std::fs::File::create(output)?, &bundle)?;
+        info!(output, "Wrote {} findings as FHIR resources", result.findings.len());
+    }
     Ok(())
 }
 
-fn handle_summarize(path: &str, cli: &Cli) -> Result<(), AktenError> {
+fn handle_summarize(path: &str, cli: &Cli, config: &ThresholdConfig) -> Result<(), AktenError> {
     let timer = Instant::now();
-    let records = load_records(path)?;
+    let records = load_records(path, cli, &config.identity)?;
     summarize::summarize_data(&records, cli.medical_mode)?;
     info!("Summary completed in {:?}", timer.elapsed());
     Ok(())
 }
 
-fn handle_merge(output: &str, inputs: &[String], cli: &Cli) -> Result<(), AktenError> {
+fn handle_merge(output: &str, inputs: &[String], cli: &Cli, config: &ThresholdConfig) -> Result<(), AktenError> {
     info!(?inputs, output, "Merging files");
     if cli.dry_run {
         info!("Dry run - would merge to {}", output);
         return Ok(());
     }
+    if store::is_store(output) {
+        let mut patient_store = store::PatientStore::open(output)?;
+        let mut inserted = 0;
+        for input in inputs {
+            inserted += patient_store.insert_records(&load_records(input, cli, &config.identity)?, input)?;
+        }
+        println!("🗄️ Stored {} new records from {} files in '{}'.", inserted, inputs.len(), output);
+        return Ok(());
+    }
     let input_refs: Vec<&str> = inputs.iter().map(|s| s.as_str()).collect();
-    merge::merge_files(&input_refs, output, cli.medical_mode)
+    merge::merge_files(&input_refs, output, cli.medical_mode, &load_options(cli, &config.identity)?)
 }
 
-fn handle_export(format: &str, output: &str, cli: &Cli) -> Result<(), AktenError> {
-    let records = load_records("mock_data/merged_output.csv")?;
+fn handle_export(
+    input: &str,
+    format: &str,
+    output: &str,
+    options: &export::ExportOptions,
+    cli: &Cli,
+) -> Result<(), AktenError> {
+    let records = load_records(input, cli, &options.identity)?;
     if cli.dry_run {
         info!("Dry run - would export to {}", output);
         return Ok(());
     }
-    export::export_data(&records, format, output, cli.medical_mode)
+    export::export_data(&records, format, output, cli.medical_mode, options)
 }
 
-fn handle_export_ai(output: &str, cli: &Cli) -> Result<(), AktenError> {
-    let records = load_records("mock_data/merged_output.csv")?;
+fn handle_export_ai(input: &str, output: &str, cli: &Cli, config: &ThresholdConfig) -> Result<(), AktenError> {
+    let records = load_records(input, cli, &config.identity)?;
     if cli.dry_run {
         info!("Dry run - would export AI data to {}", output);
         return Ok(());
@@ -190,7 +563,7 @@ fn handle_export_ai(output: &str, cli: &Cli) -> Result<(), AktenError> {
 }
 
 fn handle_predict_risk(path: &str, cli: &Cli, config: &ThresholdConfig) -> Result<(), AktenError> {
-    let records = load_records(path)?;
+    let records = load_records(path, cli, &config.identity)?;
     if cli.medical_mode {
         info!("Running in medical mode");
     }
@@ -198,7 +571,7 @@ fn handle_predict_risk(path: &str, cli: &Cli, config: &ThresholdConfig) -> Resul
 }
 
 fn handle_export_risk(path: &str, output: &str, cli: &Cli, config: &ThresholdConfig) -> Result<(), AktenError> {
-    let records = load_records(path)?;
+    let records = load_records(path, cli, &config.identity)?;
     if cli.dry_run {
         info!("Dry run - would export risks to {}", output);
         return Ok(());
@@ -206,31 +579,90 @@ fn handle_export_risk(path: &str, output: &str, cli: &Cli, config: &ThresholdCon
     risk::export_risks_as_json(&records, config, output)
 }
 
-// Core utilities
-fn validate_path(path: &str) -> Result<(), AktenError> {
-    if !Path::new(path).exists() {
-        return Err(AktenError::InvalidPath(path.into()));
-    }
-    if !path.ends_with(".csv") && !path.ends_with(".json") && !path.ends_with(".fhir") {
-        return Err(AktenError::UnsupportedFormat);
+fn handle_export_risk_fhir(path: &str, output: &str, cli: &Cli, config: &ThresholdConfig) -> Result<(), AktenError> {
+    let records = load_records(path, cli, &config.identity)?;
+    if cli.dry_run {
+        info!("Dry run - would export FHIR risk assessments to {}", output);
+        return Ok(());
     }
-    Ok(())
+    risk::export_risks_as_fhir(&records, config, output)
 }
 
-fn load_records(path: &str) -> Result<Vec<PatientRecord>, AktenError> {
-    validate_path(path)?;
-    info!(path, "Loading records");
+fn handle_join_lab(path: &str, lab: &str, output: &str, cli: &Cli, config: &ThresholdConfig) -> Result<(), AktenError> {
+    let mut records = load_records(path, cli, &config.identity)?;
+    let lab_results = ldt::load_lab_results(lab)?;
+    ldt::join_lab_results(&mut records, &lab_results);
+    if cli.dry_run {
+        info!("Dry run - would write joined records to {}", output);
+        return Ok(());
+    }
+    let format = if output.ends_with(".json") { "json" } else { "csv" };
+    export::export_data(&records, format, output, cli.medical_mode, &export::ExportOptions::default())
+}
 
-    let file = std::fs::File::open(path)?;
-    match path.rsplit('.').next() {
-        Some("json") => serde_json::from_reader(file).map_err(Into::into),
-        Some("fhir") => fhir::convert_fhir_to_records(file),
-        Some("csv") => {
-            csv::Reader::from_reader(file)
-                .deserialize()
-                .collect::<Result<_, _>>()
-                .map_err(Into::into)
+fn handle_history(path: &str, patient_id: &str) -> Result<(), AktenError> {
+    loader::validate_path(path)?;
+    let history = store::PatientStore::open(path)?.history(patient_id)?;
+    if history.is_empty() {
+        println!("No stored records for patient {}", patient_id);
+        return Ok(());
+    }
+    println!("📜 History of patient {} ({} record versions)", patient_id, history.len());
+    for stored in &history {
+        let record = &stored.record;
+        println!(
+            "- {} HR {} BP {}/{} Temp {} Sugar {} Steps {}  [{} at {}]",
+            record.timestamp,
+            display_value(record.heart_rate, 0),
+            display_value(record.bp_systolic, 0),
+            display_value(record.bp_diastolic, 0),
+            display_value(record.temperature, 1),
+            display_value(record.blood_sugar, 1),
+            display_value(record.steps, 0),
+            stored.source_file,
+            stored.inserted_at
+        );
+        for finding in &stored.findings {
+            let marker = if finding.critical { "🚨" } else { "⚠️" };
+            println!("    {} {} (validated {})", marker, finding.message, finding.validated_at);
         }
-        _ => Err(AktenError::UnsupportedFormat),
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+fn handle_listen(bind: &str, options: &mllp::ListenerOptions, cli: &Cli) -> Result<(), AktenError> {
+    if cli.dry_run {
+        info!("Dry run - would listen for MLLP connections on {}", bind);
+        return Ok(());
+    }
+    mllp::listen(bind, options)
+}
+
+// Core utilities
+/// Measurement times selected with `--since` and `--until`
+fn time_range(cli: &Cli) -> Result<TimeRange, AktenError> {
+    TimeRange::parse(cli.since.as_deref(), cli.until.as_deref()).map_err(AktenError::Time)
+}
+
+/// Demographics selected with `--demographics`, none without it
+fn load_demographics(cli: &Cli) -> Result<Vec<Demographics>, AktenError> {
+    cli.demographics.as_deref().map(demographics::load_demographics).transpose().map(Option::unwrap_or_default)
+}
+
+/// How records are prepared for this run: `--since`/`--until`,
+/// `--demographics` and `--provenance`
+fn load_options(cli: &Cli, identity: &IdentityConfig) -> Result<loader::LoadOptions, AktenError> {
+    Ok(loader::LoadOptions {
+        identity: identity.clone(),
+        range: time_range(cli)?,
+        demographics: load_demographics(cli)?,
+        with_provenance: cli.provenance,
+    })
+}
+
+/// Load records from any supported source, keyed by the primary identifier,
+/// within the selected time range and in chronological order; temperatures
+/// are in °C and blood sugar in mg/dL
+fn load_records(path: &str, cli: &Cli, identity: &IdentityConfig) -> Result<Vec<PatientRecord>, AktenError> {
+    loader::load_records(path, &load_options(cli, identity)?)
+}
@@ -1,34 +1,29 @@
+use crate::csv_io::{self, CsvOutput};
+use crate::loader::{self, LoadOptions};
 use crate::{AktenError, PatientRecord};
-use std::fs::{File, OpenOptions};
-use std::path::Path;
-use csv::{ReaderBuilder, WriterBuilder};
-use serde_json;
-
-/// Merges multiple input files into a single output CSV file
-pub fn merge_files(inputs: &Vec<&str>, output: &str, medical_mode: bool) -> Result<(), AktenError> {
+use std::fs::OpenOptions;
+
+/// Merges multiple input files of any format the shared loader reads into a
+/// single output CSV file, with every record keyed by its primary identifier
+/// and its measurements in °C and mg/dL; records within the time range are
+/// written in chronological order, with birth date and sex from the
+/// demographics when given and, with provenance, the input file and line
+/// each record came from. Additional observations are written as further columns
+pub fn merge_files(
+    inputs: &Vec<&str>,
+    output: &str,
+    medical_mode: bool,
+    options: &LoadOptions,
+) -> Result<(), AktenError> {
     let mut all_records: Vec<PatientRecord> = Vec::new();
 
     for path in inputs {
-        if !Path::new(path).exists() {
-            return Err(AktenError::InvalidPath(path.to_string()));
-        }
-
-        let file = File::open(path).map_err(AktenError::Io)?;
-
-        if path.ends_with(".json") {
-            let records: Vec<PatientRecord> = serde_json::from_reader(file).map_err(AktenError::Json)?;
-            all_records.extend(records);
-        } else if path.ends_with(".csv") {
-            let mut rdr = ReaderBuilder::new().has_headers(true).from_reader(file);
-            for result in rdr.deserialize() {
-                let record: PatientRecord = result.map_err(AktenError::Csv)?;
-                all_records.push(record);
-            }
-        } else {
-            return Err(AktenError::UnsupportedFormat);
-        }
+        all_records.extend(loader::read_records(path)?);
     }
 
+    let unit_issues = loader::prepare(&mut all_records, options, output);
+    loader::warn_unit_issues(&unit_issues, output);
+
     let file = OpenOptions::new()
         .create(true)
         .write(true)
@@ -36,11 +31,7 @@ pub fn merge_files(inputs: &Vec<&str>, output: &str, medical_mode: bool) -> Resu
         .open(output)
         .map_err(AktenError::Io)?;
 
-    let mut wtr = WriterBuilder::new().has_headers(true).from_writer(file);
-    for record in &all_records {
-        wtr.serialize(record).map_err(AktenError::Csv)?;
-    }
-    wtr.flush().map_err(AktenError::Io)?;
+    csv_io::write_records(file, &all_records, &CsvOutput::default(), false)?;
 
     if medical_mode {
         println!("📋 Medical mode enabled – merged {} records to '{}'.", all_records.len(), output);
@@ -0,0 +1,458 @@
+use crate::config::ThresholdConfig;
+use crate::hl7::{self, Message};
+use crate::validate::{self, FindingKind};
+use crate::AktenError;
+use chrono::Utc;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// MLLP frame: <VT> message <FS><CR>
+const START_BLOCK: u8 = 0x0b;
+const END_BLOCK: u8 = 0x1c;
+const CARRIAGE_RETURN: u8 = 0x0d;
+
+/// Running number of the ACKs sent, appended to their control ids so that
+/// ACKs sent within the same second differ
+static ACK_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// How often the accept loop checks whether `max_messages` was reached
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Settings shared by all connections of a listener
+#[derive(Debug, Clone)]
+pub struct ListenerOptions {
+    pub medical_mode: bool,
+    pub config: ThresholdConfig,
+    /// Stop after this many messages (scripted runs and tests)
+    pub max_messages: Option<usize>,
+    /// Close connections that send nothing for this long, also in the middle of a frame
+    pub read_timeout: Duration,
+    /// Frames longer than this many bytes are answered with AR and skipped
+    pub max_frame_size: usize,
+}
+
+/// A frame read from a connection
+#[derive(Debug)]
+pub enum Frame {
+    Message(Vec<u8>),
+    /// Longer than the limit; its content was skipped up to the end block
+    Oversized,
+}
+
+/// Bind `address` and acknowledge incoming HL7 v2 messages
+pub fn listen(address: &str, options: &ListenerOptions) -> Result<(), AktenError> {
+    let listener = TcpListener::bind(address)?;
+    info!("MLLP listener accepting HL7 v2 messages on {}", listener.local_addr()?);
+    serve(listener, options)
+}
+
+/// Serve each connection on its own thread, so a stalled peer holds up
+/// only its own channel; interface engines keep one persistent connection
+/// per channel
+pub fn serve(listener: TcpListener, options: &ListenerOptions) -> Result<(), AktenError> {
+    let options = Arc::new(options.clone());
+    let remaining = options.max_messages.map(|count| Arc::new(AtomicUsize::new(count)));
+    // Polled so that the loop notices when the last message was answered
+    listener.set_nonblocking(true)?;
+    while remaining.as_ref().is_none_or(|count| count.load(Ordering::SeqCst) > 0) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        info!(peer, "MLLP connection opened");
+        let (options, remaining) = (Arc::clone(&options), remaining.clone());
+        thread::spawn(move || match handle_connection(stream, &options, remaining.as_deref()) {
+            Ok(()) => info!(peer, "MLLP connection closed"),
+            Err(AktenError::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                info!(peer, "MLLP connection closed after {:?} without data", options.read_timeout);
+            }
+            Err(e) => warn!(peer, "MLLP connection closed with error: {}", e),
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, options: &ListenerOptions, remaining: Option<&AtomicUsize>) -> Result<(), AktenError> {
+    // Accepted sockets may inherit the listener's non-blocking mode
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(options.read_timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    while let Some(frame) = read_frame(&mut reader, options.max_frame_size)? {
+        // Other connections may have used up the message budget meanwhile
+        if remaining.is_some_and(|count| count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_err()) {
+            break;
+        }
+        let ack = match frame {
+            Frame::Message(bytes) => acknowledge(&hl7::decode_text(bytes), options.medical_mode, &options.config),
+            Frame::Oversized => reject_oversized(options.max_frame_size),
+        };
+        write_frame(&mut writer, &ack)?;
+        if remaining.is_some_and(|count| count.load(Ordering::SeqCst) == 0) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Next framed message, or `None` once the peer closes the connection.
+/// Frames of more than `max_size` bytes are not kept
+pub fn read_frame(reader: &mut impl BufRead, max_size: usize) -> Result<Option<Frame>, AktenError> {
+    // Anything before the start block, such as the previous frame's trailing CR, is skipped
+    if !skip_past(reader, START_BLOCK)? {
+        return Ok(None);
+    }
+
+    let mut frame = vec![];
+    reader.by_ref().take((max_size as u64).saturating_add(1)).read_until(END_BLOCK, &mut frame)?;
+    if frame.last() == Some(&END_BLOCK) {
+        frame.pop();
+        return Ok(Some(Frame::Message(frame)));
+    }
+    if frame.len() > max_size && skip_past(reader, END_BLOCK)? {
+        return Ok(Some(Frame::Oversized));
+    }
+    Err(AktenError::Hl7("connection closed inside an MLLP frame".to_string()))
+}
+
+/// Consume input up to and including `byte` without keeping it; false if
+/// the input ends first
+fn skip_past(reader: &mut impl BufRead, byte: u8) -> io::Result<bool> {
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(false);
+        }
+        if let Some(position) = buffer.iter().position(|b| *b == byte) {
+            reader.consume(position + 1);
+            return Ok(true);
+        }
+        let length = buffer.len();
+        reader.consume(length);
+    }
+}
+
+pub fn write_frame(writer: &mut impl Write, message: &str) -> Result<(), AktenError> {
+    writer.write_all(&[START_BLOCK])?;
+    writer.write_all(message.as_bytes())?;
+    writer.write_all(&[END_BLOCK, CARRIAGE_RETURN])?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// One ERR segment's content
+struct ErrorEntry {
+    location: String,
+    /// HL7 table 0357 code and text
+    code: (&'static str, &'static str),
+    /// E(rror), W(arning) or I(nformation)
+    severity: &'static str,
+    application_code: &'static str,
+    message: String,
+}
+
+/// Run a message through the import mapping and `validate_records` and
+/// build the ACK: AR if it cannot be processed at all, AE if it has
+/// errors or critical findings, AA otherwise (warnings still get ERR segments)
+pub fn acknowledge(text: &str, medical_mode: bool, config: &ThresholdConfig) -> String {
+    let message = match Message::parse(text) {
+        Ok(message) => message,
+        Err(e) => {
+            warn!("Rejecting unparseable HL7 message: {}", e);
+            return ack(None, "AR", &[ErrorEntry {
+                location: "MSH".to_string(),
+                code: ("100", "Segment sequence error"),
+                severity: "E",
+                application_code: "PARSE",
+                message: e.to_string(),
+            }]);
+        }
+    };
+    let control_id = message.control_id();
+    let message_type = message.message_type();
+    if message_type != "ORU^R01" {
+        warn!(control_id, "Rejecting unsupported message type {}", message_type);
+        return ack(Some(&message), "AR", &[ErrorEntry {
+            location: "MSH^1^9".to_string(),
+            code: ("200", "Unsupported message type"),
+            severity: "E",
+            application_code: "TYPE",
+            message: format!("only ORU^R01 is accepted, got {}", message_type),
+        }]);
+    }
+
+    let (records, report) = hl7::parse_messages(text);
+    let mut errors = vec![];
+    for issue in &report.issues {
+        errors.push(ErrorEntry {
+            location: String::new(),
+            code: ("207", "Application internal error"),
+            severity: "W",
+            application_code: "MAPPING",
+            message: issue.clone(),
+        });
+    }
+    for issue in &report.incomplete {
+        errors.push(ErrorEntry {
+            location: String::new(),
+            // Records with missing vitals are still stored and validated
+            code: ("0", "Message accepted"),
+            severity: "W",
+            application_code: "INCOMPLETE",
+            message: issue.clone(),
+        });
+    }
+
+    let result = validate::validate_records(&records, medical_mode, config);
+    let mut findings = result.findings;
+    // Validation runs in parallel; keep the ERR order stable
+    findings.sort_by(|a, b| {
+        (&a.record.patient_id, &a.record.timestamp, &a.message).cmp(&(&b.record.patient_id, &b.record.timestamp, &b.message))
+    });
+    for finding in findings {
+        let location = finding
+            .fields
+            .first()
+            .and_then(|field| hl7::result_location(&message, &finding.record.patient_id, &finding.record.timestamp, *field))
+            .unwrap_or_default();
+        let application_code = match finding.kind {
+            FindingKind::Clinical => "ALERT",
+            FindingKind::DataQuality => "DATA",
+        };
+        // The message is stored either way; critical findings make it an
+        // application error so the AE and its severity agree, others are warnings
+        let (code, severity) = if finding.critical {
+            (("207", "Application internal error"), "E")
+        } else {
+            (("0", "Message accepted"), "W")
+        };
+        errors.push(ErrorEntry {
+            location,
+            code,
+            severity,
+            application_code,
+            message: format!("{} | Patient {} ({})", finding.message, finding.record.patient_id, finding.record.timestamp),
+        });
+    }
+
+    let code = if errors.iter().any(|e| e.severity == "E") { "AE" } else { "AA" };
+    info!(control_id, code, findings = errors.len(), "Acknowledged HL7 message");
+    ack(Some(&message), code, &errors)
+}
+
+/// AR for a frame longer than `max_size` bytes; it was not read, so the
+/// ACK cannot echo its control id
+fn reject_oversized(max_size: usize) -> String {
+    warn!(max_size, "Rejecting oversized MLLP frame");
+    ack(None, "AR", &[ErrorEntry {
+        location: "MSH".to_string(),
+        code: ("207", "Application internal error"),
+        severity: "E",
+        application_code: "SIZE",
+        message: format!("message exceeds {} bytes", max_size),
+    }])
+}
+
+fn ack(original: Option<&Message>, code: &str, errors: &[ErrorEntry]) -> String {
+    let delimiters = original.map(|m| m.delimiters).unwrap_or_default();
+    let field = |name: &str, position: usize| {
+        original
+            .and_then(|m| m.segment(name))
+            .map(|s| s.field(position).to_string())
+            .unwrap_or_default()
+    };
+    let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    let f = delimiters.field;
+    let c = delimiters.component;
+
+    // Sender and receiver swap places; MSH-12 echoes the original version
+    let version = Some(field("MSH", 12)).filter(|v| !v.is_empty()).unwrap_or_else(|| "2.5".to_string());
+    let mut segments = vec![
+        [
+            "MSH".to_string(),
+            delimiters.encoding_characters(),
+            "AKTENAKROBAT".to_string(),
+            String::new(),
+            field("MSH", 3),
+            field("MSH", 4),
+            timestamp.clone(),
+            String::new(),
+            format!("ACK{c}R01{c}ACK"),
+            // MSH-10 holds up to 20 characters
+            format!("ACK{}{:03}", timestamp, ACK_SEQUENCE.fetch_add(1, Ordering::Relaxed) % 1000),
+            "P".to_string(),
+            version,
+        ]
+        .join(&f.to_string()),
+        [
+            "MSA".to_string(),
+            code.to_string(),
+            field("MSH", 10),
+        ]
+        .join(&f.to_string()),
+    ];
+
+    for error in errors {
+        let (code, text) = error.code;
+        segments.push(
+            [
+                "ERR".to_string(),
+                String::new(),
+                error.location.replace('^', &c.to_string()),
+                format!("{code}{c}{}{c}HL70357", delimiters.escape(text)),
+                error.severity.to_string(),
+                error.application_code.to_string(),
+                String::new(),
+                String::new(),
+                delimiters.escape(&error.message),
+            ]
+            .join(&f.to_string()),
+        );
+    }
+
+    segments.join("\r") + "\r"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CriticalHr, HypertensiveCrisis, Thresholds};
+    use std::thread;
+
+    fn options() -> ListenerOptions {
+        ListenerOptions {
+            medical_mode: true,
+            config: ThresholdConfig {
+                thresholds: Thresholds {
+                    heart_rate: CriticalHr { min: 50, max: 90 },
+                    blood_pressure: HypertensiveCrisis { systolic: 150, diastolic: 100 },
+                    hypothermia: 35.0,
+                    fever: 38.0,
+                    hypoglycemia: 70.0,
+                    hyperglycemia: 400.0,
+                    hypoxemia: 92,
+                    respiratory_rate: crate::config::CriticalRr::default(),
+                    underweight_bmi: 18.5,
+                    obesity_bmi: 30.0,
+                },
+                identity: Default::default(),
+                time: Default::default(),
+                fhir: Default::default(),
+                openehr: Default::default(),
+                omop: Default::default(),
+                csv: Default::default(),
+                observations: vec![],
+            },
+            max_messages: Some(2),
+            read_timeout: Duration::from_secs(5),
+            max_frame_size: 4096,
+        }
+    }
+
+    fn read_ack(reader: &mut impl BufRead) -> Result<String, AktenError> {
+        match read_frame(reader, usize::MAX)? {
+            Some(Frame::Message(bytes)) => Ok(String::from_utf8(bytes).unwrap()),
+            frame => panic!("expected an ACK frame, got {:?}", frame),
+        }
+    }
+
+    fn oru(control_id: &str, heart_rate: u32) -> String {
+        format!(
+            "MSH|^~\\&|MONITOR|ICU|||20241201083000||ORU^R01|{control_id}|P|2.5\r\
+             PID|1||7\rOBR|1||||||20241201083000\r\
+             OBX|1|NM|8867-4^Heart rate^LN||{heart_rate}|/min|||||F\r\
+             OBX|2|SN|85354-9^BP^LN||^120^/^80|mm[Hg]|||||F\r\
+             OBX|3|NM|8310-5^Body temperature^LN||36.8|Cel|||||F\r\
+             OBX|4|NM|2339-0^Glucose^LN||95|mg/dL|||||F\r"
+        )
+    }
+
+    fn segment<'a>(ack: &'a str, name: &str) -> Vec<&'a str> {
+        ack.split('\r').filter(|s| s.starts_with(name)).collect()
+    }
+
+    #[test]
+    fn test_acknowledgement_codes() {
+        let config = options().config;
+
+        let accepted = acknowledge(&oru("OK1", 72), true, &config);
+        assert_eq!(segment(&accepted, "MSA"), vec!["MSA|AA|OK1"]);
+        assert!(segment(&accepted, "ERR").is_empty());
+
+        let alert = acknowledge(&oru("HR1", 130), true, &config);
+        assert_eq!(segment(&alert, "MSA"), vec!["MSA|AE|HR1"]);
+        let errors = segment(&alert, "ERR");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("ERR||OBX^1^5|207^Application internal error^HL70357|E|ALERT"), "{}", errors[0]);
+        assert!(errors[0].ends_with("|ALERT|||Abnormal HR (130 bpm) \\F\\ Patient 7 (2024-12-01T08:30:00+01:00)"), "{}", errors[0]);
+
+        let rejected = acknowledge("MSH|^~\\&|ADT|X|||20241201||ADT^A01|ADT1|P|2.5\r", true, &config);
+        assert_eq!(segment(&rejected, "MSA"), vec!["MSA|AR|ADT1"]);
+
+        // ACKs sent within the same second still get their own control id
+        let control_id = |ack: &str| hl7::Message::parse(ack).unwrap().control_id().to_string();
+        assert_ne!(control_id(&accepted), control_id(&alert));
+        assert!(control_id(&rejected).len() <= 20);
+    }
+
+    #[test]
+    fn test_mllp_round_trip() -> Result<(), AktenError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let server = thread::spawn(move || serve(listener, &options()));
+
+        let mut client = TcpStream::connect(address)?;
+        let mut reader = BufReader::new(client.try_clone()?);
+        let mut acks = vec![];
+        for (id, heart_rate) in [("M1", 72), ("M2", 40)] {
+            write_frame(&mut client, &oru(id, heart_rate))?;
+            acks.push(read_ack(&mut reader)?);
+        }
+
+        server.join().expect("listener thread")?;
+        assert!(acks[0].contains("MSA|AA|M1"));
+        assert!(acks[1].contains("MSA|AE|M2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stalled_and_oversized_frames() -> Result<(), AktenError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let options = ListenerOptions {
+            read_timeout: Duration::from_millis(300),
+            max_frame_size: 512,
+            ..options()
+        };
+        let server = thread::spawn(move || serve(listener, &options));
+
+        // Starts a frame and never finishes it
+        let mut stalled = TcpStream::connect(address)?;
+        stalled.write_all(&[START_BLOCK])?;
+        stalled.write_all(b"MSH|^~\\&|MONITOR|ICU")?;
+
+        let mut client = TcpStream::connect(address)?;
+        let mut reader = BufReader::new(client.try_clone()?);
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        write_frame(&mut client, &oru("BIG", 72).repeat(4))?;
+        assert!(read_ack(&mut reader)?.contains("MSA|AR|\r"));
+        write_frame(&mut client, &oru("M1", 72))?;
+        assert!(read_ack(&mut reader)?.contains("MSA|AA|M1"));
+        server.join().expect("listener thread")?;
+
+        // The stalled connection is closed once the read timeout passes
+        stalled.set_read_timeout(Some(Duration::from_secs(5)))?;
+        assert_eq!(stalled.read(&mut [0; 16])?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use crate::config::ObservationThreshold;
+use crate::PatientRecord;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Value of an additional observation: a number when the text reads back
+/// exactly as one, so `4` and `36.5` are numbers but a serial such as
+/// `0042` keeps its leading zeros as text
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ObservationValue {
+    Number(f64),
+    Text(String),
+}
+
+impl ObservationValue {
+    pub fn parse(text: &str) -> Self {
+        match text.trim().parse::<f64>() {
+            Ok(number) if number.is_finite() && number.to_string() == text.trim() => Self::Number(number),
+            _ => Self::Text(text.to_string()),
+        }
+    }
+
+    /// The value as a number, reading text with a decimal point or comma
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Self::Number(number) => Some(*number),
+            Self::Text(text) => text.trim().replace(',', ".").parse().ok().filter(|n: &f64| n.is_finite()),
+        }
+    }
+}
+
+impl fmt::Display for ObservationValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "{}", number),
+            Self::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+/// Observations beyond the fixed vitals, by name: columns no record field
+/// takes, such as a pain score or a device serial. In CSV one column each,
+/// in JSON an object
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Observations(BTreeMap<String, ObservationValue>);
+
+impl Observations {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ObservationValue> {
+        self.0.get(name)
+    }
+
+    pub fn insert(&mut self, name: &str, value: ObservationValue) {
+        self.0.insert(name.to_string(), value);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &ObservationValue)> {
+        self.0.iter()
+    }
+}
+
+/// Outcome of a configured threshold for one record
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThresholdOutcome<'a> {
+    /// Outside the range, with the value
+    Exceeded(&'a ObservationThreshold, f64),
+    /// Recorded, but not as a number
+    NotANumber(&'a ObservationThreshold, &'a ObservationValue),
+}
+
+/// Configured thresholds `record` violates or cannot be checked against;
+/// records without the observation are not assessed
+pub fn check<'a>(record: &'a PatientRecord, thresholds: &'a [ObservationThreshold]) -> Vec<ThresholdOutcome<'a>> {
+    thresholds
+        .iter()
+        .filter_map(|threshold| {
+            let value = record.observations.get(&threshold.name)?;
+            match value.as_number() {
+                None => Some(ThresholdOutcome::NotANumber(threshold, value)),
+                Some(number) if !threshold.contains(number) => Some(ThresholdOutcome::Exceeded(threshold, number)),
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_keep_their_text_and_thresholds_apply() {
+        assert_eq!(ObservationValue::parse("4"), ObservationValue::Number(4.0));
+        assert_eq!(ObservationValue::parse("36.5").to_string(), "36.5");
+        assert_eq!(ObservationValue::parse("0042").to_string(), "0042");
+        assert_eq!(ObservationValue::parse("7,5").as_number(), Some(7.5));
+
+        let pain = ObservationThreshold {
+            name: "pain_score".to_string(),
+            min: None,
+            max: Some(6.0),
+            label: "Severe pain".to_string(),
+            critical: false,
+        };
+        let mut record = PatientRecord::default();
+        assert!(check(&record, std::slice::from_ref(&pain)).is_empty(), "not recorded, not assessed");
+        record.observations.insert("pain_score", ObservationValue::parse("8"));
+        assert_eq!(check(&record, std::slice::from_ref(&pain)), vec![ThresholdOutcome::Exceeded(&pain, 8.0)]);
+        record.observations.insert("pain_score", ObservationValue::parse("stark"));
+        assert!(matches!(check(&record, std::slice::from_ref(&pain))[0], ThresholdOutcome::NotANumber(..)));
+    }
+}
@@ -0,0 +1,394 @@
+use crate::config::OmopConfig;
+use crate::demographics::Sex;
+use crate::fhir::{self, VitalField};
+use crate::timestamp::Timestamp;
+use crate::{fnv1a, AktenError, PatientRecord};
+use chrono::{Datelike, NaiveDate};
+use csv::WriterBuilder;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::Path;
+use tracing::{info, warn};
+
+pub const PERSON_FILE: &str = "person.csv";
+pub const MEASUREMENT_FILE: &str = "measurement.csv";
+pub const OBSERVATION_PERIOD_FILE: &str = "observation_period.csv";
+
+/// Patients without a birth date named in the export error before the rest are summarised
+const MAX_REPORTED_PATIENTS: usize = 10;
+
+/// Fields written as measurements, in id order; new fields go at the end so
+/// existing measurement ids stay stable. AVPU and the oxygen flag are not exported
+const MEASURED_FIELDS: [VitalField; 11] = [
+    VitalField::HeartRate,
+    VitalField::BpSystolic,
+    VitalField::BpDiastolic,
+    VitalField::Temperature,
+    VitalField::BloodSugar,
+    VitalField::Steps,
+    VitalField::Spo2,
+    VitalField::RespiratoryRate,
+    VitalField::Weight,
+    VitalField::Height,
+    VitalField::Bmi,
+];
+
+/// OMOP CDM v5.4 PERSON, with the birth date and sex joined onto the records;
+/// race and ethnicity are not recorded and get concept 0
+#[derive(Debug, Serialize)]
+pub struct Person {
+    pub person_id: i64,
+    pub gender_concept_id: i64,
+    pub year_of_birth: i32,
+    pub month_of_birth: Option<u32>,
+    pub day_of_birth: Option<u32>,
+    pub birth_datetime: Option<String>,
+    pub race_concept_id: i64,
+    pub ethnicity_concept_id: i64,
+    pub location_id: Option<i64>,
+    pub provider_id: Option<i64>,
+    pub care_site_id: Option<i64>,
+    pub person_source_value: String,
+    pub gender_source_value: Option<String>,
+    pub gender_source_concept_id: Option<i64>,
+    pub race_source_value: Option<String>,
+    pub race_source_concept_id: Option<i64>,
+    pub ethnicity_source_value: Option<String>,
+    pub ethnicity_source_concept_id: Option<i64>,
+}
+
+/// OMOP CDM v5.4 MEASUREMENT
+#[derive(Debug, Serialize)]
+pub struct Measurement {
+    pub measurement_id: i64,
+    pub person_id: i64,
+    pub measurement_concept_id: i64,
+    pub measurement_date: String,
+    pub measurement_datetime: Option<String>,
+    pub measurement_time: Option<String>,
+    pub measurement_type_concept_id: i64,
+    pub operator_concept_id: Option<i64>,
+    pub value_as_number: f64,
+    pub value_as_concept_id: Option<i64>,
+    pub unit_concept_id: i64,
+    pub range_low: Option<f64>,
+    pub range_high: Option<f64>,
+    pub provider_id: Option<i64>,
+    pub visit_occurrence_id: Option<i64>,
+    pub visit_detail_id: Option<i64>,
+    pub measurement_source_value: String,
+    pub measurement_source_concept_id: i64,
+    pub unit_source_value: String,
+    pub unit_source_concept_id: Option<i64>,
+    pub value_source_value: String,
+    pub measurement_event_id: Option<i64>,
+    pub meas_event_field_concept_id: Option<i64>,
+}
+
+/// OMOP CDM v5.4 OBSERVATION_PERIOD, spanning each person's recorded dates
+#[derive(Debug, Serialize)]
+pub struct ObservationPeriod {
+    pub observation_period_id: i64,
+    pub person_id: i64,
+    pub observation_period_start_date: String,
+    pub observation_period_end_date: String,
+    pub period_type_concept_id: i64,
+}
+
+/// Rows of the three CDM tables
+#[derive(Debug, Default)]
+pub struct OmopTables {
+    pub persons: Vec<Person>,
+    pub measurements: Vec<Measurement>,
+    pub observation_periods: Vec<ObservationPeriod>,
+}
+
+/// Person id of a patient: numeric patient ids are used as they are, others
+/// (KVNRs, MRNs, UUIDs) get a stable 38-bit FNV-1a hash above the `u32` range.
+/// The id is also the observation period key
+pub fn person_id(patient_id: &str) -> i64 {
+    if let Ok(numeric) = patient_id.parse::<u32>() {
+        return i64::from(numeric);
+    }
+    (1 << 32) + (fnv1a(patient_id.as_bytes()) % (1 << 38)) as i64
+}
+
+/// A measurement id is a 62-bit hash of the person, the measurement time and
+/// the field, so re-exporting the same record yields the same ids
+pub fn measurement_id(person_id: i64, timestamp: &Timestamp, field_index: usize) -> i64 {
+    let key = format!("{}|{}|{}", person_id, timestamp.instant().timestamp(), field_index);
+    (fnv1a(key.as_bytes()) >> 2) as i64
+}
+
+/// Build the PERSON, MEASUREMENT and OBSERVATION_PERIOD rows for `records`.
+/// The CDM requires a year of birth, so patients without a known birth date
+/// are an error
+pub fn records_to_tables(records: &[PatientRecord], config: &OmopConfig) -> Result<OmopTables, AktenError> {
+    let mut tables = OmopTables::default();
+    let mut periods: BTreeMap<i64, (NaiveDate, NaiveDate)> = BTreeMap::new();
+    let mut source_values: BTreeMap<i64, &str> = BTreeMap::new();
+    let mut demographics: BTreeMap<i64, (Option<NaiveDate>, Option<Sex>)> = BTreeMap::new();
+    let mut unmapped = BTreeSet::new();
+    let mut exported = BTreeSet::new();
+    let mut measurement_ids = HashSet::new();
+
+    for record in records {
+        let person_id = person_id(&record.patient_id);
+        let source_value = *source_values.entry(person_id).or_insert(&record.patient_id);
+        if source_value != record.patient_id {
+            return Err(AktenError::Omop(format!(
+                "patients '{}' and '{}' map to the same person id {}",
+                source_value, record.patient_id, person_id
+            )));
+        }
+        // Keys are per patient and time; a repeated record (e.g. merged twice) would collide
+        if !exported.insert((person_id, record.timestamp)) {
+            warn!(patient_id = record.patient_id, timestamp = %record.timestamp, "Duplicate record skipped in OMOP export");
+            continue;
+        }
+        let known = demographics.entry(person_id).or_default();
+        known.0 = known.0.or(record.birth_date);
+        known.1 = known.1.or(record.sex);
+        let date = record.timestamp.date();
+        // Times are written as the local wall-clock time, as the CDM has no time zone
+        let local_time = (!record.timestamp.is_date_only()).then(|| record.timestamp.instant().naive_local());
+        periods
+            .entry(person_id)
+            .and_modify(|(start, end)| {
+                *start = (*start).min(date);
+                *end = (*end).max(date);
+            })
+            .or_insert((date, date));
+
+        for (index, field) in MEASURED_FIELDS.iter().enumerate() {
+            // Unrecorded values get no row; the field index keeps the other ids stable
+            let Some(value) = field_value(record, *field) else {
+                continue;
+            };
+            let coding = fhir::vital_coding(*field);
+            // Unmapped codes get concept 0, as the CDM conventions require
+            let concept_id = config.measurement_concepts.get(coding.loinc).copied().unwrap_or_else(|| {
+                unmapped.insert(coding.loinc);
+                0
+            });
+            let id = measurement_id(person_id, &record.timestamp, index);
+            if !measurement_ids.insert(id) {
+                return Err(AktenError::Omop(format!("measurement id {} is not unique", id)));
+            }
+            tables.measurements.push(Measurement {
+                measurement_id: id,
+                person_id,
+                measurement_concept_id: concept_id,
+                measurement_date: date.format("%Y-%m-%d").to_string(),
+                measurement_datetime: local_time.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()),
+                measurement_time: local_time.map(|t| t.format("%H:%M:%S").to_string()),
+                measurement_type_concept_id: config.type_concept_id,
+                operator_concept_id: None,
+                value_as_number: value,
+                value_as_concept_id: None,
+                unit_concept_id: config.unit_concepts.get(coding.ucum).copied().unwrap_or_default(),
+                range_low: None,
+                range_high: None,
+                provider_id: None,
+                visit_occurrence_id: None,
+                visit_detail_id: None,
+                measurement_source_value: coding.loinc.to_string(),
+                measurement_source_concept_id: concept_id,
+                unit_source_value: coding.ucum.to_string(),
+                unit_source_concept_id: None,
+                value_source_value: value.to_string(),
+                measurement_event_id: None,
+                meas_event_field_concept_id: None,
+            });
+        }
+    }
+    for loinc in unmapped {
+        warn!(loinc, "No OMOP measurement concept configured; written with concept 0");
+    }
+
+    let without_birth_date: Vec<&str> = periods
+        .keys()
+        .filter(|person_id| demographics.get(person_id).is_none_or(|(birth_date, _)| birth_date.is_none()))
+        .map(|person_id| source_values[person_id])
+        .collect();
+    if !without_birth_date.is_empty() {
+        let mut message = without_birth_date.iter().take(MAX_REPORTED_PATIENTS).copied().collect::<Vec<_>>().join(", ");
+        if without_birth_date.len() > MAX_REPORTED_PATIENTS {
+            message.push_str(&format!(" (and {} more)", without_birth_date.len() - MAX_REPORTED_PATIENTS));
+        }
+        return Err(AktenError::Omop(format!(
+            "year of birth required; no birth date known for patients {} (join them with --demographics)",
+            message
+        )));
+    }
+
+    for (person_id, (start, end)) in periods {
+        let (birth_date, sex) = demographics.get(&person_id).copied().unwrap_or_default();
+        // Patients without one were reported above
+        let Some(birth_date) = birth_date else {
+            continue;
+        };
+        tables.persons.push(Person {
+            person_id,
+            gender_concept_id: sex.map_or(0, gender_concept_id),
+            year_of_birth: birth_date.year(),
+            month_of_birth: Some(birth_date.month()),
+            day_of_birth: Some(birth_date.day()),
+            birth_datetime: None,
+            race_concept_id: 0,
+            ethnicity_concept_id: 0,
+            location_id: None,
+            provider_id: None,
+            care_site_id: None,
+            person_source_value: source_values[&person_id].to_string(),
+            gender_source_value: sex.map(|sex| sex.code().to_string()),
+            gender_source_concept_id: None,
+            race_source_value: None,
+            race_source_concept_id: None,
+            ethnicity_source_value: None,
+            ethnicity_source_concept_id: None,
+        });
+        tables.observation_periods.push(ObservationPeriod {
+            observation_period_id: person_id,
+            person_id,
+            observation_period_start_date: start.format("%Y-%m-%d").to_string(),
+            observation_period_end_date: end.format("%Y-%m-%d").to_string(),
+            period_type_concept_id: config.type_concept_id,
+        });
+    }
+    Ok(tables)
+}
+
+/// OMOP gender concept; sexes without a standard concept get 0
+fn gender_concept_id(sex: Sex) -> i64 {
+    match sex {
+        Sex::Female => 8532,
+        Sex::Male => 8507,
+        Sex::Other | Sex::Unknown => 0,
+    }
+}
+
+fn field_value(record: &PatientRecord, field: VitalField) -> Option<f64> {
+    let value = record.measurement(field)?;
+    match field {
+        // f32 widens to values such as 36.599998
+        VitalField::Temperature | VitalField::BloodSugar | VitalField::Weight | VitalField::Height => {
+            Some((value * 10.0).round() / 10.0)
+        }
+        _ => Some(value),
+    }
+}
+
+/// Write `person.csv`, `measurement.csv` and `observation_period.csv` into `dir`
+pub fn write_tables(records: &[PatientRecord], dir: &Path, config: &OmopConfig) -> Result<OmopTables, AktenError> {
+    let tables = records_to_tables(records, config)?;
+    fs::create_dir_all(dir)?;
+    write_csv(&dir.join(PERSON_FILE), &tables.persons)?;
+    write_csv(&dir.join(MEASUREMENT_FILE), &tables.measurements)?;
+    write_csv(&dir.join(OBSERVATION_PERIOD_FILE), &tables.observation_periods)?;
+    info!(
+        "OMOP CDM tables: {} persons, {} measurements",
+        tables.persons.len(),
+        tables.measurements.len()
+    );
+    Ok(tables)
+}
+
+fn write_csv<T: Serialize>(path: &Path, rows: &[T]) -> Result<(), AktenError> {
+    let mut writer = WriterBuilder::new().has_headers(true).from_path(path)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(patient_id: &str, timestamp: &str, blood_sugar: f32) -> PatientRecord {
+        PatientRecord {
+            patient_id: patient_id.to_string(),
+            timestamp: Timestamp::parse(timestamp).unwrap(),
+            heart_rate: Some(78),
+            bp_systolic: Some(120),
+            bp_diastolic: Some(80),
+            temperature: Some(36.6),
+            blood_sugar: Some(blood_sugar),
+            steps: Some(4500),
+            birth_date: NaiveDate::from_ymd_opt(1950, 6, 15),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tables_use_configured_concepts() {
+        let mut records = [record("3", "2024-12-01", 92.0), record("3", "2024-12-05", 88.0), record("4", "2024-12-02", 100.0)];
+        records[1].sex = Some(Sex::Female);
+        let tables = records_to_tables(&records, &OmopConfig::default()).unwrap();
+
+        assert_eq!(tables.persons.len(), 2);
+        let person = &tables.persons[0];
+        assert_eq!(
+            (person.gender_concept_id, person.year_of_birth, person.month_of_birth, person.day_of_birth),
+            (8532, 1950, Some(6), Some(15))
+        );
+        assert_eq!((tables.persons[1].gender_concept_id, tables.persons[1].year_of_birth), (0, 1950));
+        assert_eq!(tables.measurements.len(), 18);
+        let period = &tables.observation_periods[0];
+        assert_eq!(
+            (period.person_id, period.observation_period_start_date.as_str(), period.observation_period_end_date.as_str()),
+            (3, "2024-12-01", "2024-12-05")
+        );
+
+        let heart_rate = &tables.measurements[0];
+        assert_eq!((heart_rate.measurement_concept_id, heart_rate.unit_concept_id), (3027018, 8541));
+        let glucose_mg = &tables.measurements[4];
+        assert_eq!((glucose_mg.measurement_concept_id, glucose_mg.unit_concept_id), (3000483, 8840));
+        let glucose = &tables.measurements[10];
+        assert_eq!((glucose.unit_source_value.as_str(), glucose.value_as_number), ("mg/dL", 88.0));
+        let steps = &tables.measurements[5];
+        assert_eq!((steps.measurement_concept_id, steps.measurement_source_value.as_str()), (0, "55423-8"));
+
+        let mut measured = record("5", "2024-12-03", 92.0);
+        (measured.weight, measured.height) = (Some(70.0), Some(175.0));
+        let tables = records_to_tables(&[measured], &OmopConfig::default()).unwrap();
+        let bmi = tables.measurements.last().unwrap();
+        assert_eq!((bmi.measurement_concept_id, bmi.unit_concept_id, bmi.value_as_number), (3038553, 9531, 22.9));
+    }
+
+    #[test]
+    fn test_patients_without_birth_date_are_reported() {
+        let mut records = [record("3", "2024-12-01", 92.0), record("4", "2024-12-02", 100.0), record("KH-7", "2024-12-02", 100.0)];
+        records[1].birth_date = None;
+        records[2].birth_date = None;
+
+        let error = records_to_tables(&records, &OmopConfig::default()).unwrap_err().to_string();
+        assert!(error.contains("patients 4, KH-7 "), "{}", error);
+        assert!(!error.contains(" 3,"), "{}", error);
+    }
+
+    #[test]
+    fn test_surrogate_keys_are_stable_and_unique() {
+        let records = [record("1", "2024-12-01", 92.0), record("1", "2024-12-02", 92.0), record("2", "2024-12-01", 92.0)];
+        let first = records_to_tables(&records, &OmopConfig::default()).unwrap();
+        let second = records_to_tables(&records[1..], &OmopConfig::default()).unwrap();
+
+        let ids: BTreeSet<i64> = first.measurements.iter().map(|m| m.measurement_id).collect();
+        assert_eq!(ids.len(), first.measurements.len());
+        assert_eq!(first.measurements[6].measurement_id, second.measurements[0].measurement_id);
+        assert_eq!(first.measurements[12].person_id, 2);
+
+        let duplicated = records_to_tables(&[records[0].clone(), records[0].clone()], &OmopConfig::default()).unwrap();
+        assert_eq!(duplicated.measurements.len(), 6);
+
+        let readings = [record("1", "2024-12-01T08:30:00+01:00", 92.0), record("1", "2024-12-01T14:30:00+01:00", 92.0)];
+        let timed = records_to_tables(&readings, &OmopConfig::default()).unwrap();
+        assert_eq!(timed.measurements.len(), 12);
+        assert_eq!(timed.measurements[6].measurement_datetime.as_deref(), Some("2024-12-01 14:30:00"));
+        assert_eq!(timed.measurements[6].measurement_time.as_deref(), Some("14:30:00"));
+        assert_eq!(timed.observation_periods[0].observation_period_start_date, "2024-12-01");
+    }
+}
@@ -0,0 +1,428 @@
+use crate::config::OpenEhrConfig;
+use crate::{AktenError, PatientRecord};
+use crate::timestamp::Timestamp;
+use chrono::SecondsFormat;
+use serde_json::{json, Map, Value};
+
+/// Reference model release the compositions are written against
+const RM_VERSION: &str = "1.0.4";
+const COMPOSITION_ARCHETYPE: &str = "openEHR-EHR-COMPOSITION.encounter.v1";
+const PULSE_ARCHETYPE: &str = "openEHR-EHR-OBSERVATION.pulse.v2";
+const BLOOD_PRESSURE_ARCHETYPE: &str = "openEHR-EHR-OBSERVATION.blood_pressure.v2";
+const BODY_TEMPERATURE_ARCHETYPE: &str = "openEHR-EHR-OBSERVATION.body_temperature.v2";
+const RESPIRATION_ARCHETYPE: &str = "openEHR-EHR-OBSERVATION.respiration.v2";
+const PULSE_OXIMETRY_ARCHETYPE: &str = "openEHR-EHR-OBSERVATION.pulse_oximetry.v1";
+const BODY_WEIGHT_ARCHETYPE: &str = "openEHR-EHR-OBSERVATION.body_weight.v2";
+const HEIGHT_ARCHETYPE: &str = "openEHR-EHR-OBSERVATION.height.v2";
+const BMI_ARCHETYPE: &str = "openEHR-EHR-OBSERVATION.body_mass_index.v2";
+
+/// openEHR terminology codes for the composition category and context setting
+const CATEGORY_EVENT: (&str, &str) = ("433", "event");
+const SETTING_OTHER_CARE: (&str, &str) = ("238", "other care");
+
+/// Serialisation of exported compositions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompositionFormat {
+    /// Canonical JSON of the reference model
+    #[default]
+    Canonical,
+    /// Simplified FLAT format keyed by web template paths
+    Flat,
+}
+
+/// One composition per record with pulse, blood pressure, body temperature,
+/// respiration, pulse oximetry, weight, height and BMI observations; blood
+/// sugar, steps, AVPU and the oxygen flag have no counterpart in these archetypes
+pub fn records_to_compositions(
+    records: &[PatientRecord],
+    format: CompositionFormat,
+    config: &OpenEhrConfig,
+) -> Result<Vec<Value>, AktenError> {
+    if config.template_id.trim().is_empty() {
+        return Err(AktenError::OpenEhr("template_id must not be empty".to_string()));
+    }
+    Ok(records
+        .iter()
+        .map(|record| match format {
+            CompositionFormat::Canonical => canonical_composition(record, config),
+            CompositionFormat::Flat => flat_composition(record, config),
+        })
+        .collect())
+}
+
+/// DV_DATE_TIME needs a time; date-only measurements are placed at the
+/// start of their day in the configured time zone
+fn date_time(timestamp: &Timestamp) -> String {
+    timestamp.instant().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn text(value: &str) -> Value {
+    json!({"_type": "DV_TEXT", "value": value})
+}
+
+fn code_phrase(terminology: &str, code: &str) -> Value {
+    json!({
+        "_type": "CODE_PHRASE",
+        "terminology_id": {"_type": "TERMINOLOGY_ID", "value": terminology},
+        "code_string": code
+    })
+}
+
+fn coded_text((code, value): (&str, &str)) -> Value {
+    json!({
+        "_type": "DV_CODED_TEXT",
+        "value": value,
+        "defining_code": code_phrase("openehr", code)
+    })
+}
+
+fn archetype_details(archetype_id: &str, template_id: Option<&str>) -> Value {
+    let mut details = json!({
+        "_type": "ARCHETYPED",
+        "archetype_id": {"_type": "ARCHETYPE_ID", "value": archetype_id},
+        "rm_version": RM_VERSION
+    });
+    if let Some(template_id) = template_id {
+        details["template_id"] = json!({"_type": "TEMPLATE_ID", "value": template_id});
+    }
+    details
+}
+
+fn quantity(node_id: &str, name: &str, magnitude: f64, units: &str) -> Value {
+    json!({
+        "_type": "ELEMENT",
+        "name": text(name),
+        "archetype_node_id": node_id,
+        "value": {"_type": "DV_QUANTITY", "magnitude": magnitude, "units": units}
+    })
+}
+
+/// SpO2 is a percent proportion (type 2) in the pulse oximetry archetype
+fn percent(node_id: &str, name: &str, numerator: f64) -> Value {
+    json!({
+        "_type": "ELEMENT",
+        "name": text(name),
+        "archetype_node_id": node_id,
+        "value": {"_type": "DV_PROPORTION", "numerator": numerator, "denominator": 100.0, "type": 2}
+    })
+}
+
+/// OBSERVATION with a single point event; node ids differ between archetypes
+struct ObservationNodes {
+    archetype_id: &'static str,
+    name: &'static str,
+    history: &'static str,
+    event: &'static str,
+    event_name: &'static str,
+    tree: &'static str,
+}
+
+const PULSE: ObservationNodes = ObservationNodes {
+    archetype_id: PULSE_ARCHETYPE,
+    name: "Pulse/Heart beat",
+    history: "at0002",
+    event: "at0003",
+    event_name: "Any event",
+    tree: "at0001",
+};
+
+const BLOOD_PRESSURE: ObservationNodes = ObservationNodes {
+    archetype_id: BLOOD_PRESSURE_ARCHETYPE,
+    name: "Blood pressure",
+    history: "at0001",
+    event: "at0006",
+    event_name: "Any event",
+    tree: "at0003",
+};
+
+const BODY_TEMPERATURE: ObservationNodes = ObservationNodes {
+    archetype_id: BODY_TEMPERATURE_ARCHETYPE,
+    name: "Body temperature",
+    history: "at0002",
+    event: "at0003",
+    event_name: "Any event",
+    tree: "at0001",
+};
+
+const RESPIRATION: ObservationNodes = ObservationNodes {
+    archetype_id: RESPIRATION_ARCHETYPE,
+    name: "Respiration",
+    history: "at0001",
+    event: "at0002",
+    event_name: "Any event",
+    tree: "at0003",
+};
+
+const PULSE_OXIMETRY: ObservationNodes = ObservationNodes {
+    archetype_id: PULSE_OXIMETRY_ARCHETYPE,
+    name: "Pulse oximetry",
+    history: "at0001",
+    event: "at0002",
+    event_name: "Any event",
+    tree: "at0003",
+};
+
+const BODY_WEIGHT: ObservationNodes = ObservationNodes {
+    archetype_id: BODY_WEIGHT_ARCHETYPE,
+    name: "Body weight",
+    history: "at0002",
+    event: "at0003",
+    event_name: "Any event",
+    tree: "at0001",
+};
+
+const HEIGHT: ObservationNodes = ObservationNodes {
+    archetype_id: HEIGHT_ARCHETYPE,
+    name: "Height/Length",
+    history: "at0001",
+    event: "at0002",
+    event_name: "Any event",
+    tree: "at0003",
+};
+
+const BODY_MASS_INDEX: ObservationNodes = ObservationNodes {
+    archetype_id: BMI_ARCHETYPE,
+    name: "Body mass index",
+    history: "at0001",
+    event: "at0002",
+    event_name: "Any event",
+    tree: "at0003",
+};
+
+fn observation(nodes: &ObservationNodes, record: &PatientRecord, config: &OpenEhrConfig, items: Vec<Value>) -> Value {
+    let time = json!({"_type": "DV_DATE_TIME", "value": date_time(&record.timestamp)});
+    json!({
+        "_type": "OBSERVATION",
+        "name": text(nodes.name),
+        "archetype_node_id": nodes.archetype_id,
+        "archetype_details": archetype_details(nodes.archetype_id, None),
+        "language": code_phrase("ISO_639-1", &config.language),
+        "encoding": code_phrase("IANA_character-sets", "UTF-8"),
+        "subject": subject(record, config),
+        "data": {
+            "_type": "HISTORY",
+            "name": text("History"),
+            "archetype_node_id": nodes.history,
+            "origin": time,
+            "events": [{
+                "_type": "POINT_EVENT",
+                "name": text(nodes.event_name),
+                "archetype_node_id": nodes.event,
+                "time": time,
+                "data": {
+                    "_type": "ITEM_TREE",
+                    "name": text("Tree"),
+                    "archetype_node_id": nodes.tree,
+                    "items": items
+                }
+            }]
+        }
+    })
+}
+
+/// The patient, referenced through the demographic identifier namespace
+fn subject(record: &PatientRecord, config: &OpenEhrConfig) -> Value {
+    json!({
+        "_type": "PARTY_SELF",
+        "external_ref": {
+            "_type": "PARTY_REF",
+            "id": {"_type": "GENERIC_ID", "value": record.patient_id.to_string(), "scheme": config.subject_namespace},
+            "namespace": config.subject_namespace,
+            "type": "PERSON"
+        }
+    })
+}
+
+fn canonical_composition(record: &PatientRecord, config: &OpenEhrConfig) -> Value {
+    let start_time = date_time(&record.timestamp);
+    let mut context = json!({
+        "_type": "EVENT_CONTEXT",
+        "start_time": {"_type": "DV_DATE_TIME", "value": start_time},
+        "setting": coded_text(SETTING_OTHER_CARE)
+    });
+    if let Some(facility) = &config.health_care_facility {
+        context["health_care_facility"] = json!({"_type": "PARTY_IDENTIFIED", "name": facility});
+    }
+
+    // Unrecorded values are left out, and so is an OBSERVATION without any
+    let observations = [
+        (&PULSE, vec![record.heart_rate.map(|v| quantity("at0004", "Rate", f64::from(v), "/min"))]),
+        (&BLOOD_PRESSURE, vec![
+            record.bp_systolic.map(|v| quantity("at0004", "Systolic", f64::from(v), "mm[Hg]")),
+            record.bp_diastolic.map(|v| quantity("at0005", "Diastolic", f64::from(v), "mm[Hg]")),
+        ]),
+        (&BODY_TEMPERATURE, vec![record.temperature.map(|v| quantity("at0004", "Temperature", round_tenths(v), "Cel"))]),
+        (&RESPIRATION, vec![record.respiratory_rate.map(|v| quantity("at0004", "Rate", f64::from(v), "/min"))]),
+        (&PULSE_OXIMETRY, vec![record.spo2.map(|v| percent("at0006", "SpO₂", f64::from(v)))]),
+        (&BODY_WEIGHT, vec![record.weight.map(|v| quantity("at0004", "Weight", round_tenths(v), "kg"))]),
+        (&HEIGHT, vec![record.height.map(|v| quantity("at0004", "Height/Length", round_tenths(v), "cm"))]),
+        (&BODY_MASS_INDEX, vec![record.bmi().map(|v| quantity("at0004", "Body mass index", v, "kg/m2"))]),
+    ];
+    let content: Vec<Value> = observations
+        .into_iter()
+        .filter_map(|(nodes, items)| {
+            let items: Vec<Value> = items.into_iter().flatten().collect();
+            (!items.is_empty()).then(|| observation(nodes, record, config, items))
+        })
+        .collect();
+
+    json!({
+        "_type": "COMPOSITION",
+        "name": text("Vital signs"),
+        "archetype_node_id": COMPOSITION_ARCHETYPE,
+        "archetype_details": archetype_details(COMPOSITION_ARCHETYPE, Some(&config.template_id)),
+        "language": code_phrase("ISO_639-1", &config.language),
+        "territory": code_phrase("ISO_3166-1", &config.territory),
+        "category": coded_text(CATEGORY_EVENT),
+        "composer": {"_type": "PARTY_IDENTIFIED", "name": config.composer},
+        "context": context,
+        "content": content,
+    })
+}
+
+/// FLAT paths start with the template id in web template form, e.g.
+/// "AktenAkrobat Vital Signs" becomes "aktenakrobat_vital_signs"
+pub fn flat_prefix(template_id: &str) -> String {
+    let mut prefix = String::with_capacity(template_id.len());
+    for c in template_id.trim().chars() {
+        if c.is_ascii_alphanumeric() {
+            prefix.push(c.to_ascii_lowercase());
+        } else if !prefix.ends_with('_') {
+            prefix.push('_');
+        }
+    }
+    prefix.trim_matches('_').to_string()
+}
+
+fn flat_composition(record: &PatientRecord, config: &OpenEhrConfig) -> Value {
+    let prefix = flat_prefix(&config.template_id);
+    let time = date_time(&record.timestamp);
+    let mut flat = Map::new();
+    let mut put = |path: &str, value: Value| {
+        flat.insert(format!("{}/{}", prefix, path), value);
+    };
+
+    put("language|code", json!(config.language));
+    put("language|terminology", json!("ISO_639-1"));
+    put("territory|code", json!(config.territory));
+    put("territory|terminology", json!("ISO_3166-1"));
+    put("category|code", json!(CATEGORY_EVENT.0));
+    put("category|value", json!(CATEGORY_EVENT.1));
+    put("category|terminology", json!("openehr"));
+    put("composer|name", json!(config.composer));
+    put("context/start_time", json!(time));
+    put("context/setting|code", json!(SETTING_OTHER_CARE.0));
+    put("context/setting|value", json!(SETTING_OTHER_CARE.1));
+    put("context/setting|terminology", json!("openehr"));
+    if let Some(facility) = &config.health_care_facility {
+        put("context/_health_care_facility|name", json!(facility));
+    }
+
+    let quantity = |magnitude: f64, unit: &str| vec![("magnitude", json!(magnitude)), ("unit", json!(unit))];
+    for (observation, element, attributes) in [
+        ("pulse", "rate", record.heart_rate.map(|v| quantity(f64::from(v), "/min"))),
+        ("blood_pressure", "systolic", record.bp_systolic.map(|v| quantity(f64::from(v), "mm[Hg]"))),
+        ("blood_pressure", "diastolic", record.bp_diastolic.map(|v| quantity(f64::from(v), "mm[Hg]"))),
+        ("body_temperature", "temperature", record.temperature.map(|v| quantity(round_tenths(v), "Cel"))),
+        ("respiration", "rate", record.respiratory_rate.map(|v| quantity(f64::from(v), "/min"))),
+        (
+            "pulse_oximetry",
+            "spo2",
+            record.spo2.map(|v| {
+                vec![("numerator", json!(f64::from(v))), ("denominator", json!(100.0)), ("type", json!(2))]
+            }),
+        ),
+        ("body_weight", "weight", record.weight.map(|v| quantity(round_tenths(v), "kg"))),
+        ("height", "height_length", record.height.map(|v| quantity(round_tenths(v), "cm"))),
+        ("body_mass_index", "body_mass_index", record.bmi().map(|v| quantity(v, "kg/m2"))),
+    ] {
+        let Some(attributes) = attributes else {
+            continue;
+        };
+        let event = format!("{}:0/any_event:0", observation);
+        put(&format!("{}/time", event), json!(time));
+        for (attribute, value) in attributes {
+            put(&format!("{}/{}|{}", event, element, attribute), value);
+        }
+        put(&format!("{}:0/language|code", observation), json!(config.language));
+        put(&format!("{}:0/language|terminology", observation), json!("ISO_639-1"));
+        put(&format!("{}:0/encoding|code", observation), json!("UTF-8"));
+        put(&format!("{}:0/encoding|terminology", observation), json!("IANA_character-sets"));
+    }
+    // `ctx/` entries are not template paths and carry no prefix
+    flat.insert("ctx/subject|id".to_string(), json!(record.patient_id.to_string()));
+    flat.insert("ctx/subject|id_namespace".to_string(), json!(config.subject_namespace));
+    Value::Object(flat)
+}
+
+/// f32 values widen to values such as 36.599998
+fn round_tenths(value: f32) -> f64 {
+    (f64::from(value) * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_record() -> PatientRecord {
+        PatientRecord {
+            patient_id: "7".to_string(),
+            timestamp: Timestamp::parse("2024-12-01").unwrap(),
+            heart_rate: Some(78),
+            bp_systolic: Some(120),
+            bp_diastolic: Some(80),
+            temperature: Some(36.6),
+            blood_sugar: Some(92.0),
+            steps: Some(4500),
+            ..Default::default()
+        }
+    }
+
+    fn config() -> OpenEhrConfig {
+        OpenEhrConfig {
+            template_id: "AktenAkrobat Vital Signs".to_string(),
+            composer: "Station 3".to_string(),
+            health_care_facility: Some("Klinikum Nord".to_string()),
+            ..OpenEhrConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_canonical_composition() {
+        let compositions = records_to_compositions(&[sample_record()], CompositionFormat::Canonical, &config()).unwrap();
+        let composition = &compositions[0];
+
+        assert_eq!(composition["archetype_details"]["template_id"]["value"], "AktenAkrobat Vital Signs");
+        assert_eq!(composition["composer"]["name"], "Station 3");
+        assert_eq!(composition["context"]["start_time"]["value"], "2024-12-01T00:00:00+01:00");
+        assert_eq!(composition["context"]["health_care_facility"]["name"], "Klinikum Nord");
+
+        let content = composition["content"].as_array().unwrap();
+        let archetypes: Vec<&str> = content.iter().map(|o| o["archetype_node_id"].as_str().unwrap()).collect();
+        assert_eq!(archetypes, [PULSE_ARCHETYPE, BLOOD_PRESSURE_ARCHETYPE, BODY_TEMPERATURE_ARCHETYPE]);
+
+        let bp_items = &content[1]["data"]["events"][0]["data"]["items"];
+        assert_eq!(bp_items[0]["archetype_node_id"], "at0004");
+        assert_eq!(bp_items[1]["value"]["magnitude"], 80.0);
+        assert_eq!(content[2]["data"]["events"][0]["data"]["items"][0]["value"]["magnitude"], 36.6);
+        assert_eq!(content[0]["subject"]["external_ref"]["id"]["value"], "7");
+    }
+
+    #[test]
+    fn test_flat_composition() {
+        let compositions = records_to_compositions(&[sample_record()], CompositionFormat::Flat, &config()).unwrap();
+        let flat = &compositions[0];
+
+        assert_eq!(flat["aktenakrobat_vital_signs/composer|name"], "Station 3");
+        assert_eq!(flat["aktenakrobat_vital_signs/pulse:0/any_event:0/rate|magnitude"], 78.0);
+        assert_eq!(flat["aktenakrobat_vital_signs/blood_pressure:0/any_event:0/systolic|unit"], "mm[Hg]");
+        assert_eq!(flat["aktenakrobat_vital_signs/context/_health_care_facility|name"], "Klinikum Nord");
+        assert_eq!(flat["ctx/subject|id"], "7");
+
+        let empty = OpenEhrConfig {
+            template_id: " ".to_string(),
+            ..OpenEhrConfig::default()
+        };
+        assert!(records_to_compositions(&[sample_record()], CompositionFormat::Flat, &empty).is_err());
+    }
+}
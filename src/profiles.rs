@@ -0,0 +1,479 @@
+use crate::identity::KVNR_SYSTEM;
+use crate::AktenError;
+use serde_json::{json, Value};
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// StructureDefinition snapshots of the German profiles, trimmed to the
+/// elements the conformance check evaluates
+const DE_SNAPSHOTS: &str = include_str!("../profiles/de-profile-snapshots.json");
+
+const KBV_BASE: &str = "https://fhir.kbv.de/StructureDefinition/";
+const ISIK_VITALS: &str = "https://gematik.de/fhir/isik/v3/VitalparameterUndKoerpermasze/StructureDefinition/";
+
+/// CodeSystem of the German `Identifier.type` codes, such as `KVZ10` for the
+/// KVNR; the KVNR identifier system itself is `identity::KVNR_SYSTEM`
+const IDENTIFIER_TYPE_DE_SYSTEM: &str = "http://fhir.de/CodeSystem/identifier-type-de-basis";
+const IDENTIFIER_TYPE_V2_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v2-0203";
+
+/// Violations listed in the export error before the rest are summarised
+const MAX_REPORTED_VIOLATIONS: usize = 10;
+
+/// Profile set applied to FHIR output
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum FhirProfile {
+    /// Plain FHIR R4 core resources
+    #[default]
+    Core,
+    /// KBV Basis and ISiK Vitalparameter profiles with German display texts
+    German,
+}
+
+impl FhirProfile {
+    pub fn parse(value: &str) -> Result<Self, AktenError> {
+        match value.to_lowercase().as_str() {
+            "core" => Ok(Self::Core),
+            "de" | "isik" | "kbv" => Ok(Self::German),
+            other => Err(AktenError::Fhir(format!(
+                "unsupported FHIR profile '{}' (expected core or de)",
+                other
+            ))),
+        }
+    }
+
+    /// Adapt a resource built by the core export to the profile
+    pub fn apply(&self, resource: &mut Value) {
+        if *self != Self::German {
+            return;
+        }
+        match resource["resourceType"].as_str() {
+            Some("Patient") => german_patient(resource),
+            Some("Observation") => german_observation(resource),
+            _ => {}
+        }
+    }
+}
+
+/// Types the identifiers by system: the KVNR as such, any other as the
+/// local patient number
+fn german_patient(patient: &mut Value) {
+    patient["meta"] = json!({ "profile": [format!("{}KBV_PR_Base_Patient", KBV_BASE)] });
+    let Some(identifiers) = patient.get_mut("identifier").and_then(Value::as_array_mut) else {
+        return;
+    };
+    for identifier in identifiers {
+        identifier["type"] = if identifier["system"] == KVNR_SYSTEM {
+            json!({
+                "coding": [{"system": IDENTIFIER_TYPE_DE_SYSTEM, "code": "KVZ10"}],
+                "text": "Krankenversichertennummer",
+            })
+        } else {
+            json!({
+                "coding": [{"system": IDENTIFIER_TYPE_V2_SYSTEM, "code": "MR", "display": "Medical record number"}],
+                "text": "Patientennummer",
+            })
+        };
+    }
+}
+
+fn german_observation(observation: &mut Value) {
+    let code = observation["code"]["coding"][0]["code"].as_str().unwrap_or_default();
+    let respiration = code == "9279-1";
+    let (profiles, text): (Vec<String>, &str) = match code {
+        "8867-4" => (
+            vec![
+                format!("{}KBV_PR_Base_Observation_Heart_Rate", KBV_BASE),
+                format!("{}ISiKHerzfrequenz", ISIK_VITALS),
+            ],
+            "Herzfrequenz",
+        ),
+        "85354-9" => (
+            vec![
+                format!("{}KBV_PR_Base_Observation_Blood_Pressure", KBV_BASE),
+                format!("{}ISiKBlutdruckSystemischArteriell", ISIK_VITALS),
+            ],
+            "Blutdruck",
+        ),
+        "8310-5" => (
+            vec![
+                format!("{}KBV_PR_Base_Observation_Body_Temperature", KBV_BASE),
+                format!("{}ISiKKoerperkerntemperatur", ISIK_VITALS),
+            ],
+            "Körpertemperatur",
+        ),
+        "2339-0" | "15074-8" => (
+            vec![format!("{}KBV_PR_Base_Observation_Glucose_Concentration", KBV_BASE)],
+            "Glukose im Blut",
+        ),
+        // No German profile covers activity data
+        "55423-8" => (vec![], "Schrittzahl"),
+        // Extended vitals get German texts only; their KBV/ISiK profiles are not bundled
+        "59408-5" | "2708-6" => (vec![], "Sauerstoffsättigung"),
+        "9279-1" => (vec![], "Atemfrequenz"),
+        "29463-7" => (vec![], "Körpergewicht"),
+        "8302-2" => (vec![], "Körpergröße"),
+        "39156-5" => (vec![], "Body-Mass-Index"),
+        "67775-7" => (vec![], "Bewusstseinslage"),
+        "371825009" => (vec![], "Sauerstoffgabe"),
+        _ => return,
+    };
+
+    if !profiles.is_empty() {
+        observation["meta"] = json!({ "profile": profiles });
+    }
+    observation["code"]["text"] = json!(text);
+    if let Some(category) = observation["category"][0].as_object_mut() {
+        let text = match category["coding"][0]["code"].as_str() {
+            Some("vital-signs") => "Vitalparameter",
+            Some("laboratory") => "Labor",
+            Some("survey") => "Erhebung",
+            _ => "Aktivität",
+        };
+        category.insert("text".to_string(), json!(text));
+    }
+    if let Some(quantity) = observation.get_mut("valueQuantity") {
+        german_unit(quantity, respiration);
+    }
+
+    if let Some(components) = observation.get_mut("component").and_then(Value::as_array_mut) {
+        for component in components {
+            let text = match component["code"]["coding"][0]["code"].as_str() {
+                Some("8480-6") => "Systolischer Blutdruck",
+                Some("8462-4") => "Diastolischer Blutdruck",
+                _ => continue,
+            };
+            component["code"]["text"] = json!(text);
+        }
+    }
+}
+
+/// German unit display; the UCUM code stays as is. `/min` counts breaths for
+/// the respiratory rate and beats otherwise
+fn german_unit(quantity: &mut Value, respiration: bool) {
+    let unit = match quantity["code"].as_str() {
+        Some("/min") if respiration => "Atemzüge/Minute",
+        Some("/min") => "Schläge/Minute",
+        Some("{steps}") => "Schritte",
+        _ => return,
+    };
+    quantity["unit"] = json!(unit);
+}
+
+/// Element constraints of one StructureDefinition snapshot
+#[derive(Debug)]
+struct ProfileElement {
+    id: String,
+    path: String,
+    min: usize,
+    slice_name: Option<String>,
+    /// `pattern[x]` or `fixed[x]` value the element must contain
+    required_value: Option<Value>,
+    /// Whether the required value is a pattern (slice discriminator) rather than fixed
+    is_pattern: bool,
+}
+
+#[derive(Debug)]
+struct Profile {
+    name: String,
+    elements: Vec<ProfileElement>,
+}
+
+fn bundled_profiles() -> &'static HashMap<String, Profile> {
+    static PROFILES: OnceLock<HashMap<String, Profile>> = OnceLock::new();
+    PROFILES.get_or_init(|| {
+        let bundle: Value = serde_json::from_str(DE_SNAPSHOTS).expect("bundled profile snapshots are valid JSON");
+        let mut profiles = HashMap::new();
+        for entry in bundle["entry"].as_array().into_iter().flatten() {
+            let definition = &entry["resource"];
+            let elements = definition["snapshot"]["element"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(parse_element)
+                .collect();
+            profiles.insert(
+                definition["url"].as_str().unwrap_or_default().to_string(),
+                Profile {
+                    name: definition["name"].as_str().unwrap_or_default().to_string(),
+                    elements,
+                },
+            );
+        }
+        profiles
+    })
+}
+
+fn parse_element(element: &Value) -> ProfileElement {
+    let rule = element.as_object().and_then(|fields| {
+        fields
+            .iter()
+            .find(|(key, _)| key.starts_with("pattern") || key.starts_with("fixed"))
+            .map(|(key, value)| (value.clone(), key.starts_with("pattern")))
+    });
+    ProfileElement {
+        id: element["id"].as_str().unwrap_or_default().to_string(),
+        path: element["path"].as_str().unwrap_or_default().to_string(),
+        min: element["min"].as_u64().unwrap_or_default() as usize,
+        slice_name: element["sliceName"].as_str().map(str::to_string),
+        is_pattern: rule.as_ref().is_some_and(|(_, is_pattern)| *is_pattern),
+        required_value: rule.map(|(value, _)| value),
+    }
+}
+
+/// Check a resource against every profile in its `meta.profile`
+pub fn check_conformance(resource: &Value) -> Vec<String> {
+    let label = format!(
+        "{}/{}",
+        resource["resourceType"].as_str().unwrap_or("Resource"),
+        resource["id"].as_str().unwrap_or("?")
+    );
+    let mut violations = vec![];
+    for url in resource["meta"]["profile"].as_array().into_iter().flatten() {
+        let url = url.as_str().unwrap_or_default();
+        let Some(profile) = bundled_profiles().get(url) else {
+            violations.push(format!("{}: no bundled StructureDefinition for {}", label, url));
+            continue;
+        };
+        let mut issues = vec![];
+        check_profile(resource, profile, &mut issues);
+        violations.extend(issues.into_iter().map(|issue| format!("{} ({}): {}", label, profile.name, issue)));
+    }
+    violations
+}
+
+/// Fail with the collected violations if any resource does not conform
+pub fn ensure_conformant<I, V>(resources: I) -> Result<(), AktenError>
+where
+    I: IntoIterator<Item = V>,
+    V: Borrow<Value>,
+{
+    let violations: Vec<String> = resources
+        .into_iter()
+        .flat_map(|resource| check_conformance(resource.borrow()))
+        .collect();
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = violations
+        .iter()
+        .take(MAX_REPORTED_VIOLATIONS)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("; ");
+    if violations.len() > MAX_REPORTED_VIOLATIONS {
+        message.push_str(&format!(" (and {} more)", violations.len() - MAX_REPORTED_VIOLATIONS));
+    }
+    Err(AktenError::Fhir(format!("profile conformance check failed: {}", message)))
+}
+
+fn check_profile(resource: &Value, profile: &Profile, issues: &mut Vec<String>) {
+    for element in &profile.elements {
+        if element.slice_name.is_some() {
+            check_slice(resource, profile, element, issues);
+        } else if !element.id.contains(':') {
+            // Element paths start with the resource type
+            if let Some((_, relative)) = element.path.split_once('.') {
+                check_path(resource, relative, element, issues);
+            }
+        }
+    }
+}
+
+/// Count the repetitions matching the slice's patterns and check the fixed
+/// values and cardinalities of its child elements on each of them
+fn check_slice(resource: &Value, profile: &Profile, slice: &ProfileElement, issues: &mut Vec<String>) {
+    let Some((_, relative)) = slice.path.split_once('.') else {
+        return;
+    };
+    let prefix = format!("{}.", slice.id);
+    let children: Vec<(&str, &ProfileElement)> = profile
+        .elements
+        .iter()
+        .filter_map(|element| element.id.strip_prefix(&prefix).map(|rest| (rest, element)))
+        .collect();
+
+    let items = values_at(resource, relative);
+    let matching: Vec<&Value> = items
+        .into_iter()
+        .filter(|item| slice.required_value.as_ref().is_none_or(|pattern| contains(item, pattern)))
+        .filter(|item| {
+            children
+                .iter()
+                .filter(|(_, child)| child.is_pattern)
+                .all(|(path, child)| {
+                    let pattern = child.required_value.as_ref().expect("pattern elements carry a value");
+                    values_at(item, path).iter().any(|value| contains(value, pattern))
+                })
+        })
+        .collect();
+
+    if matching.len() < slice.min {
+        issues.push(format!(
+            "{}: expected at least {} matching element(s), found {}",
+            slice.id,
+            slice.min,
+            matching.len()
+        ));
+    }
+    for item in matching {
+        for (path, child) in &children {
+            check_path(item, path, child, issues);
+        }
+    }
+}
+
+/// Cardinality and required value of the element at `relative` below `root`
+fn check_path(root: &Value, relative: &str, element: &ProfileElement, issues: &mut Vec<String>) {
+    let (parents, name) = match relative.rsplit_once('.') {
+        Some((parent, name)) => (values_at(root, parent), name),
+        None => (vec![root], relative),
+    };
+    for parent in parents {
+        let values = child_values(parent, name);
+        if values.len() < element.min {
+            issues.push(format!(
+                "{}: minimum cardinality {} not met",
+                element.id, element.min
+            ));
+        }
+        if let Some(required) = &element.required_value {
+            if !values.is_empty() && !values.iter().any(|value| contains(value, required)) {
+                issues.push(format!("{}: expected {}", element.id, required));
+            }
+        }
+    }
+}
+
+/// All values reached by a dotted element path, flattening repetitions
+fn values_at<'a>(root: &'a Value, path: &str) -> Vec<&'a Value> {
+    path.split('.').fold(vec![root], |values, name| {
+        values.into_iter().flat_map(|value| child_values(value, name)).collect()
+    })
+}
+
+fn child_values<'a>(value: &'a Value, name: &str) -> Vec<&'a Value> {
+    let Some(object) = value.as_object() else {
+        return vec![];
+    };
+    // Choice elements such as `value[x]` match `valueQuantity`, `valueString`, ...
+    let found: Vec<&Value> = match name.strip_suffix("[x]") {
+        Some(prefix) => object
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(_, value)| value)
+            .collect(),
+        None => object.get(name).into_iter().collect(),
+    };
+    found
+        .into_iter()
+        .flat_map(|value| match value {
+            Value::Array(items) => items.iter().collect(),
+            other => vec![other],
+        })
+        .collect()
+}
+
+/// FHIR pattern semantics: every property of `pattern` is present in `value`,
+/// and every repetition in `pattern` matches some repetition in `value`
+fn contains(value: &Value, pattern: &Value) -> bool {
+    match (value, pattern) {
+        (Value::Object(value), Value::Object(pattern)) => pattern
+            .iter()
+            .all(|(key, expected)| value.get(key).is_some_and(|actual| contains(actual, expected))),
+        (Value::Array(values), Value::Array(patterns)) => patterns
+            .iter()
+            .all(|expected| values.iter().any(|actual| contains(actual, expected))),
+        (value, pattern) => value == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn german() -> FhirProfile {
+        FhirProfile::parse("de").unwrap()
+    }
+
+    fn heart_rate() -> Value {
+        json!({
+            "resourceType": "Observation",
+            "id": "1-2024-12-01-8867-4",
+            "status": "final",
+            "category": [{"coding": [{"system": "http://terminology.hl7.org/CodeSystem/observation-category", "code": "vital-signs"}]}],
+            "code": {"coding": [{"system": "http://loinc.org", "code": "8867-4"}]},
+            "subject": {"reference": "Patient/1"},
+            "effectiveDateTime": "2024-12-01",
+            "valueQuantity": {"value": 78, "unit": "beats/minute", "system": "http://unitsofmeasure.org", "code": "/min"},
+        })
+    }
+
+    #[test]
+    fn test_german_profile_applies_and_conforms() {
+        let mut observation = heart_rate();
+        german().apply(&mut observation);
+
+        assert_eq!(observation["meta"]["profile"].as_array().unwrap().len(), 2);
+        assert_eq!(observation["code"]["text"], "Herzfrequenz");
+        assert_eq!(observation["valueQuantity"]["unit"], "Schläge/Minute");
+        assert!(check_conformance(&observation).is_empty());
+
+        let mut patient = json!({
+            "resourceType": "Patient",
+            "id": "1",
+            "identifier": [{"system": "https://klinik.example/sid/pid", "value": "1"}],
+        });
+        german().apply(&mut patient);
+        assert_eq!(patient["identifier"][0]["type"]["coding"][0]["code"], "MR");
+        assert!(check_conformance(&patient).is_empty());
+    }
+
+    #[test]
+    fn test_conformance_reports_violations() {
+        let mut observation = heart_rate();
+        german().apply(&mut observation);
+        observation["valueQuantity"]["code"] = json!("bpm");
+        observation.as_object_mut().unwrap().remove("effectiveDateTime");
+
+        let violations = check_conformance(&observation);
+        // Both profiles flag both problems
+        assert_eq!(violations.len(), 4, "{:?}", violations);
+        assert!(violations.iter().any(|v| v.contains("Observation.effective[x]")));
+        assert!(ensure_conformant([&observation]).is_err());
+    }
+
+    #[test]
+    fn test_kvnr_slice_checks_system() {
+        let patient = json!({
+            "resourceType": "Patient",
+            "id": "1",
+            "meta": {"profile": ["https://fhir.kbv.de/StructureDefinition/KBV_PR_Base_Patient"]},
+            "identifier": [{
+                "type": {"coding": [{"system": IDENTIFIER_TYPE_DE_SYSTEM, "code": "KVZ10"}]},
+                "system": "urn:wrong",
+                "value": "A123456789",
+            }],
+        });
+        let violations = check_conformance(&patient);
+        assert_eq!(violations.len(), 1, "{:?}", violations);
+        assert!(violations[0].contains(KVNR_SYSTEM));
+    }
+
+    #[test]
+    fn test_kvnr_identifier_is_typed() {
+        let mut patient = json!({
+            "resourceType": "Patient",
+            "id": "A123456789",
+            "identifier": [
+                {"system": KVNR_SYSTEM, "value": "A123456789"},
+                {"system": "https://klinik.example/sid/pid", "value": "4711"},
+            ],
+        });
+        german().apply(&mut patient);
+        assert_eq!(patient["identifier"][0]["type"]["coding"][0]["code"], "KVZ10");
+        assert_eq!(patient["identifier"][1]["type"]["coding"][0]["code"], "MR");
+        assert!(check_conformance(&patient).is_empty());
+    }
+}
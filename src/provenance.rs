@@ -0,0 +1,172 @@
+use crate::PatientRecord;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Name and version written as the tool that ingested a record
+pub const TOOL: &str = concat!("aktenakrobat ", env!("CARGO_PKG_VERSION"));
+
+/// Where a record was first read from. Kept through merges, so a merged
+/// file or store still names the original input; in CSV and JSON a single
+/// `;`-separated list such as
+/// `source=ward.csv;location=line 12;format=csv;ingested=2025-05-26T08:00:00Z;tool=aktenakrobat 0.2.0`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Provenance {
+    /// Path of the input file, directory or store
+    pub source: String,
+    /// Line, message or entry within the source, where the format tells
+    pub location: Option<String>,
+    /// Format the source was read as (`csv`, `json`, `fhir`, `hl7`, ...)
+    pub format: String,
+    pub ingested_at: DateTime<Utc>,
+    pub tool: String,
+}
+
+impl Provenance {
+    /// Provenance of records read now by this version of the tool
+    pub fn new(source: &str, format: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            location: None,
+            format: format.to_string(),
+            ingested_at: Utc::now(),
+            tool: TOOL.to_string(),
+        }
+    }
+
+    pub fn at(&self, location: String) -> Self {
+        Self { location: Some(location), ..self.clone() }
+    }
+
+    /// Source and location for messages, e.g. `ward.csv line 12`
+    pub fn origin(&self) -> String {
+        match &self.location {
+            Some(location) => format!("{} {}", self.source, location),
+            None => self.source.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "source={}", self.source)?;
+        if let Some(location) = &self.location {
+            write!(f, ";location={}", location)?;
+        }
+        write!(
+            f,
+            ";format={};ingested={};tool={}",
+            self.format,
+            self.ingested_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.tool
+        )
+    }
+}
+
+impl TryFrom<String> for Provenance {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut source = None;
+        let mut location = None;
+        let mut format = None;
+        let mut ingested_at = None;
+        let mut tool = None;
+        for part in value.split(';').filter(|part| !part.trim().is_empty()) {
+            let (key, text) = part
+                .split_once('=')
+                .ok_or_else(|| format!("provenance entry '{}' is not key=value", part))?;
+            let text = text.trim().to_string();
+            match key.trim() {
+                "source" => source = Some(text),
+                "location" => location = Some(text),
+                "format" => format = Some(text),
+                "ingested" => {
+                    let time = DateTime::parse_from_rfc3339(&text)
+                        .map_err(|_| format!("provenance ingestion time '{}' is not RFC 3339", text))?;
+                    ingested_at = Some(time.with_timezone(&Utc));
+                }
+                "tool" => tool = Some(text),
+                other => return Err(format!("unknown provenance entry '{}'", other)),
+            }
+        }
+        let missing = |name: &str| format!("provenance '{}' has no {}", value, name);
+        Ok(Self {
+            source: source.ok_or_else(|| missing("source"))?,
+            location,
+            format: format.ok_or_else(|| missing("format"))?,
+            ingested_at: ingested_at.ok_or_else(|| missing("ingested"))?,
+            tool: tool.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<Provenance> for String {
+    fn from(provenance: Provenance) -> Self {
+        provenance.to_string()
+    }
+}
+
+/// Give records without a provenance that of `source`; records read from a
+/// file that carried one keep theirs
+pub fn stamp(records: &mut [PatientRecord], source: &str, format: &str) {
+    let provenance = Provenance::new(source, format);
+    for record in records.iter_mut().filter(|r| r.provenance.is_none()) {
+        record.provenance = Some(provenance.clone());
+    }
+}
+
+/// `stamp` with the position of each record in the file, e.g. `record 3`
+pub fn stamp_numbered(records: &mut [PatientRecord], source: &str, format: &str, unit: &str) {
+    let provenance = Provenance::new(source, format);
+    for (index, record) in records.iter_mut().enumerate().filter(|(_, r)| r.provenance.is_none()) {
+        record.provenance = Some(provenance.at(format!("{} {}", unit, index + 1)));
+    }
+}
+
+/// Drop the provenance of all records, for outputs that should not carry it
+pub fn strip(records: &mut [PatientRecord]) {
+    for record in records {
+        record.provenance = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_io::{self, CsvOutput};
+
+    #[test]
+    fn test_provenance_text_round_trips() {
+        let provenance = Provenance::new("mock_data/ward 2.csv", "csv").at("line 12".to_string());
+        let text = provenance.to_string();
+        assert!(text.starts_with("source=mock_data/ward 2.csv;location=line 12;format=csv;ingested="), "{}", text);
+        let parsed = Provenance::try_from(text).unwrap();
+        assert_eq!((parsed.origin(), parsed.tool.as_str()), ("mock_data/ward 2.csv line 12".to_string(), TOOL));
+        assert_eq!(parsed.ingested_at.timestamp(), provenance.ingested_at.timestamp());
+
+        assert!(Provenance::try_from("source=a.csv;format=csv".to_string()).is_err());
+        assert!(Provenance::try_from("a.csv line 3".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_csv_rows_keep_their_original_source() {
+        let input = "patient_id,date,heart_rate\n1,2024-12-01,78\n2,2024-12-01,91\n";
+        let records = csv_io::read_records(input.as_bytes(), "ward.csv").unwrap();
+        let origins: Vec<String> = records.iter().filter_map(|r| r.provenance.as_ref()).map(Provenance::origin).collect();
+        assert_eq!(origins, ["ward.csv line 2", "ward.csv line 3"]);
+
+        // Written out, as by merge-files, and read back from the merged file
+        let mut merged = vec![];
+        csv_io::write_records(&mut merged, &records, &CsvOutput::default(), false).unwrap();
+        let mut reread = csv_io::read_records(merged.as_slice(), "merged.csv").unwrap();
+        let (original, kept) = (records[1].provenance.as_ref().unwrap(), reread[1].provenance.as_ref().unwrap());
+        assert_eq!((kept.origin(), kept.ingested_at.timestamp()), (original.origin(), original.ingested_at.timestamp()));
+
+        stamp(&mut reread, "merged.csv", "csv");
+        assert_eq!(reread[1].provenance.as_ref().map(Provenance::origin).as_deref(), Some("ward.csv line 3"));
+        strip(&mut reread);
+        assert!(reread.iter().all(|r| r.provenance.is_none()));
+    }
+}
@@ -1,5 +1,11 @@
-use crate::{AktenError, PatientRecord};
-use crate::config::ThresholdConfig;
+use crate::{display_value, AktenError, Consciousness, PatientRecord};
+use crate::config::{ObservationThreshold, ThresholdConfig};
+use crate::demographics::{self, Sex};
+use crate::fhir::{self, VitalField};
+use crate::identity::{self, Identifiers};
+use crate::observations::{self, Observations, ThresholdOutcome};
+use crate::provenance::Provenance;
+use crate::timestamp::Timestamp;
 use serde::Serialize;
 use std::fs::File;
 use std::io::Write;
@@ -7,14 +13,86 @@ use std::io::Write;
 /// Risk output structure for JSON export
 #[derive(Debug, Serialize)]
 pub struct RiskResult {
-    pub patient_id: u32,
-    pub date: String,
+    pub patient_id: String,
+    /// Identifier system of `patient_id`: the configured primary system, or
+    /// the local one for patients without that identifier
+    pub patient_id_system: String,
+    #[serde(skip_serializing_if = "Identifiers::is_empty")]
+    pub identifiers: Identifiers,
+    pub timestamp: Timestamp,
+    /// Age at measurement, if the birth date is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sex: Option<Sex>,
     pub risks: Vec<String>,
-    pub heart_rate: u32,
-    pub bp_systolic: u32,
-    pub bp_diastolic: u32,
-    pub temperature: f32,
-    pub blood_sugar: f32,
+    /// Rules that could not be evaluated because their inputs are missing
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub insufficient_data: Vec<String>,
+    pub heart_rate: Option<u32>,
+    pub bp_systolic: Option<u32>,
+    pub bp_diastolic: Option<u32>,
+    pub temperature: Option<f32>,
+    pub blood_sugar: Option<f32>,
+    pub spo2: Option<u32>,
+    pub respiratory_rate: Option<u32>,
+    pub consciousness: Option<Consciousness>,
+    pub bmi: Option<f64>,
+    #[serde(skip_serializing_if = "Observations::is_empty")]
+    pub observations: Observations,
+    /// Input the record came from, when read with `--provenance`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+/// Risks the rule set can flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RiskKind {
+    Tachycardia,
+    Bradycardia,
+    HypertensiveCrisis,
+    Fever,
+    Hyperglycemia,
+    Hypoglycemia,
+    Hypoxemia,
+    Tachypnea,
+    Bradypnea,
+    ReducedConsciousness,
+    Obesity,
+    Underweight,
+}
+
+impl RiskKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            // Reported together as one risk in the text and JSON outputs
+            Self::Tachycardia | Self::Bradycardia => "Abnormal heart rate",
+            Self::HypertensiveCrisis => "Hypertensive crisis",
+            Self::Fever => "Fever",
+            Self::Hyperglycemia => "Hyperglycemia",
+            Self::Hypoglycemia => "Hypoglycemia",
+            Self::Hypoxemia => "Hypoxemia",
+            Self::Tachypnea | Self::Bradypnea => "Abnormal respiratory rate",
+            Self::ReducedConsciousness => "Reduced consciousness",
+            Self::Obesity => "Obesity",
+            Self::Underweight => "Underweight",
+        }
+    }
+
+    /// Measurements the risk is derived from
+    pub fn basis(&self) -> &'static [VitalField] {
+        match self {
+            Self::Tachycardia | Self::Bradycardia => &[VitalField::HeartRate],
+            Self::HypertensiveCrisis => &[VitalField::BpSystolic, VitalField::BpDiastolic],
+            Self::Fever => &[VitalField::Temperature],
+            Self::Hyperglycemia | Self::Hypoglycemia => &[VitalField::BloodSugar],
+            Self::Hypoxemia => &[VitalField::Spo2],
+            Self::Tachypnea | Self::Bradypnea => &[VitalField::RespiratoryRate],
+            // AVPU is a coded value, not a measurement
+            Self::ReducedConsciousness => &[],
+            Self::Obesity | Self::Underweight => &[VitalField::Bmi],
+        }
+    }
 }
 
 /// Predict health risks from patient records
@@ -23,12 +101,18 @@ pub fn predict_risks(
     config: &ThresholdConfig,
 ) -> Result<(), AktenError> {
     let mut flagged = vec![];
+    let mut unassessed = vec![];
 
     for record in records {
-        let risks = detect_risks(record, &config.thresholds);
+        let mut risks = detect_risks(record, &config.thresholds);
+        risks.extend(observation_risks(record, &config.observations));
         if !risks.is_empty() {
             flagged.push((record, risks));
         }
+        let missing = insufficient_data(record);
+        if !missing.is_empty() {
+            unassessed.push((record, missing));
+        }
     }
 
     if flagged.is_empty() {
@@ -37,15 +121,31 @@ pub fn predict_risks(
         println!("⚠️ Risk Summary:");
         for (record, risks) in flagged {
             println!(
-                "Patient {} on {}: {:?} => HR: {}, BP: {}/{}, Temp: {:.1}°C, Sugar: {:.1}",
+                "Patient {} on {}: {:?} => HR: {}, RR: {}, SpO2: {}%, BP: {}/{}, Temp: {}°C, Sugar: {}",
                 record.patient_id,
-                record.date,
+                record.timestamp,
                 risks,
-                record.heart_rate,
-                record.bp_systolic,
-                record.bp_diastolic,
-                record.temperature,
-                record.blood_sugar
+                display_value(record.heart_rate, 0),
+                display_value(record.respiratory_rate, 0),
+                display_value(record.spo2, 0),
+                display_value(record.bp_systolic, 0),
+                display_value(record.bp_diastolic, 0),
+                display_value(record.temperature, 1),
+                display_value(record.blood_sugar, 1)
+            );
+            if let Some(provenance) = &record.provenance {
+                println!("   from {}", provenance.origin());
+            }
+        }
+    }
+    if !unassessed.is_empty() {
+        println!("❔ Insufficient data:");
+        for (record, missing) in unassessed {
+            println!(
+                "Patient {} on {}: no {} recorded, not assessed",
+                record.patient_id,
+                record.timestamp,
+                missing.join(", ")
             );
         }
     }
@@ -62,17 +162,30 @@ pub fn export_risks_as_json(
     let mut results = vec![];
 
     for record in records {
-        let risks = detect_risks(record, &config.thresholds);
-        if !risks.is_empty() {
+        let mut risks = detect_risks(record, &config.thresholds);
+        risks.extend(observation_risks(record, &config.observations));
+        let insufficient_data: Vec<String> = insufficient_data(record).into_iter().map(str::to_string).collect();
+        if !risks.is_empty() || !insufficient_data.is_empty() {
             results.push(RiskResult {
-                patient_id: record.patient_id,
-                date: record.date.clone(),
+                patient_id: record.patient_id.clone(),
+                patient_id_system: identity::patient_identifier(record, config.identity.primary_system()).system,
+                identifiers: record.identifiers.clone(),
+                timestamp: record.timestamp,
+                age: record.age(),
+                sex: record.sex,
                 risks,
+                insufficient_data,
                 heart_rate: record.heart_rate,
                 bp_systolic: record.bp_systolic,
                 bp_diastolic: record.bp_diastolic,
                 temperature: record.temperature,
                 blood_sugar: record.blood_sugar,
+                spo2: record.spo2,
+                respiratory_rate: record.respiratory_rate,
+                consciousness: record.consciousness,
+                bmi: record.bmi(),
+                observations: record.observations.clone(),
+                provenance: record.provenance.clone(),
             });
         }
     }
@@ -84,35 +197,127 @@ pub fn export_risks_as_json(
     Ok(())
 }
 
+/// Export risk predictions as a Bundle of FHIR RiskAssessments
+pub fn export_risks_as_fhir(
+    records: &[PatientRecord],
+    config: &ThresholdConfig,
+    output_path: &str,
+) -> Result<(), AktenError> {
+    let flagged: Vec<(&PatientRecord, usize, Vec<RiskKind>, Vec<String>)> = records
+        .iter()
+        .zip(fhir::occurrences(records))
+        .map(|(record, occurrence)| {
+            let risks = detect_risk_kinds(record, &config.thresholds);
+            (record, occurrence, risks, observation_risks(record, &config.observations))
+        })
+        .filter(|(_, _, risks, observation_risks)| !risks.is_empty() || !observation_risks.is_empty())
+        .collect();
+
+    let bundle = fhir::risks_to_bundle(&flagged);
+    serde_json::to_writer_pretty(File::create(output_path)?, &bundle)?;
+
+    println!("✅ Exported {} FHIR risk assessments to {}", flagged.len(), output_path);
+    Ok(())
+}
+
 /// Core risk detection logic
 fn detect_risks(record: &PatientRecord, thresholds: &crate::config::Thresholds) -> Vec<String> {
+    detect_risk_kinds(record, thresholds)
+        .iter()
+        .map(|risk| risk.label().to_string())
+        .collect()
+}
+
+/// Labels of the `[[observations]]` thresholds `record` is outside of;
+/// observations that were not recorded or are not numbers are not assessed
+fn observation_risks(record: &PatientRecord, thresholds: &[ObservationThreshold]) -> Vec<String> {
+    observations::check(record, thresholds)
+        .into_iter()
+        .filter_map(|outcome| match outcome {
+            ThresholdOutcome::Exceeded(threshold, _) => Some(threshold.label.clone()),
+            ThresholdOutcome::NotANumber(..) => None,
+        })
+        .collect()
+}
+
+/// Rules skip measurements that were not recorded; blood pressure is
+/// assessed on whichever of the two values is present. The BMI categories
+/// are for adults, so they are not applied to children
+fn detect_risk_kinds(record: &PatientRecord, thresholds: &crate::config::Thresholds) -> Vec<RiskKind> {
     let mut risks = vec![];
 
-    if record.heart_rate < thresholds.heart_rate.min 
-        || record.heart_rate > thresholds.heart_rate.max {
-        risks.push("Abnormal heart rate".to_string());
+    if let Some(heart_rate) = record.heart_rate {
+        if heart_rate < thresholds.heart_rate.min {
+            risks.push(RiskKind::Bradycardia);
+        } else if heart_rate > thresholds.heart_rate.max {
+            risks.push(RiskKind::Tachycardia);
+        }
+    }
+    if record.bp_systolic.is_some_and(|v| v >= thresholds.blood_pressure.systolic)
+        || record.bp_diastolic.is_some_and(|v| v >= thresholds.blood_pressure.diastolic) {
+        risks.push(RiskKind::HypertensiveCrisis);
+    }
+    if record.temperature.is_some_and(|v| v > thresholds.fever) {
+        risks.push(RiskKind::Fever);
     }
-    if record.bp_systolic >= thresholds.blood_pressure.systolic
-        || record.bp_diastolic >= thresholds.blood_pressure.diastolic {
-        risks.push("Hypertensive crisis".to_string());
+    if let Some(blood_sugar) = record.blood_sugar {
+        if blood_sugar > thresholds.hyperglycemia {
+            risks.push(RiskKind::Hyperglycemia);
+        }
+        if blood_sugar < thresholds.hypoglycemia {
+            risks.push(RiskKind::Hypoglycemia);
+        }
     }
-    if record.temperature > thresholds.fever {
-        risks.push("Fever".to_string());
+    if record.spo2.is_some_and(|v| v < thresholds.hypoxemia) {
+        risks.push(RiskKind::Hypoxemia);
+    }
+    if let Some(rate) = record.respiratory_rate {
+        if rate < thresholds.respiratory_rate.min {
+            risks.push(RiskKind::Bradypnea);
+        } else if rate > thresholds.respiratory_rate.max {
+            risks.push(RiskKind::Tachypnea);
+        }
     }
-    if record.blood_sugar > thresholds.hyperglycemia {
-        risks.push("Hyperglycemia".to_string());
+    if record.consciousness.is_some_and(|level| level != Consciousness::Alert) {
+        risks.push(RiskKind::ReducedConsciousness);
     }
-    if record.blood_sugar < thresholds.hypoglycemia {
-        risks.push("Hypoglycemia".to_string());
+    if let Some(bmi) = record.bmi().filter(|_| demographics::is_adult(record)) {
+        if bmi >= f64::from(thresholds.obesity_bmi) {
+            risks.push(RiskKind::Obesity);
+        } else if bmi < f64::from(thresholds.underweight_bmi) {
+            risks.push(RiskKind::Underweight);
+        }
     }
 
     risks
 }
 
+/// Core measurements whose rules could not run for `record`; the extended
+/// vitals (SpO2, respiratory rate, AVPU, BMI) are assessed when recorded but
+/// not reported as missing, since most sources do not carry them
+pub fn insufficient_data(record: &PatientRecord) -> Vec<&'static str> {
+    let mut missing = vec![];
+    if record.heart_rate.is_none() {
+        missing.push("heart rate");
+    }
+    if record.bp_systolic.is_none() && record.bp_diastolic.is_none() {
+        missing.push("blood pressure");
+    }
+    if record.temperature.is_none() {
+        missing.push("temperature");
+    }
+    if record.blood_sugar.is_none() {
+        missing.push("blood sugar");
+    }
+    missing
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{Thresholds, CriticalHr, HypertensiveCrisis};
+    use crate::config::{CriticalHr, CriticalRr, HypertensiveCrisis, Thresholds};
+    use crate::Consciousness;
+    use chrono::NaiveDate;
 
     fn test_thresholds() -> Thresholds {
         Thresholds {
@@ -120,8 +325,13 @@ mod tests {
             blood_pressure: HypertensiveCrisis { systolic: 140, diastolic: 90 },
             hypothermia: 35.0,
             fever: 38.0,
-            hypoglycemia: 3.9,
-            hyperglycemia: 7.0,
+            // 3.9 and 7.0 mmol/L
+            hypoglycemia: 70.0,
+            hyperglycemia: 126.0,
+            hypoxemia: 92,
+            respiratory_rate: CriticalRr::default(),
+            underweight_bmi: 18.5,
+            obesity_bmi: 30.0,
         }
     }
 
@@ -129,15 +339,95 @@ mod tests {
     fn test_detect_risks() {
         let thresholds = test_thresholds();
         let normal_record = PatientRecord {
-            patient_id: 1,
-            date: "2023-01-01".to_string(),
-            heart_rate: 75,
-            bp_systolic: 120,
-            bp_diastolic: 80,
-            temperature: 37.0,
-            blood_sugar: 5.5,
-            steps: 0,
+            patient_id: "1".to_string(),
+            timestamp: Timestamp::parse("2023-01-01").unwrap(),
+            heart_rate: Some(75),
+            bp_systolic: Some(120),
+            bp_diastolic: Some(80),
+            temperature: Some(37.0),
+            blood_sugar: Some(99.0),
+            steps: Some(0),
+            ..Default::default()
         };
         assert!(detect_risks(&normal_record, &thresholds).is_empty());
+        assert!(insufficient_data(&normal_record).is_empty());
+    }
+
+    #[test]
+    fn test_detect_risk_kinds_splits_heart_rate() {
+        let thresholds = test_thresholds();
+        let record = PatientRecord {
+            patient_id: "2".to_string(),
+            timestamp: Timestamp::parse("2023-01-01").unwrap(),
+            heart_rate: Some(45),
+            bp_systolic: Some(150),
+            bp_diastolic: Some(80),
+            temperature: Some(37.0),
+            blood_sugar: Some(146.0),
+            steps: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            detect_risk_kinds(&record, &thresholds),
+            vec![RiskKind::Bradycardia, RiskKind::HypertensiveCrisis, RiskKind::Hyperglycemia]
+        );
+        assert_eq!(detect_risks(&record, &thresholds)[0], "Abnormal heart rate");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_missing_inputs_are_not_assessed() {
+        let thresholds = test_thresholds();
+        let record = PatientRecord {
+            patient_id: "3".to_string(),
+            timestamp: Timestamp::parse("2023-01-01").unwrap(),
+            heart_rate: Some(72),
+            bp_diastolic: Some(95),
+            ..Default::default()
+        };
+        assert_eq!(detect_risk_kinds(&record, &thresholds), vec![RiskKind::HypertensiveCrisis]);
+        assert_eq!(insufficient_data(&record), vec!["temperature", "blood sugar"]);
+    }
+
+    #[test]
+    fn test_extended_vitals_are_assessed() {
+        let thresholds = test_thresholds();
+        let record = PatientRecord {
+            patient_id: "4".to_string(),
+            timestamp: Timestamp::parse("2023-01-01").unwrap(),
+            spo2: Some(88),
+            respiratory_rate: Some(26),
+            consciousness: Some(Consciousness::Voice),
+            weight: Some(98.0),
+            height: Some(170.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            detect_risk_kinds(&record, &thresholds),
+            vec![RiskKind::Hypoxemia, RiskKind::Tachypnea, RiskKind::ReducedConsciousness, RiskKind::Obesity]
+        );
+
+        // Adult BMI categories do not apply to children
+        let child = PatientRecord { birth_date: NaiveDate::from_ymd_opt(2012, 3, 1), ..record };
+        assert_eq!(
+            detect_risk_kinds(&child, &thresholds),
+            vec![RiskKind::Hypoxemia, RiskKind::Tachypnea, RiskKind::ReducedConsciousness]
+        );
+    }
+
+    #[test]
+    fn test_custom_observations_are_assessed() {
+        let pain = ObservationThreshold {
+            name: "pain_score".to_string(),
+            min: Some(0.0),
+            max: Some(6.0),
+            label: "Severe pain".to_string(),
+            critical: false,
+        };
+        let mut record = PatientRecord::default();
+        record.observations.insert("pain_score", observations::ObservationValue::parse("9"));
+        record.observations.insert("device_serial", observations::ObservationValue::parse("0042"));
+        assert_eq!(observation_risks(&record, std::slice::from_ref(&pain)), vec!["Severe pain"]);
+        record.observations.insert("pain_score", observations::ObservationValue::parse("3"));
+        assert!(observation_risks(&record, &[pain]).is_empty());
+    }
+}
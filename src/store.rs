@@ -0,0 +1,552 @@
+use crate::validate::{FindingKind, ValidationResult};
+use crate::demographics::Sex;
+use crate::identity::Identifiers;
+use crate::provenance::Provenance;
+use crate::timestamp::Timestamp;
+use crate::units::Unit;
+use crate::{AktenError, Consciousness, PatientRecord};
+use chrono::Utc;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::path::Path;
+use tracing::info;
+
+/// Paths with these extensions are treated as a patient store, not a data file
+pub const STORE_EXTENSIONS: [&str; 3] = [".db", ".sqlite", ".sqlite3"];
+
+/// Bumped whenever `SCHEMA` changes; stored in `PRAGMA user_version`
+const SCHEMA_VERSION: i32 = 9;
+
+/// Measurements are nullable. NULLs are distinct in a UNIQUE constraint, so
+/// re-ingestion is deduplicated by an expression index that maps them to ''
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS records (
+    id INTEGER PRIMARY KEY,
+    patient_id TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    heart_rate INTEGER,
+    bp_systolic INTEGER,
+    bp_diastolic INTEGER,
+    temperature REAL,
+    blood_sugar REAL,
+    steps INTEGER,
+    source_file TEXT NOT NULL,
+    inserted_at TEXT NOT NULL,
+    spo2 INTEGER,
+    respiratory_rate INTEGER,
+    supplemental_oxygen INTEGER,
+    consciousness TEXT,
+    weight REAL,
+    height REAL,
+    identifiers TEXT,
+    temperature_unit TEXT,
+    blood_sugar_unit TEXT,
+    birth_date TEXT,
+    sex TEXT,
+    provenance TEXT,
+    observations TEXT
+);
+CREATE INDEX IF NOT EXISTS records_by_patient ON records (patient_id, timestamp);
+CREATE UNIQUE INDEX IF NOT EXISTS records_unique ON records (
+    patient_id, timestamp, IFNULL(heart_rate, ''), IFNULL(bp_systolic, ''), IFNULL(bp_diastolic, ''),
+    IFNULL(temperature, ''), IFNULL(blood_sugar, ''), IFNULL(steps, ''), IFNULL(spo2, ''),
+    IFNULL(respiratory_rate, ''), IFNULL(supplemental_oxygen, ''), IFNULL(consciousness, ''),
+    IFNULL(weight, ''), IFNULL(height, ''), IFNULL(identifiers, ''), IFNULL(observations, ''), source_file
+);
+
+CREATE TABLE IF NOT EXISTS validation_runs (
+    id INTEGER PRIMARY KEY,
+    validated_at TEXT NOT NULL,
+    medical_mode INTEGER NOT NULL,
+    record_count INTEGER NOT NULL,
+    issues_found INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS validation_findings (
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER NOT NULL REFERENCES validation_runs (id),
+    record_id INTEGER REFERENCES records (id),
+    kind TEXT NOT NULL,
+    critical INTEGER NOT NULL,
+    message TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS findings_by_record ON validation_findings (record_id);
+";
+
+/// Version 1 declared every measurement NOT NULL; SQLite cannot drop a
+/// constraint in place, so the table is rebuilt with the same ids
+const MIGRATE_V1: &str = "
+CREATE TABLE records_v2 (
+    id INTEGER PRIMARY KEY,
+    patient_id INTEGER NOT NULL,
+    date TEXT NOT NULL,
+    heart_rate INTEGER,
+    bp_systolic INTEGER,
+    bp_diastolic INTEGER,
+    temperature REAL,
+    blood_sugar REAL,
+    steps INTEGER,
+    source_file TEXT NOT NULL,
+    inserted_at TEXT NOT NULL
+);
+INSERT INTO records_v2 SELECT id, patient_id, date, heart_rate, bp_systolic, bp_diastolic, temperature,
+    blood_sugar, steps, source_file, inserted_at FROM records;
+DROP TABLE records;
+ALTER TABLE records_v2 RENAME TO records;
+";
+
+/// Version 2 lacks the extended vital signs; the unique index is rebuilt over them
+const MIGRATE_V2: &str = "
+ALTER TABLE records ADD COLUMN spo2 INTEGER;
+ALTER TABLE records ADD COLUMN respiratory_rate INTEGER;
+ALTER TABLE records ADD COLUMN supplemental_oxygen INTEGER;
+ALTER TABLE records ADD COLUMN consciousness TEXT;
+ALTER TABLE records ADD COLUMN weight REAL;
+ALTER TABLE records ADD COLUMN height REAL;
+DROP INDEX IF EXISTS records_unique;
+";
+
+/// Version 3 stored numeric patient ids; the table is rebuilt with text ids
+/// (`17` becomes `'17'`) and a column for further identifiers
+const MIGRATE_V3: &str = "
+CREATE TABLE records_v4 (
+    id INTEGER PRIMARY KEY,
+    patient_id TEXT NOT NULL,
+    date TEXT NOT NULL,
+    heart_rate INTEGER,
+    bp_systolic INTEGER,
+    bp_diastolic INTEGER,
+    temperature REAL,
+    blood_sugar REAL,
+    steps INTEGER,
+    source_file TEXT NOT NULL,
+    inserted_at TEXT NOT NULL,
+    spo2 INTEGER,
+    respiratory_rate INTEGER,
+    supplemental_oxygen INTEGER,
+    consciousness TEXT,
+    weight REAL,
+    height REAL,
+    identifiers TEXT
+);
+INSERT INTO records_v4 SELECT id, CAST(patient_id AS TEXT), date, heart_rate, bp_systolic, bp_diastolic,
+    temperature, blood_sugar, steps, source_file, inserted_at, spo2, respiratory_rate, supplemental_oxygen,
+    consciousness, weight, height, NULL FROM records;
+DROP TABLE records;
+ALTER TABLE records_v4 RENAME TO records;
+";
+
+/// Version 4 kept day-only `date`s; the column holds measurement timestamps
+/// now, written as they were read (`2024-12-01` or RFC 3339)
+const MIGRATE_V4: &str = "
+ALTER TABLE records RENAME COLUMN date TO timestamp;
+";
+
+/// Version 5 stored temperature and blood sugar as they were read; stored
+/// values without a unit are told by their magnitude again when loaded
+const MIGRATE_V5: &str = "
+ALTER TABLE records ADD COLUMN temperature_unit TEXT;
+ALTER TABLE records ADD COLUMN blood_sugar_unit TEXT;
+";
+
+/// Version 6 had no demographics
+const MIGRATE_V6: &str = "
+ALTER TABLE records ADD COLUMN birth_date TEXT;
+ALTER TABLE records ADD COLUMN sex TEXT;
+";
+
+/// Version 7 only kept the file each record version was stored from
+const MIGRATE_V7: &str = "
+ALTER TABLE records ADD COLUMN provenance TEXT;
+";
+
+/// Version 8 had no additional observations; they are stored as a JSON
+/// object and the unique index is rebuilt over them
+const MIGRATE_V8: &str = "
+ALTER TABLE records ADD COLUMN observations TEXT;
+DROP INDEX IF EXISTS records_unique;
+";
+
+/// Record columns in `PatientRecord` order, as read by `record_from_row`
+const RECORD_COLUMNS: &str = "patient_id, identifiers, timestamp, heart_rate, bp_systolic, bp_diastolic, temperature,
+    blood_sugar, steps, spo2, respiratory_rate, supplemental_oxygen, consciousness, weight, height, temperature_unit,
+    blood_sugar_unit, birth_date, sex, provenance, observations";
+
+/// Latest stored version of each patient's record per measurement time
+fn current_records_query() -> String {
+    format!(
+        "SELECT {} FROM records r
+         WHERE id = (SELECT MAX(id) FROM records WHERE patient_id = r.patient_id AND timestamp = r.timestamp)
+         ORDER BY patient_id, id",
+        RECORD_COLUMNS
+    )
+}
+
+pub fn is_store(path: &str) -> bool {
+    STORE_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+fn store_error(e: rusqlite::Error) -> AktenError {
+    AktenError::Store(e.to_string())
+}
+
+/// Read the `RECORD_COLUMNS` starting at column `offset`
+fn record_from_row(row: &Row, offset: usize) -> rusqlite::Result<PatientRecord> {
+    let identifiers: Option<String> = row.get(offset + 1)?;
+    let timestamp: String = row.get(offset + 2)?;
+    let consciousness: Option<String> = row.get(offset + 12)?;
+    let birth_date: Option<String> = row.get(offset + 17)?;
+    let sex: Option<String> = row.get(offset + 18)?;
+    let provenance: Option<String> = row.get(offset + 19)?;
+    let observations: Option<String> = row.get(offset + 20)?;
+    let unit = |index: usize| -> rusqlite::Result<Option<Unit>> {
+        Ok(row.get::<_, Option<String>>(offset + index)?.as_deref().and_then(Unit::parse))
+    };
+    Ok(PatientRecord {
+        patient_id: row.get(offset)?,
+        identifiers: Identifiers::try_from(identifiers.unwrap_or_default()).unwrap_or_default(),
+        birth_date: birth_date.and_then(|date| date.parse().ok()),
+        sex: sex.as_deref().and_then(Sex::parse),
+        timestamp: Timestamp::parse_iso(&timestamp).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(offset + 2, Type::Text, format!("timestamp '{}'", timestamp).into())
+        })?,
+        heart_rate: row.get(offset + 3)?,
+        bp_systolic: row.get(offset + 4)?,
+        bp_diastolic: row.get(offset + 5)?,
+        temperature: row.get(offset + 6)?,
+        temperature_unit: unit(15)?,
+        blood_sugar: row.get(offset + 7)?,
+        blood_sugar_unit: unit(16)?,
+        steps: row.get(offset + 8)?,
+        spo2: row.get(offset + 9)?,
+        respiratory_rate: row.get(offset + 10)?,
+        supplemental_oxygen: row.get(offset + 11)?,
+        consciousness: consciousness.as_deref().and_then(Consciousness::parse),
+        weight: row.get(offset + 13)?,
+        height: row.get(offset + 14)?,
+        provenance: provenance.and_then(|text| Provenance::try_from(text).ok()),
+        observations: observations.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default(),
+    })
+}
+
+/// A stored record version with where and when it came in
+#[derive(Debug, Clone)]
+pub struct StoredRecord {
+    pub record: PatientRecord,
+    pub source_file: String,
+    pub inserted_at: String,
+    /// Findings of all validation runs that saw this version
+    pub findings: Vec<StoredFinding>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredFinding {
+    pub validated_at: String,
+    pub critical: bool,
+    pub message: String,
+}
+
+/// Embedded SQLite store of patient records, their history and validation results
+pub struct PatientStore {
+    connection: Connection,
+}
+
+impl PatientStore {
+    /// Open or create the store at `path`
+    pub fn open(path: &str) -> Result<Self, AktenError> {
+        Self::init(Connection::open(path).map_err(store_error)?)
+    }
+
+    fn init(connection: Connection) -> Result<Self, AktenError> {
+        let version: i32 = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(store_error)?;
+        if version > SCHEMA_VERSION {
+            return Err(AktenError::Store(format!(
+                "store schema version {} is newer than supported version {}",
+                version, SCHEMA_VERSION
+            )));
+        }
+        let migrations = [(1, MIGRATE_V1), (2, MIGRATE_V2), (3, MIGRATE_V3), (4, MIGRATE_V4), (5, MIGRATE_V5), (6, MIGRATE_V6), (7, MIGRATE_V7), (8, MIGRATE_V8)];
+        for (from, migration) in migrations.iter().filter(|(from, _)| version > 0 && version <= *from) {
+            connection
+                .execute_batch(&format!("BEGIN; {} COMMIT;", migration))
+                .map_err(store_error)?;
+            info!("Migrated patient store schema from version {} to {}", from, from + 1);
+        }
+        connection.execute_batch(SCHEMA).map_err(store_error)?;
+        connection
+            .pragma_update(None, "user_version", SCHEMA_VERSION)
+            .map_err(store_error)?;
+        Ok(Self { connection })
+    }
+
+    /// Store records read from `source_file`; records already stored from the
+    /// same file with identical values are skipped. Returns the number inserted
+    pub fn insert_records(&mut self, records: &[PatientRecord], source_file: &str) -> Result<usize, AktenError> {
+        let inserted_at = Utc::now().to_rfc3339();
+        let transaction = self.connection.transaction().map_err(store_error)?;
+        let mut inserted = 0;
+        {
+            let mut statement = transaction
+                .prepare(
+                    &format!(
+                        "INSERT OR IGNORE INTO records ({}, source_file, inserted_at)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23)",
+                        RECORD_COLUMNS
+                    ),
+                )
+                .map_err(store_error)?;
+            for record in records {
+                inserted += statement
+                    .execute(params![
+                        record.patient_id,
+                        Some(record.identifiers.to_string()).filter(|text| !text.is_empty()),
+                        record.timestamp.to_string(),
+                        record.heart_rate,
+                        record.bp_systolic,
+                        record.bp_diastolic,
+                        record.temperature,
+                        record.blood_sugar,
+                        record.steps,
+                        record.spo2,
+                        record.respiratory_rate,
+                        record.supplemental_oxygen,
+                        record.consciousness.map(|level| level.letter()),
+                        record.weight,
+                        record.height,
+                        record.temperature_unit.map(|unit| unit.ucum()),
+                        record.blood_sugar_unit.map(|unit| unit.ucum()),
+                        record.birth_date.map(|date| date.to_string()),
+                        record.sex.map(|sex| sex.code()),
+                        record.provenance.as_ref().map(Provenance::to_string),
+                        Some(&record.observations)
+                            .filter(|observations| !observations.is_empty())
+                            .map(serde_json::to_string)
+                            .transpose()?,
+                        source_file,
+                        inserted_at,
+                    ])
+                    .map_err(store_error)?;
+            }
+        }
+        transaction.commit().map_err(store_error)?;
+        info!(source_file, "Stored {} of {} records", inserted, records.len());
+        Ok(inserted)
+    }
+
+    /// The latest version of every patient's record per measurement time,
+    /// by patient and in chronological order
+    pub fn load_records(&self) -> Result<Vec<PatientRecord>, AktenError> {
+        let mut statement = self.connection.prepare(&current_records_query()).map_err(store_error)?;
+        let mut records: Vec<PatientRecord> = statement
+            .query_map([], |row| record_from_row(row, 0))
+            .map_err(store_error)?
+            .collect::<Result<_, _>>()
+            .map_err(store_error)?;
+        records.sort_by(|a, b| (&a.patient_id, a.timestamp).cmp(&(&b.patient_id, b.timestamp)));
+        Ok(records)
+    }
+
+    /// Record a validation run; each finding is linked to the record version it was raised on
+    pub fn save_validation(&mut self, result: &ValidationResult, medical_mode: bool) -> Result<i64, AktenError> {
+        let transaction = self.connection.transaction().map_err(store_error)?;
+        transaction
+            .execute(
+                "INSERT INTO validation_runs (validated_at, medical_mode, record_count, issues_found)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![Utc::now().to_rfc3339(), medical_mode, result.record_count, result.issues_found],
+            )
+            .map_err(store_error)?;
+        let run_id = transaction.last_insert_rowid();
+        for finding in &result.findings {
+            let record_id: Option<i64> = transaction
+                .query_row(
+                    "SELECT MAX(id) FROM records WHERE patient_id = ?1 AND timestamp = ?2",
+                    params![finding.record.patient_id, finding.record.timestamp.to_string()],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(store_error)?
+                .flatten();
+            let kind = match finding.kind {
+                FindingKind::Clinical => "clinical",
+                FindingKind::DataQuality => "data-quality",
+            };
+            transaction
+                .execute(
+                    "INSERT INTO validation_findings (run_id, record_id, kind, critical, message)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![run_id, record_id, kind, finding.critical, finding.message],
+                )
+                .map_err(store_error)?;
+        }
+        transaction.commit().map_err(store_error)?;
+        Ok(run_id)
+    }
+
+    /// Every stored version of a patient's records, earliest measurement first
+    pub fn history(&self, patient_id: &str) -> Result<Vec<StoredRecord>, AktenError> {
+        let mut statement = self
+            .connection
+            .prepare(&format!(
+                "SELECT id, source_file, inserted_at, {} FROM records WHERE patient_id = ?1 ORDER BY id",
+                RECORD_COLUMNS
+            ))
+            .map_err(store_error)?;
+        let mut rows: Vec<(i64, StoredRecord)> = statement
+            .query_map([patient_id], |row| {
+                Ok((
+                    row.get(0)?,
+                    StoredRecord {
+                        record: record_from_row(row, 3)?,
+                        source_file: row.get(1)?,
+                        inserted_at: row.get(2)?,
+                        findings: vec![],
+                    },
+                ))
+            })
+            .map_err(store_error)?
+            .collect::<Result<_, _>>()
+            .map_err(store_error)?;
+        rows.sort_by_key(|(_, stored)| stored.record.timestamp);
+
+        let mut findings = self
+            .connection
+            .prepare(
+                "SELECT v.validated_at, f.critical, f.message
+                 FROM validation_findings f JOIN validation_runs v ON v.id = f.run_id
+                 WHERE f.record_id = ?1 ORDER BY v.id, f.id",
+            )
+            .map_err(store_error)?;
+        rows.into_iter()
+            .map(|(id, mut stored)| {
+                stored.findings = findings
+                    .query_map([id], |row| {
+                        Ok(StoredFinding {
+                            validated_at: row.get(0)?,
+                            critical: row.get(1)?,
+                            message: row.get(2)?,
+                        })
+                    })
+                    .map_err(store_error)?
+                    .collect::<Result<_, _>>()
+                    .map_err(store_error)?;
+                Ok(stored)
+            })
+            .collect()
+    }
+}
+
+/// Open the store at `path`, which must already exist for reading
+pub fn load_records(path: &str) -> Result<Vec<PatientRecord>, AktenError> {
+    if !Path::new(path).exists() {
+        return Err(AktenError::InvalidPath(path.into()));
+    }
+    PatientStore::open(path)?.load_records()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity;
+    use crate::validate::Finding;
+
+    fn record(patient_id: &str, timestamp: &str, heart_rate: u32) -> PatientRecord {
+        PatientRecord {
+            patient_id: patient_id.to_string(),
+            timestamp: Timestamp::parse(timestamp).unwrap(),
+            heart_rate: Some(heart_rate),
+            bp_systolic: Some(120),
+            bp_diastolic: Some(80),
+            temperature: Some(36.6),
+            blood_sugar: None,
+            steps: Some(4500),
+            ..Default::default()
+        }
+    }
+
+    fn memory_store() -> PatientStore {
+        PatientStore::init(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_latest_version_is_current() -> Result<(), AktenError> {
+        let mut store = memory_store();
+        assert_eq!(store.insert_records(&[record("1", "2024-12-01", 78), record("2", "2024-12-01", 60)], "day1.csv")?, 2);
+        // Re-ingesting the same file adds nothing; a corrected value adds a version
+        assert_eq!(store.insert_records(&[record("1", "2024-12-01", 78)], "day1.csv")?, 0);
+        assert_eq!(store.insert_records(&[record("1", "2024-12-01", 82)], "day1_corrected.csv")?, 1);
+
+        let mut insured = record("A123456789", "2024-12-01", 64);
+        insured.identifiers = "urn:aktenakrobat:pid|3".to_string().try_into().unwrap();
+        store.insert_records(&[insured], "day1.csv")?;
+        // A second reading on the same day is a record of its own
+        store.insert_records(&[record("2", "2024-12-01T14:00:00+01:00", 66)], "day1_ward.csv")?;
+
+        let current = store.load_records()?;
+        assert_eq!(current.len(), 4);
+        assert_eq!(current[0].heart_rate, Some(82));
+        assert_eq!(current[0].blood_sugar, None);
+        assert_eq!((current[1].heart_rate, current[2].heart_rate), (Some(60), Some(66)));
+        assert_eq!(current[3].patient_id, "A123456789");
+        assert_eq!(current[3].identifiers.get(identity::LOCAL_SYSTEM), Some("3"));
+
+        let history = store.history("1")?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].source_file, "day1.csv");
+        assert_eq!(history[1].record.heart_rate, Some(82));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validation_results_are_kept() -> Result<(), AktenError> {
+        let mut store = memory_store();
+        store.insert_records(&[record("1", "2024-12-01", 150)], "ward.csv")?;
+        let result = ValidationResult {
+            record_count: 1,
+            issues_found: 1,
+            findings: vec![Finding {
+                kind: FindingKind::Clinical,
+                critical: true,
+                message: "Abnormal HR (150 bpm)".to_string(),
+                fields: vec![],
+                record: record("1", "2024-12-01", 150),
+                occurrence: 1,
+            }],
+            ..Default::default()
+        };
+        store.save_validation(&result, true)?;
+
+        let history = store.history("1")?;
+        assert_eq!(history[0].findings.len(), 1);
+        assert!(history[0].findings[0].critical);
+        assert_eq!(history[0].findings[0].message, "Abnormal HR (150 bpm)");
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_1_store_is_migrated() -> Result<(), AktenError> {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE records (id INTEGER PRIMARY KEY, patient_id INTEGER NOT NULL, date TEXT NOT NULL,
+                    heart_rate INTEGER NOT NULL, bp_systolic INTEGER NOT NULL, bp_diastolic INTEGER NOT NULL,
+                    temperature REAL NOT NULL, blood_sugar REAL NOT NULL, steps INTEGER NOT NULL,
+                    source_file TEXT NOT NULL, inserted_at TEXT NOT NULL);
+                 INSERT INTO records VALUES (7, 1, '2024-12-01', 78, 120, 80, 36.6, 92.0, 4500, 'old.csv', 'then');
+                 PRAGMA user_version = 1;",
+            )
+            .unwrap();
+        let mut store = PatientStore::init(connection)?;
+        assert_eq!(store.insert_records(&[record("1", "2024-12-02", 70)], "new.csv")?, 1);
+        assert_eq!(store.insert_records(&[record("1", "2024-12-02", 70)], "new.csv")?, 0);
+
+        let history = store.history("1")?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].record.patient_id, "1");
+        assert_eq!(history[0].record.blood_sugar, Some(92.0));
+        assert_eq!(history[1].record.blood_sugar, None);
+        Ok(())
+    }
+}
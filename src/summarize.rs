@@ -1,26 +1,115 @@
-use crate::{AktenError, PatientRecord};
+use crate::demographics::{self, Sex};
+use crate::fhir::VitalField;
+use std::collections::BTreeMap;
+use crate::{display_value, AktenError, Consciousness, PatientRecord};
 
-/// Summarizes health metrics from a dataset
+/// Average of a field over the records that have it, and how many do not
+fn average(records: &[PatientRecord], field: VitalField) -> (Option<f64>, usize) {
+    let values: Vec<f64> = records.iter().filter_map(|r| r.measurement(field)).collect();
+    let missing = records.len() - values.len();
+    if values.is_empty() {
+        return (None, missing);
+    }
+    (Some(values.iter().sum::<f64>() / values.len() as f64), missing)
+}
+
+/// Note appended to a summary line when some records lack the value
+fn missing_note(missing: usize) -> String {
+    if missing == 0 {
+        String::new()
+    } else {
+        format!(" ({} missing)", missing)
+    }
+}
+
+/// Summarizes health metrics from a dataset; averages only count the records
+/// that have a value
 pub fn summarize_data(records: &[PatientRecord], medical_mode: bool) -> Result<(), AktenError> {
     if records.is_empty() {
         println!("📭 No records found to summarize.");
         return Ok(());
     }
 
-    let count = records.len() as f32;
-    let avg_heart_rate: f32 = records.iter().map(|r| r.heart_rate as f32).sum::<f32>() / count;
-    let avg_bp_systolic: f32 = records.iter().map(|r| r.bp_systolic as f32).sum::<f32>() / count;
-    let avg_bp_diastolic: f32 = records.iter().map(|r| r.bp_diastolic as f32).sum::<f32>() / count;
-    let avg_temperature: f32 = records.iter().map(|r| r.temperature).sum::<f32>() / count;
-    let avg_blood_sugar: f32 = records.iter().map(|r| r.blood_sugar).sum::<f32>() / count;
-    let total_steps: u32 = records.iter().map(|r| r.steps).sum();
+    let (avg_heart_rate, heart_rate_missing) = average(records, VitalField::HeartRate);
+    let (avg_bp_systolic, systolic_missing) = average(records, VitalField::BpSystolic);
+    let (avg_bp_diastolic, diastolic_missing) = average(records, VitalField::BpDiastolic);
+    let (avg_temperature, temperature_missing) = average(records, VitalField::Temperature);
+    let (avg_blood_sugar, blood_sugar_missing) = average(records, VitalField::BloodSugar);
+    let total_steps: u32 = records.iter().filter_map(|r| r.steps).sum();
+    let steps_missing = records.iter().filter(|r| r.steps.is_none()).count();
+    let (avg_spo2, spo2_missing) = average(records, VitalField::Spo2);
+    let (avg_respiratory_rate, respiratory_rate_missing) = average(records, VitalField::RespiratoryRate);
+    let (avg_weight, weight_missing) = average(records, VitalField::Weight);
+    let (avg_bmi, bmi_missing) = average(records, VitalField::Bmi);
+    let on_oxygen = records.iter().filter(|r| r.supplemental_oxygen == Some(true)).count();
+    let not_alert = records
+        .iter()
+        .filter(|r| r.consciousness.is_some_and(|level| level != Consciousness::Alert))
+        .count();
+
+    // Patients by their first recorded sex; ages are at the time of each measurement
+    let mut sexes: BTreeMap<&str, Option<Sex>> = BTreeMap::new();
+    for record in records {
+        let sex = sexes.entry(&record.patient_id).or_default();
+        *sex = sex.or(record.sex);
+    }
+    let count_sex = |wanted: Option<Sex>| sexes.values().filter(|sex| **sex == wanted).count();
+    let ages: Vec<u32> = records.iter().filter_map(PatientRecord::age).collect();
+    let avg_age = (!ages.is_empty()).then(|| ages.iter().sum::<u32>() as f64 / ages.len() as f64);
+    let minors = ages.iter().filter(|age| **age < demographics::ADULT_AGE).count();
+    let without_demographics = demographics::missing_patients(records);
+
+    let show = |value: Option<f64>, unit: &str, precision: usize| match value {
+        Some(value) => format!("{:.*} {}", precision, value, unit),
+        None => "no data".to_string(),
+    };
 
     println!("📊 Summary ({} records):", records.len());
-    println!("- Avg Heart Rate: {:.1} bpm", avg_heart_rate);
-    println!("- Avg Blood Pressure: {:.0}/{:.0} mmHg", avg_bp_systolic, avg_bp_diastolic);
-    println!("- Avg Temperature: {:.1} °C", avg_temperature);
-    println!("- Avg Blood Sugar: {:.1} mmol/L", avg_blood_sugar);
-    println!("- Total Steps: {}", total_steps);
+    println!(
+        "- Patients: {} (female {}, male {}, other {}, unknown {}, not recorded {})",
+        sexes.len(),
+        count_sex(Some(Sex::Female)),
+        count_sex(Some(Sex::Male)),
+        count_sex(Some(Sex::Other)),
+        count_sex(Some(Sex::Unknown)),
+        count_sex(None)
+    );
+    println!(
+        "- Avg Age at Measurement: {}{}",
+        show(avg_age, "years", 1),
+        missing_note(records.len() - ages.len())
+    );
+    if minors > 0 {
+        println!("- Measurements of Minors (under {}): {}", demographics::ADULT_AGE, minors);
+    }
+    println!("- Avg Heart Rate: {}{}", show(avg_heart_rate, "bpm", 1), missing_note(heart_rate_missing));
+    println!(
+        "- Avg Blood Pressure: {}/{} mmHg{}",
+        display_value(avg_bp_systolic, 0),
+        display_value(avg_bp_diastolic, 0),
+        missing_note(systolic_missing.max(diastolic_missing))
+    );
+    println!("- Avg Temperature: {}{}", show(avg_temperature, "°C", 1), missing_note(temperature_missing));
+    println!("- Avg Blood Sugar: {}{}", show(avg_blood_sugar, "mg/dL", 1), missing_note(blood_sugar_missing));
+    println!("- Total Steps: {}{}", total_steps, missing_note(steps_missing));
+    println!("- Avg SpO2: {}{}", show(avg_spo2, "%", 1), missing_note(spo2_missing));
+    println!(
+        "- Avg Respiratory Rate: {}{}",
+        show(avg_respiratory_rate, "/min", 1),
+        missing_note(respiratory_rate_missing)
+    );
+    println!("- Avg Weight: {}{}", show(avg_weight, "kg", 1), missing_note(weight_missing));
+    println!("- Avg BMI: {}{}", show(avg_bmi, "kg/m²", 1), missing_note(bmi_missing));
+    println!("- On Supplemental Oxygen: {}", on_oxygen);
+    println!("- Not Alert (AVPU V/P/U): {}", not_alert);
+
+    if !without_demographics.is_empty() {
+        println!(
+            "⚠️ Demographics missing: {} patients ({})",
+            without_demographics.len(),
+            without_demographics.join(", ")
+        );
+    }
 
     if medical_mode {
         println!("🩺 Medical Mode: Additional metrics or annotations may be added here.");
@@ -28,3 +117,19 @@ pub fn summarize_data(records: &[PatientRecord], medical_mode: bool) -> Result<(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_average_skips_missing_values() {
+        let records = [
+            PatientRecord { heart_rate: Some(70), ..Default::default() },
+            PatientRecord { heart_rate: None, ..Default::default() },
+            PatientRecord { heart_rate: Some(90), ..Default::default() },
+        ];
+        assert_eq!(average(&records, VitalField::HeartRate), (Some(80.0), 1));
+        assert_eq!(average(&records, VitalField::Temperature), (None, 3));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::config::TimeConfig;
+use crate::PatientRecord;
+use chrono::{DateTime, Days, FixedOffset, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::OnceLock;
+
+static SETTINGS: OnceLock<TimeConfig> = OnceLock::new();
+
+/// Use the formats and time zone of `config` for all timestamps read from
+/// now on; without this call the defaults apply. Only the first call counts
+pub fn configure(config: &TimeConfig) {
+    let _ = SETTINGS.set(config.clone());
+}
+
+fn settings() -> &'static TimeConfig {
+    SETTINGS.get_or_init(TimeConfig::default)
+}
+
+/// When a measurement was taken. Date-only inputs are kept as such and
+/// written back as `YYYY-MM-DD`; for ordering they count as midnight of
+/// that day in the configured time zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Timestamp {
+    instant: DateTime<FixedOffset>,
+    date_only: bool,
+}
+
+impl Timestamp {
+    /// Parse ISO 8601 (see `parse_iso`) or one of the configured formats
+    pub fn parse(value: &str) -> Result<Self, String> {
+        Self::parse_with(value, settings())
+    }
+
+    pub fn parse_with(value: &str, config: &TimeConfig) -> Result<Self, String> {
+        let value = value.trim();
+        let tz = config.tz();
+        if let Some(timestamp) = Self::iso_in(value, tz) {
+            return Ok(timestamp);
+        }
+        for format in &config.formats {
+            if let Ok(instant) = DateTime::parse_from_str(value, format) {
+                return Ok(Self::from(instant));
+            }
+            if let Ok(local) = NaiveDateTime::parse_from_str(value, format) {
+                return Self::local_in(local, tz).ok_or_else(|| format!("'{}' does not exist in {}", value, tz));
+            }
+            if let Ok(date) = NaiveDate::parse_from_str(value, format) {
+                return Ok(Self::day_in(date, tz));
+            }
+        }
+        Err(format!("'{}' matches none of the configured timestamp formats", value))
+    }
+
+    /// ISO 8601 as used by FHIR: `YYYY-MM-DD`, or date and time with an
+    /// offset (times without one are read in the configured time zone)
+    pub fn parse_iso(value: &str) -> Option<Self> {
+        Self::iso_in(value.trim(), settings().tz())
+    }
+
+    fn iso_in(value: &str, tz: Tz) -> Option<Self> {
+        if let Ok(instant) = DateTime::parse_from_rfc3339(value) {
+            return Some(Self::from(instant));
+        }
+        if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+            return Some(Self::day_in(date, tz));
+        }
+        Self::local_in(NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f").ok()?, tz)
+    }
+
+    /// A measurement known only by its day
+    pub fn from_date(date: NaiveDate) -> Self {
+        Self::day_in(date, settings().tz())
+    }
+
+    /// A wall-clock time in the configured time zone; `None` for times
+    /// skipped by a daylight saving change
+    pub fn from_local(local: NaiveDateTime) -> Option<Self> {
+        Self::local_in(local, settings().tz())
+    }
+
+    fn day_in(date: NaiveDate, tz: Tz) -> Self {
+        let midnight = date.and_time(Default::default());
+        let instant = tz
+            .from_local_datetime(&midnight)
+            .earliest()
+            .map_or_else(|| Utc.from_utc_datetime(&midnight).fixed_offset(), |t| t.fixed_offset());
+        Self { instant, date_only: true }
+    }
+
+    fn local_in(local: NaiveDateTime, tz: Tz) -> Option<Self> {
+        let instant = tz.from_local_datetime(&local).earliest()?.fixed_offset();
+        Some(Self::from(instant))
+    }
+
+    pub fn instant(&self) -> DateTime<FixedOffset> {
+        self.instant
+    }
+
+    /// Calendar day of the measurement at its own UTC offset
+    pub fn date(&self) -> NaiveDate {
+        self.instant.date_naive()
+    }
+
+    pub fn is_date_only(&self) -> bool {
+        self.date_only
+    }
+
+    /// `DD.MM.YYYY`, with `HH:MM` (and seconds if any) of the wall-clock
+    /// time in the configured time zone, as German spreadsheets write it
+    pub fn to_german(self) -> String {
+        if self.date_only {
+            return self.date().format("%d.%m.%Y").to_string();
+        }
+        let local = self.instant.with_timezone(&settings().tz());
+        let format = if local.second() == 0 { "%d.%m.%Y %H:%M" } else { "%d.%m.%Y %H:%M:%S" };
+        local.format(format).to_string()
+    }
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Self {
+            instant: DateTime::<Utc>::UNIX_EPOCH.fixed_offset(),
+            date_only: true,
+        }
+    }
+}
+
+impl From<DateTime<FixedOffset>> for Timestamp {
+    fn from(instant: DateTime<FixedOffset>) -> Self {
+        Self { instant, date_only: false }
+    }
+}
+
+/// `YYYY-MM-DD` for date-only measurements, RFC 3339 otherwise
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.date_only {
+            write!(f, "{}", self.date().format("%Y-%m-%d"))
+        } else {
+            f.write_str(&self.instant.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+        }
+    }
+}
+
+impl TryFrom<String> for Timestamp {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value)
+    }
+}
+
+impl From<Timestamp> for String {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.to_string()
+    }
+}
+
+/// Measurement times to keep; both bounds are inclusive, and a date-only
+/// `until` includes that whole day
+#[derive(Debug, Clone, Default)]
+pub struct TimeRange {
+    since: Option<DateTime<FixedOffset>>,
+    /// Exclusive end, the midnight after a date-only `until`
+    before: Option<DateTime<FixedOffset>>,
+    until: Option<DateTime<FixedOffset>>,
+}
+
+impl TimeRange {
+    pub fn parse(since: Option<&str>, until: Option<&str>) -> Result<Self, String> {
+        let mut range = Self {
+            since: since.map(Timestamp::parse).transpose()?.map(|t| t.instant),
+            ..Self::default()
+        };
+        match until.map(Timestamp::parse).transpose()? {
+            Some(day) if day.date_only => {
+                let next = day.date().checked_add_days(Days::new(1)).ok_or("date out of range")?;
+                range.before = Some(Timestamp::from_date(next).instant);
+            }
+            Some(time) => range.until = Some(time.instant),
+            None => {}
+        }
+        Ok(range)
+    }
+
+    /// Drop records outside the range and order the rest chronologically;
+    /// records taken at the same time keep their order. Returns the number
+    /// of records dropped
+    pub fn apply(&self, records: &mut Vec<PatientRecord>) -> usize {
+        let count = records.len();
+        records.retain(|record| self.contains(&record.timestamp));
+        records.sort_by_key(|record| record.timestamp);
+        count - records.len()
+    }
+
+    pub fn contains(&self, timestamp: &Timestamp) -> bool {
+        let instant = timestamp.instant;
+        self.since.is_none_or(|since| instant >= since)
+            && self.before.is_none_or(|before| instant < before)
+            && self.until.is_none_or(|until| instant <= until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats_and_time_zones() {
+        let config = TimeConfig::default();
+        let parse = |value: &str| Timestamp::parse_with(value, &config).unwrap();
+
+        let winter = parse("01.12.2024 08:30");
+        assert_eq!(winter.to_string(), "2024-12-01T08:30:00+01:00");
+        assert_eq!(parse("2024-07-01 08:30").to_string(), "2024-07-01T08:30:00+02:00");
+        assert_eq!(parse("2024-12-01T07:30:00Z"), winter);
+        assert_eq!(parse("2024-12-01T07:30:00Z").to_string(), "2024-12-01T07:30:00Z");
+        assert_eq!(parse("2024-12-01T07:30:00Z").to_german(), "01.12.2024 08:30");
+        assert_eq!(parse(&parse("2024-07-01 08:30:15").to_german()).to_string(), "2024-07-01T08:30:15+02:00");
+
+        let day = parse("2024-12-01");
+        assert!(day.is_date_only());
+        assert_eq!((day.to_string(), day.to_german()), ("2024-12-01".to_string(), "01.12.2024".to_string()));
+        assert!(day < winter);
+        assert!(Timestamp::parse_with("2024-03-31 02:30", &config).is_err(), "skipped by the DST change");
+        assert!(Timestamp::parse_with("1. Dezember", &config).is_err());
+    }
+
+    #[test]
+    fn test_time_range_bounds() {
+        let range = TimeRange::parse(Some("2024-12-01T08:00:00+01:00"), Some("2024-12-02")).unwrap();
+        let contains = |value: &str| range.contains(&Timestamp::parse(value).unwrap());
+        assert!(!contains("2024-12-01T07:59:59+01:00"));
+        assert!(contains("2024-12-01T08:00:00+01:00"));
+        assert!(contains("2024-12-02T23:59:00+01:00"));
+        assert!(!contains("2024-12-03"));
+        assert!(TimeRange::parse(Some("gestern"), None).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::fhir::VitalField;
+use crate::PatientRecord;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// mg/dL per mmol/L of glucose (molar mass 180.16 g/mol)
+const GLUCOSE_MG_PER_MMOL: f64 = 18.016;
+
+/// Glucose values without a unit below this are read as mmol/L, from
+/// `GLUCOSE_MG_DL_MIN` on as mg/dL; values in between are plausible in both
+/// (severe hyperglycaemia in mmol/L, severe hypoglycaemia in mg/dL)
+const GLUCOSE_MMOL_L_MAX: f64 = 20.0;
+const GLUCOSE_MG_DL_MIN: f64 = 40.0;
+
+/// Plausible body temperatures; 25-45 °C is 77-113 °F, so the ranges do not overlap
+const CELSIUS_RANGE: std::ops::RangeInclusive<f64> = 25.0..=45.0;
+const FAHRENHEIT_RANGE: std::ops::RangeInclusive<f64> = 77.0..=113.0;
+
+/// UCUM units temperature and blood sugar are read in; written as the UCUM
+/// code, read from it or a common spelling in any case
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    MilligramsPerDeciliter,
+    MillimolesPerLiter,
+}
+
+impl Unit {
+    pub fn ucum(&self) -> &'static str {
+        match self {
+            Self::Celsius => "Cel",
+            Self::Fahrenheit => "[degF]",
+            Self::MilligramsPerDeciliter => "mg/dL",
+            Self::MillimolesPerLiter => "mmol/L",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "cel" | "°c" | "c" | "degc" => Some(Self::Celsius),
+            "[degf]" | "°f" | "f" | "degf" => Some(Self::Fahrenheit),
+            "mg/dl" | "mg%" => Some(Self::MilligramsPerDeciliter),
+            "mmol/l" => Some(Self::MillimolesPerLiter),
+            _ => None,
+        }
+    }
+
+    /// The measurement this unit is one of
+    fn field(&self) -> VitalField {
+        match self {
+            Self::Celsius | Self::Fahrenheit => VitalField::Temperature,
+            Self::MilligramsPerDeciliter | Self::MillimolesPerLiter => VitalField::BloodSugar,
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.ucum())
+    }
+}
+
+impl TryFrom<String> for Unit {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value).ok_or_else(|| format!("'{}' is not a supported unit (Cel, [degF], mg/dL or mmol/L)", value))
+    }
+}
+
+impl From<Unit> for String {
+    fn from(unit: Unit) -> Self {
+        unit.ucum().to_string()
+    }
+}
+
+/// Unit records hold `field` in, which thresholds are given in as well;
+/// `None` for measurements read in a single unit anyway
+pub fn canonical_unit(field: VitalField) -> Option<Unit> {
+    match field {
+        VitalField::Temperature => Some(Unit::Celsius),
+        VitalField::BloodSugar => Some(Unit::MilligramsPerDeciliter),
+        _ => None,
+    }
+}
+
+/// Unit of a value given without one, told by its magnitude; `None` where
+/// it is plausible in both units or in neither
+pub fn detect(field: VitalField, value: f64) -> Option<Unit> {
+    match field {
+        VitalField::Temperature if CELSIUS_RANGE.contains(&value) => Some(Unit::Celsius),
+        VitalField::Temperature if FAHRENHEIT_RANGE.contains(&value) => Some(Unit::Fahrenheit),
+        VitalField::BloodSugar if value < GLUCOSE_MMOL_L_MAX => Some(Unit::MillimolesPerLiter),
+        VitalField::BloodSugar if value >= GLUCOSE_MG_DL_MIN => Some(Unit::MilligramsPerDeciliter),
+        _ => None,
+    }
+}
+
+/// `value` of `field` in its canonical unit, converted from `unit` or, without
+/// one, from the unit its magnitude points to. Conversions are rounded to one decimal
+pub fn to_canonical(field: VitalField, value: f64, unit: Option<Unit>) -> Result<f64, String> {
+    let Some(canonical) = canonical_unit(field) else {
+        return Ok(value);
+    };
+    let unit = match unit {
+        Some(unit) if unit.field() != field => {
+            return Err(format!("{:?} {} is given in {}, which is not a unit of {:?}", field, value, unit, field));
+        }
+        Some(unit) => unit,
+        None => detect(field, value).ok_or_else(|| match field {
+            VitalField::BloodSugar => format!("blood sugar {} has no unit and could be mg/dL or mmol/L", value),
+            _ => format!("temperature {} has no unit and is plausible neither in °C nor in °F", value),
+        })?,
+    };
+    if unit == canonical {
+        return Ok(value);
+    }
+    let converted = match unit {
+        Unit::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+        Unit::MillimolesPerLiter => value * GLUCOSE_MG_PER_MMOL,
+        Unit::Celsius | Unit::MilligramsPerDeciliter => value,
+    };
+    Ok((converted * 10.0).round() / 10.0)
+}
+
+/// `to_canonical` for a unit as written in a message or file; an empty unit
+/// is told by the value's magnitude
+pub fn convert(field: VitalField, value: f64, unit: &str) -> Result<f64, String> {
+    if canonical_unit(field).is_none() {
+        return Ok(value);
+    }
+    let unit = match unit.trim() {
+        "" => None,
+        text => Some(Unit::parse(text).ok_or_else(|| format!("{:?} unit '{}' is not supported", field, text))?),
+    };
+    to_canonical(field, value, unit)
+}
+
+/// Convert temperature and blood sugar of `record` to °C and mg/dL. Values
+/// whose unit cannot be told are removed rather than misread; the returned
+/// issues say which
+pub fn normalize(record: &mut PatientRecord) -> Vec<(VitalField, String)> {
+    let mut issues = vec![];
+    if let Some(issue) = normalize_value(VitalField::Temperature, &mut record.temperature, &mut record.temperature_unit) {
+        issues.push((VitalField::Temperature, issue));
+    }
+    if let Some(issue) = normalize_value(VitalField::BloodSugar, &mut record.blood_sugar, &mut record.blood_sugar_unit) {
+        issues.push((VitalField::BloodSugar, issue));
+    }
+    issues
+}
+
+fn normalize_value(field: VitalField, value: &mut Option<f32>, unit: &mut Option<Unit>) -> Option<String> {
+    let Some(raw) = *value else {
+        *unit = None;
+        return None;
+    };
+    match to_canonical(field, f64::from(raw), *unit) {
+        Ok(canonical) => {
+            *value = Some(canonical as f32);
+            *unit = canonical_unit(field);
+            None
+        }
+        Err(issue) => {
+            *value = None;
+            *unit = None;
+            Some(issue)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversion_and_detection() {
+        assert_eq!(to_canonical(VitalField::BloodSugar, 5.2, Some(Unit::MillimolesPerLiter)), Ok(93.7));
+        assert_eq!(to_canonical(VitalField::BloodSugar, 5.2, None), Ok(93.7));
+        assert_eq!(to_canonical(VitalField::BloodSugar, 410.0, None), Ok(410.0));
+        assert_eq!(to_canonical(VitalField::Temperature, 101.3, None), Ok(38.5));
+        assert_eq!(convert(VitalField::Temperature, 36.6, "°C"), Ok(36.6));
+        assert_eq!(convert(VitalField::HeartRate, 78.0, "/min"), Ok(78.0));
+        assert!(to_canonical(VitalField::BloodSugar, 30.0, None).is_err(), "hypo in mg/dL or hyper in mmol/L");
+        assert!(to_canonical(VitalField::Temperature, 60.0, None).is_err());
+        assert!(to_canonical(VitalField::Temperature, 36.6, Some(Unit::MillimolesPerLiter)).is_err());
+        assert!(convert(VitalField::BloodSugar, 92.0, "g/L").is_err());
+    }
+
+    #[test]
+    fn test_normalize_keeps_declared_units_and_drops_ambiguous_values() {
+        let mut record = PatientRecord {
+            temperature: Some(98.6),
+            blood_sugar: Some(30.0),
+            blood_sugar_unit: Some(Unit::MilligramsPerDeciliter),
+            ..Default::default()
+        };
+        assert!(normalize(&mut record).is_empty());
+        assert_eq!((record.temperature, record.temperature_unit), (Some(37.0), Some(Unit::Celsius)));
+        assert_eq!((record.blood_sugar, record.blood_sugar_unit), (Some(30.0), Some(Unit::MilligramsPerDeciliter)));
+
+        record.blood_sugar_unit = None;
+        let issues = normalize(&mut record);
+        assert_eq!(issues.len(), 1, "{:?}", issues);
+        assert_eq!((record.blood_sugar, record.blood_sugar_unit), (None, None));
+        assert_eq!(record.temperature, Some(37.0));
+    }
+}
@@ -1,10 +1,17 @@
-use crate::{AktenError, PatientRecord, config::ThresholdConfig};
-use csv::ReaderBuilder;
+use crate::{display_value, AktenError, Consciousness, PatientRecord, config::ThresholdConfig};
+use crate::fhir::{self, VitalField};
+use crate::demographics;
+use crate::ldt::{self, LabResult};
+use crate::loader::{self, LoadOptions, UnitIssue};
+use crate::observations::{self, ThresholdOutcome};
 use rayon::prelude::*;
-use serde_json;
-use std::{fs::File, path::Path, sync::Mutex};
+use std::sync::Mutex;
 use tracing::{info, warn};
 
+/// Stage 1/2 hypertension (mmHg), reported as a warning below the configured crisis level
+pub const STAGE_HYPERTENSION_SYSTOLIC: u32 = 140;
+pub const STAGE_HYPERTENSION_DIASTOLIC: u32 = 90;
+
 /// Results container for validation operations
 #[derive(Debug, Default)]
 pub struct ValidationResult {
@@ -12,19 +19,56 @@ pub struct ValidationResult {
     pub issues_found: usize,
     pub critical_alerts: Vec<String>,
     pub warnings: Vec<String>,
+    pub findings: Vec<Finding>,
+}
+
+/// Whether a finding concerns the patient's condition or the recorded data itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FindingKind {
+    Clinical,
+    DataQuality,
 }
 
-/// Main validation entry point
+/// Structured form of an alert, for machine-readable output
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub kind: FindingKind,
+    pub critical: bool,
+    pub message: String,
+    /// Measurements the finding is based on
+    pub fields: Vec<VitalField>,
+    pub record: PatientRecord,
+    /// Position of the record among those of its patient and time, by which
+    /// the FHIR export tells their Observations apart (see `fhir::occurrences`)
+    pub occurrence: usize,
+}
+
+/// Main validation entry point for the records of any source the shared
+/// loader reads, prepared as `options` ask; lab results are joined onto the
+/// records first and also checked against the lab's own reference ranges.
+/// Values whose unit cannot be told are left out of the checks and reported
+/// as data issues
 pub fn run_validation(
     input_path: &str,
     medical_mode: bool,
     config: &ThresholdConfig,
+    lab_results: &[LabResult],
+    options: &LoadOptions,
 ) -> Result<ValidationResult, AktenError> {
     let path = input_path.trim();
-    validate_path(path)?;
-
-    let records = load_records(path)?;
-    let result = validate_records(&records, medical_mode, config);
+    let mut records = loader::read_records(path)?;
+    let unit_issues = loader::prepare(&mut records, options, path);
+    if !lab_results.is_empty() {
+        ldt::join_lab_results(&mut records, lab_results);
+    }
+    let mut result = validate_records(&records, medical_mode, config);
+    let occurrences = fhir::occurrences(&records);
+    for UnitIssue { record, index, field, issue } in &unit_issues {
+        let first = result.findings.len();
+        log_data_issue(record, &format!("Unknown unit: {}", issue), &[*field], &mut result);
+        set_occurrence(&mut result, first, occurrences[*index]);
+    }
+    check_lab_results(&records, lab_results, &mut result);
 
     info!("Validated {} records - {} issues found", 
           result.record_count, 
@@ -33,47 +77,21 @@ pub fn run_validation(
     Ok(result)
 }
 
-/// Validate file path existence and format
-fn validate_path(path: &str) -> Result<(), AktenError> {
-    match path {
-        "" => Err(AktenError::InvalidPath("Empty path provided".into())),
-        _ if !Path::new(path).exists() => Err(AktenError::InvalidPath(path.into())),
-        _ if !path.ends_with(".json") && !path.ends_with(".csv") => {
-            Err(AktenError::UnsupportedFormat)
-        }
-        _ => Ok(()),
-    }
-}
-
-/// Load records from supported file formats
-fn load_records(path: &str) -> Result<Vec<PatientRecord>, AktenError> {
-    let file = File::open(path)?;
-    
-    if path.ends_with(".json") {
-        serde_json::from_reader(file).map_err(Into::into)
-    } else {
-        ReaderBuilder::new()
-            .has_headers(true)
-            .flexible(true)
-            .from_reader(file)
-            .deserialize()
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(Into::into)
-    }
-}
-
 /// Parallel record validation
-fn validate_records(
+pub fn validate_records(
     records: &[PatientRecord],
     medical_mode: bool,
     config: &ThresholdConfig,
 ) -> ValidationResult {
     let result = Mutex::new(ValidationResult::default());
+    let occurrences = fhir::occurrences(records);
 
-    records.par_iter().for_each(|record| {
+    records.par_iter().zip(&occurrences).for_each(|(record, occurrence)| {
         let mut guard = result.lock().unwrap();
         guard.record_count += 1;
+        let first = guard.findings.len();
         validate_record(record, medical_mode, &mut guard, config);
+        set_occurrence(&mut guard, first, *occurrence);
     });
 
     result.into_inner().unwrap()
@@ -86,13 +104,61 @@ fn validate_record(
     result: &mut ValidationResult,
     config: &ThresholdConfig,
 ) {
+    check_data_quality(record, result);
     check_vital_signs(record, result, config);
+    check_observations(record, result, config);
     
     if medical_mode {
         check_medical_conditions(record, result, config);
     }
 }
 
+/// Physiologically impossible values point at entry or device errors;
+/// a check only runs when the values it looks at were recorded
+fn check_data_quality(record: &PatientRecord, result: &mut ValidationResult) {
+    if record.patient_id.trim().is_empty() {
+        log_data_issue(record, "Missing patient identifier", &[], result);
+    }
+    if let Some(heart_rate) = record.heart_rate.filter(|hr| *hr == 0 || *hr > 300) {
+        log_data_issue(record, &format!("Implausible HR ({} bpm)", heart_rate), &[VitalField::HeartRate], result);
+    }
+    if let (Some(systolic), Some(diastolic)) = (record.bp_systolic, record.bp_diastolic) {
+        if diastolic >= systolic {
+            log_data_issue(
+                record,
+                &format!("Diastolic BP not below systolic ({}/{})", systolic, diastolic),
+                &[VitalField::BpSystolic, VitalField::BpDiastolic],
+                result,
+            );
+        }
+    }
+    if let Some(temperature) = record.temperature.filter(|t| !(25.0..=45.0).contains(t)) {
+        log_data_issue(record, &format!("Implausible temperature ({:.1}°C)", temperature), &[VitalField::Temperature], result);
+    }
+    if let Some(blood_sugar) = record.blood_sugar.filter(|s| *s <= 0.0) {
+        log_data_issue(record, &format!("Implausible blood sugar ({:.1} mg/dL)", blood_sugar), &[VitalField::BloodSugar], result);
+    }
+    if let Some(spo2) = record.spo2.filter(|s| *s == 0 || *s > 100) {
+        log_data_issue(record, &format!("Implausible SpO2 ({}%)", spo2), &[VitalField::Spo2], result);
+    }
+    if let Some(rate) = record.respiratory_rate.filter(|r| *r == 0 || *r > 80) {
+        log_data_issue(record, &format!("Implausible respiratory rate ({}/min)", rate), &[VitalField::RespiratoryRate], result);
+    }
+    if let Some(weight) = record.weight.filter(|w| !(0.2..=500.0).contains(w)) {
+        log_data_issue(record, &format!("Implausible weight ({:.1} kg)", weight), &[VitalField::Weight], result);
+    }
+    if let Some(height) = record.height.filter(|h| !(20.0..=275.0).contains(h)) {
+        log_data_issue(record, &format!("Implausible height ({:.0} cm)", height), &[VitalField::Height], result);
+    }
+    if let Some(birth_date) = record.birth_date {
+        match record.age() {
+            None => log_data_issue(record, &format!("Birth date {} after the measurement", birth_date), &[], result),
+            Some(age) if age > 125 => log_data_issue(record, &format!("Implausible age ({} years)", age), &[], result),
+            Some(_) => {}
+        }
+    }
+}
+
 /// Core vital sign validation
 fn check_vital_signs(
     record: &PatientRecord,
@@ -102,32 +168,81 @@ fn check_vital_signs(
     let thresholds = &config.thresholds;
 
     // Heart rate check
-    if record.heart_rate < thresholds.heart_rate.min 
-        || record.heart_rate > thresholds.heart_rate.max 
-    {
-        log_alert(
-            record, 
-            &format!("Abnormal HR ({} bpm)", record.heart_rate),
-            true,
-            result
-        );
+    if let Some(heart_rate) = record.heart_rate {
+        if heart_rate < thresholds.heart_rate.min
+            || heart_rate > thresholds.heart_rate.max
+        {
+            log_alert(
+                record,
+                &format!("Abnormal HR ({} bpm)", heart_rate),
+                true,
+                &[VitalField::HeartRate],
+                result
+            );
+        }
     }
 
     // Temperature check
-    if record.temperature < thresholds.hypothermia {
-        log_alert(
-            record,
-            &format!("Hypothermia ({:.1}°C)", record.temperature),
-            true,
-            result
-        );
-    } else if record.temperature > thresholds.fever {
-        log_alert(
-            record,
-            &format!("Fever ({:.1}°C)", record.temperature),
-            true,
-            result
-        );
+    if let Some(temperature) = record.temperature {
+        if temperature < thresholds.hypothermia {
+            log_alert(
+                record,
+                &format!("Hypothermia ({:.1}°C)", temperature),
+                true,
+                &[VitalField::Temperature],
+                result
+            );
+        } else if temperature > thresholds.fever {
+            log_alert(
+                record,
+                &format!("Fever ({:.1}°C)", temperature),
+                true,
+                &[VitalField::Temperature],
+                result
+            );
+        }
+    }
+
+    // Oxygenation and breathing checks
+    if let Some(spo2) = record.spo2.filter(|s| *s < thresholds.hypoxemia) {
+        let oxygen = if record.supplemental_oxygen == Some(true) { " on supplemental oxygen" } else { "" };
+        log_alert(record, &format!("Hypoxemia (SpO2 {}%{})", spo2, oxygen), true, &[VitalField::Spo2], result);
+    }
+    if let Some(rate) = record.respiratory_rate {
+        if rate < thresholds.respiratory_rate.min || rate > thresholds.respiratory_rate.max {
+            log_alert(
+                record,
+                &format!("Abnormal respiratory rate ({}/min)", rate),
+                true,
+                &[VitalField::RespiratoryRate],
+                result
+            );
+        }
+    }
+
+    // Any response below "alert" needs urgent review
+    if let Some(level) = record.consciousness.filter(|level| *level != Consciousness::Alert) {
+        log_alert(record, &format!("Reduced consciousness (AVPU {})", level.letter()), true, &[], result);
+    }
+}
+
+/// Additional observations against the `[[observations]]` thresholds;
+/// values that are not numbers cannot be checked and are data issues
+fn check_observations(
+    record: &PatientRecord,
+    result: &mut ValidationResult,
+    config: &ThresholdConfig,
+) {
+    for outcome in observations::check(record, &config.observations) {
+        match outcome {
+            ThresholdOutcome::Exceeded(threshold, value) => {
+                let message = format!("{} ({} {})", threshold.label, threshold.name, value);
+                log_alert(record, &message, threshold.critical, &[], result);
+            }
+            ThresholdOutcome::NotANumber(threshold, value) => {
+                log_data_issue(record, &format!("{} '{}' is not a number", threshold.name, value), &[], result);
+            }
+        }
     }
 }
 
@@ -139,62 +254,151 @@ fn check_medical_conditions(
 ) {
     let thresholds = &config.thresholds;
 
-    // Blood pressure evaluation
-    match (record.bp_systolic, record.bp_diastolic) {
-        (s, d) if s >= thresholds.blood_pressure.systolic 
-               || d >= thresholds.blood_pressure.diastolic => {
-            log_alert(record, "Hypertensive crisis", true, result);
+    // Blood pressure evaluation; either value alone is enough to raise an alert
+    let bp = [VitalField::BpSystolic, VitalField::BpDiastolic];
+    let reaches = |systolic: u32, diastolic: u32| {
+        record.bp_systolic.is_some_and(|s| s >= systolic) || record.bp_diastolic.is_some_and(|d| d >= diastolic)
+    };
+    if reaches(thresholds.blood_pressure.systolic, thresholds.blood_pressure.diastolic) {
+        log_alert(record, "Hypertensive crisis", true, &bp, result);
+    } else if reaches(STAGE_HYPERTENSION_SYSTOLIC, STAGE_HYPERTENSION_DIASTOLIC) {
+        log_alert(record, "Stage 1/2 hypertension", false, &bp, result);
+    }
+
+    // Blood sugar evaluation
+    if let Some(blood_sugar) = record.blood_sugar {
+        if blood_sugar > thresholds.hyperglycemia {
+            log_alert(record, "Hyperglycemia", true, &[VitalField::BloodSugar], result);
+        } else if blood_sugar < thresholds.hypoglycemia {
+            log_alert(record, "Hypoglycemia", false, &[VitalField::BloodSugar], result);
         }
-        (s, d) if s >= 140 || d >= 90 => {
-            log_alert(record, "Stage 1/2 hypertension", false, result);
+    }
+
+    // Body mass index evaluation; the categories are for adults
+    if let Some(bmi) = record.bmi().filter(|_| demographics::is_adult(record)) {
+        if bmi >= f64::from(thresholds.obesity_bmi) {
+            log_alert(record, &format!("Obesity (BMI {:.1})", bmi), false, &[VitalField::Bmi], result);
+        } else if bmi < f64::from(thresholds.underweight_bmi) {
+            log_alert(record, &format!("Underweight (BMI {:.1})", bmi), false, &[VitalField::Bmi], result);
         }
-        _ => {}
     }
+}
 
-    // Blood sugar evaluation
-    if record.blood_sugar > thresholds.hyperglycemia {
-        log_alert(record, "Hyperglycemia", true, result);
-    } else if record.blood_sugar < thresholds.hypoglycemia {
-        log_alert(record, "Hypoglycemia", false, result);
+/// Lab results outside the lab's own reference range or carrying a limit
+/// indicator (8422); the range is specific to the lab's method
+pub fn check_lab_results(records: &[PatientRecord], lab_results: &[LabResult], result: &mut ValidationResult) {
+    let occurrences = fhir::occurrences(records);
+    for lab in lab_results.iter().filter(|lab| lab.is_abnormal()) {
+        let Some(index) = records
+            .iter()
+            .position(|r| r.patient_id == lab.patient_id && r.timestamp.date() == lab.date)
+        else {
+            continue;
+        };
+        let record = &records[index];
+        let mut message = format!("Lab {} {} {}", lab.label(), lab.raw_value, lab.unit).trim_end().to_string();
+        if !lab.reference.is_empty() {
+            message.push_str(&format!(" outside reference {}", lab.reference.describe()));
+        }
+        if let Some(flag) = &lab.flag {
+            message.push_str(&format!(" ({})", flag));
+        }
+        let fields: Vec<VitalField> = lab.vital_field().into_iter().collect();
+        let first = result.findings.len();
+        log_alert(record, &message, lab.is_critical(), &fields, result);
+        set_occurrence(result, first, occurrences[index]);
     }
 }
 
+/// Findings from `first` on concern the `occurrence`th record of their patient and time
+fn set_occurrence(result: &mut ValidationResult, first: usize, occurrence: usize) {
+    result.findings[first..].iter_mut().for_each(|finding| finding.occurrence = occurrence);
+}
+
 /// Unified alert logging
 fn log_alert(
     record: &PatientRecord,
     message: &str,
     is_critical: bool,
+    fields: &[VitalField],
     result: &mut ValidationResult,
 ) {
+    result.findings.push(Finding {
+        kind: FindingKind::Clinical,
+        critical: is_critical,
+        message: message.to_string(),
+        fields: fields.to_vec(),
+        record: record.clone(),
+        occurrence: 1,
+    });
+
     let alert = if is_critical {
         result.critical_alerts.push(format!(
-            "🚨 CRITICAL: {} | Patient {} ({})\n   HR: {}, Temp: {:.1}°C, BP: {}/{}",
+            "🚨 CRITICAL: {} | Patient {} ({}){}\n   HR: {}, RR: {}, SpO2: {}%, Temp: {}°C, BP: {}/{}",
             message,
             record.patient_id,
-            record.date,
-            record.heart_rate,
-            record.temperature,
-            record.bp_systolic,
-            record.bp_diastolic
+            record.timestamp,
+            origin_note(record),
+            display_value(record.heart_rate, 0),
+            display_value(record.respiratory_rate, 0),
+            display_value(record.spo2, 0),
+            display_value(record.temperature, 1),
+            display_value(record.bp_systolic, 0),
+            display_value(record.bp_diastolic, 0)
         ));
         "CRITICAL"
     } else {
         result.warnings.push(format!(
-            "⚠️ WARNING: {} | Patient {}",
+            "⚠️ WARNING: {} | Patient {}{}",
             message,
-            record.patient_id
+            record.patient_id,
+            origin_note(record)
         ));
         "WARNING"
     };
 
     result.issues_found += 1;
-    warn!("{} alert for patient {}: {}", alert, record.patient_id, message);
+    warn!("{} alert for patient {}: {}{}", alert, record.patient_id, message, origin_note(record));
+}
+
+/// Where the record came from, for records read with their provenance
+fn origin_note(record: &PatientRecord) -> String {
+    record.provenance.as_ref().map_or_else(String::new, |p| format!(" [from {}]", p.origin()))
+}
+
+/// Data-quality problems are reported as warnings; the value itself is suspect
+fn log_data_issue(
+    record: &PatientRecord,
+    message: &str,
+    fields: &[VitalField],
+    result: &mut ValidationResult,
+) {
+    result.findings.push(Finding {
+        kind: FindingKind::DataQuality,
+        critical: false,
+        message: message.to_string(),
+        fields: fields.to_vec(),
+        record: record.clone(),
+        occurrence: 1,
+    });
+    result.warnings.push(format!(
+        "⚠️ DATA: {} | Patient {} ({}){}",
+        message,
+        record.patient_id,
+        record.timestamp,
+        origin_note(record)
+    ));
+
+    result.issues_found += 1;
+    warn!("Data issue for patient {}: {}{}", record.patient_id, message, origin_note(record));
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile::NamedTempFile;
+    use crate::demographics::Demographics;
+    use crate::AktenError;
+    use tempfile::Builder;
 
     fn test_config() -> ThresholdConfig {
         ThresholdConfig {
@@ -208,7 +412,18 @@ mod tests {
                 fever: 38.0,
                 hypoglycemia: 70.0,
                 hyperglycemia: 400.0,
+                hypoxemia: 92,
+                respiratory_rate: crate::config::CriticalRr::default(),
+                underweight_bmi: 18.5,
+                obesity_bmi: 30.0,
             },
+            identity: Default::default(),
+            time: Default::default(),
+            fhir: Default::default(),
+            openehr: Default::default(),
+            omop: Default::default(),
+            csv: Default::default(),
+            observations: vec![],
         }
     }
 
@@ -218,10 +433,10 @@ mod tests {
 patient_id,date,heart_rate,bp_systolic,bp_diastolic,temperature,blood_sugar,steps
 1,2023-01-01,72,120,80,36.5,90,5000";
         
-        let file = NamedTempFile::new()?;
+        let file = Builder::new().suffix(".csv").tempfile()?;
         std::fs::write(&file, csv_data)?;
         
-        let result = run_validation(file.path().to_str().unwrap(), true, &test_config())?;
+        let result = run_validation(file.path().to_str().unwrap(), true, &test_config(), &[], &LoadOptions::default())?;
         assert_eq!(result.issues_found, 0);
         Ok(())
     }
@@ -232,12 +447,105 @@ patient_id,date,heart_rate,bp_systolic,bp_diastolic,temperature,blood_sugar,step
 patient_id,date,heart_rate,bp_systolic,bp_diastolic,temperature,blood_sugar,steps
 1,2023-01-01,180,190,110,39.0,450,0";
         
-        let file = NamedTempFile::new()?;
+        let file = Builder::new().suffix(".csv").tempfile()?;
         std::fs::write(&file, csv_data)?;
         
-        let result = run_validation(file.path().to_str().unwrap(), true, &test_config())?;
+        let result = run_validation(file.path().to_str().unwrap(), true, &test_config(), &[], &LoadOptions::default())?;
         assert_eq!(result.critical_alerts.len(), 4);
         assert_eq!(result.issues_found, 4);
         Ok(())
     }
+
+    #[test]
+    fn test_data_quality_findings() -> Result<(), AktenError> {
+        let csv_data = "\
+patient_id,date,heart_rate,bp_systolic,bp_diastolic,temperature,blood_sugar,steps
+1,2023-01-01,0,80,120,36.5,90,5000";
+
+        let file = Builder::new().suffix(".csv").tempfile()?;
+        std::fs::write(&file, csv_data)?;
+
+        let result = run_validation(file.path().to_str().unwrap(), false, &test_config(), &[], &LoadOptions::default())?;
+        let data_issues: Vec<_> = result.findings.iter().filter(|f| f.kind == FindingKind::DataQuality).collect();
+        assert_eq!(data_issues.len(), 2);
+        assert_eq!(data_issues.iter().map(|f| f.fields.len()).sum::<usize>(), 3);
+
+        let born_later = Demographics {
+            patient_id: "1".to_string(),
+            birth_date: chrono::NaiveDate::from_ymd_opt(2023, 6, 1),
+            ..Default::default()
+        };
+        let options = LoadOptions { demographics: vec![born_later], ..Default::default() };
+        let result = run_validation(file.path().to_str().unwrap(), false, &test_config(), &[], &options)?;
+        assert!(result.findings.iter().any(|f| f.kind == FindingKind::DataQuality && f.message.contains("Birth date")));
+        Ok(())
+    }
+    #[test]
+    fn test_all_loader_formats_are_validated() -> Result<(), AktenError> {
+        for path in ["mock_data/vitals_oru.hl7", "mock_data/patients_bundle.fhir"] {
+            let result = run_validation(path, true, &test_config(), &[], &LoadOptions::default())?;
+            assert_eq!(result.record_count, 2, "{}", path);
+            assert!(result.critical_alerts.iter().any(|a| a.contains("Fever (39.0°C) | Patient 2")), "{}", path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_findings_reference_the_observation_of_their_record() {
+        let record = |temperature| PatientRecord {
+            patient_id: "5".to_string(),
+            timestamp: crate::timestamp::Timestamp::parse("2024-12-01").unwrap(),
+            temperature: Some(temperature),
+            ..Default::default()
+        };
+        // Two readings of the same day; only the second has a fever
+        let records = vec![record(36.8), record(39.2)];
+        let result = validate_records(&records, false, &test_config());
+        assert_eq!(result.findings.len(), 1);
+        assert_eq!(result.findings[0].occurrence, 2);
+
+        let findings = fhir::findings_to_bundle(&result.findings);
+        let reference = findings["entry"][0]["resource"]["implicated"][0]["reference"].as_str().unwrap();
+        let export = fhir::records_to_bundle(
+            &records,
+            fhir::BundleType::Collection,
+            &crate::profiles::FhirProfile::Core,
+            crate::identity::LOCAL_SYSTEM,
+        )
+        .unwrap();
+        let implicated = export["entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|e| reference == format!("Observation/{}", e["resource"]["id"].as_str().unwrap()))
+            .unwrap();
+        assert_eq!(implicated["resource"]["valueQuantity"]["value"], 39.2);
+    }
+
+    #[test]
+    fn test_custom_observation_thresholds() -> Result<(), AktenError> {
+        let csv_data = "\
+patient_id,date,heart_rate,pain_score,device_serial
+1,2023-01-01,72,8,0042
+2,2023-01-01,72,2,0043
+3,2023-01-01,72,stark,0044";
+
+        let file = Builder::new().suffix(".csv").tempfile()?;
+        std::fs::write(&file, csv_data)?;
+
+        let mut config = test_config();
+        config.observations.push(crate::config::ObservationThreshold {
+            name: "pain_score".to_string(),
+            min: None,
+            max: Some(6.0),
+            label: "Severe pain".to_string(),
+            critical: true,
+        });
+        let result = run_validation(file.path().to_str().unwrap(), false, &config, &[], &LoadOptions::default())?;
+        assert_eq!(result.critical_alerts.len(), 1);
+        assert!(result.critical_alerts[0].contains("Severe pain (pain_score 8) | Patient 1"), "{}", result.critical_alerts[0]);
+        assert!(result.warnings.iter().any(|w| w.contains("pain_score 'stark' is not a number")), "{:?}", result.warnings);
+        assert_eq!(result.issues_found, 2);
+        Ok(())
+    }
 }
\ No newline at end of file